@@ -0,0 +1,83 @@
+//! IP allow and deny lists for control connections and public tunnel ports.
+
+use std::net::IpAddr;
+
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of networks in the access list a client requests for one tunnel.
+pub const MAX_ACCESS_RULES: usize = 16;
+
+/// Parse a network in CIDR notation, or a single IP address.
+///
+/// ```
+/// use bore_cli::access::parse_net;
+///
+/// assert_eq!(parse_net("10.0.0.0/8").unwrap().to_string(), "10.0.0.0/8");
+/// assert_eq!(parse_net("2001:db8::1").unwrap().to_string(), "2001:db8::1/128");
+/// assert!(parse_net("10.0.0.0/33").is_err());
+/// ```
+pub fn parse_net(s: &str) -> Result<IpNet> {
+    match s.parse::<IpAddr>() {
+        Ok(ip) => Ok(ip.into()),
+        Err(_) => s
+            .parse()
+            .with_context(|| format!("invalid network {s:?}, expected an IP address or CIDR")),
+    }
+}
+
+/// Networks that may or may not connect to a port.
+///
+/// Denied networks take precedence, and an empty allow list allows everyone.
+///
+/// ```
+/// use bore_cli::access::{parse_net, AccessList};
+///
+/// let list = AccessList {
+///     allow: vec![parse_net("10.0.0.0/8").unwrap()],
+///     deny: vec![parse_net("10.0.0.13").unwrap()],
+/// };
+/// assert!(list.permits("10.1.2.3".parse().unwrap()));
+/// assert!(!list.permits("10.0.0.13".parse().unwrap()));
+/// assert!(!list.permits("192.0.2.1".parse().unwrap()));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessList {
+    /// Networks allowed to connect, or all networks if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<IpNet>,
+
+    /// Networks denied, even if they are allowed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    /// Parse the allowed and denied networks, as accepted by [`parse_net`].
+    pub fn parse(allow: &[String], deny: &[String]) -> Result<Self> {
+        let parse_all = |nets: &[String]| nets.iter().map(|s| parse_net(s)).collect::<Result<_>>();
+        Ok(Self {
+            allow: parse_all(allow).context("allow")?,
+            deny: parse_all(deny).context("deny")?,
+        })
+    }
+
+    /// Returns whether the list places no restrictions.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Returns the number of networks in the list.
+    pub fn len(&self) -> usize {
+        self.allow.len() + self.deny.len()
+    }
+
+    /// Returns whether an address may connect.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners report IPv4 clients as IPv4-mapped IPv6 addresses.
+        let ip = ip.to_canonical();
+        let matches = |nets: &[IpNet]| nets.iter().any(|net| net.contains(&ip));
+        !matches(&self.deny) && (self.allow.is_empty() || matches(&self.allow))
+    }
+}
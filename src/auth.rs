@@ -1,26 +1,91 @@
 //! Auth implementation for bore client and server.
 
+use std::collections::HashMap;
+
 use anyhow::{bail, ensure, Result};
 use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, AsyncWrite};
 use uuid::Uuid;
 
+use crate::policy::Policy;
 use crate::shared::{ClientMessage, Delimited, ServerMessage};
 
 /// Wrapper around a MAC used for authenticating clients that have a secret.
-pub struct Authenticator(Hmac<Sha256>);
+#[derive(Clone)]
+pub struct Authenticator {
+    mac: Hmac<Sha256>,
+    key_id: Option<String>,
+}
 
 impl Authenticator {
     /// Generate an authenticator from a secret.
     pub fn new(secret: &str) -> Self {
         let hashed_secret = Sha256::new().chain_update(secret).finalize();
-        Self(Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"))
+        Self {
+            mac: Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"),
+            key_id: None,
+        }
+    }
+
+    /// Generate an authenticator for a named client credential.
+    ///
+    /// As the client, the key ID is sent along with the answer to a challenge.
+    pub fn with_key_id(key_id: &str, secret: &str) -> Self {
+        Self {
+            key_id: Some(key_id.to_string()),
+            ..Self::new(secret)
+        }
+    }
+
+    /// Returns the ID of the credential, if this is not a shared secret.
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// MAC over a label and both nonces of a mutual handshake.
+    fn keyed(&self, label: &str, challenge: &Uuid, nonce: &Uuid) -> Hmac<Sha256> {
+        let mut hmac = self.mac.clone();
+        hmac.update(label.as_bytes());
+        hmac.update(challenge.as_bytes());
+        hmac.update(nonce.as_bytes());
+        hmac
+    }
+
+    /// MAC proving the client's knowledge of the secret, bound to its key ID
+    /// and the transcript of the negotiation.
+    fn client_proof(&self, challenge: &Uuid, nonce: &Uuid, transcript: &[u8]) -> Hmac<Sha256> {
+        let mut hmac = self.keyed("bore client proof", challenge, nonce);
+        hmac.update(transcript);
+        hmac.update(self.key_id.as_deref().unwrap_or_default().as_bytes());
+        hmac
+    }
+
+    /// MAC proving the server's knowledge of the secret, bound to the
+    /// transcript of the negotiation.
+    fn server_proof(&self, challenge: &Uuid, nonce: &Uuid, transcript: &[u8]) -> Hmac<Sha256> {
+        let mut hmac = self.keyed("bore server proof", challenge, nonce);
+        hmac.update(transcript);
+        hmac
+    }
+
+    /// Derive the keys authenticating control messages after a mutual handshake.
+    fn message_auth(&self, challenge: &Uuid, nonce: &Uuid, server: bool) -> MessageAuth {
+        let key = |label| {
+            let key = self.keyed(label, challenge, nonce).finalize().into_bytes();
+            Hmac::new_from_slice(&key).expect("HMAC can take key of any size")
+        };
+        let (to_server, to_client) = (key("bore client to server"), key("bore server to client"));
+        if server {
+            MessageAuth::new(to_client, to_server)
+        } else {
+            MessageAuth::new(to_server, to_client)
+        }
     }
 
     /// Generate a reply message for a challenge.
     pub fn answer(&self, challenge: &Uuid) -> String {
-        let mut hmac = self.0.clone();
+        let mut hmac = self.mac.clone();
         hmac.update(challenge.as_bytes());
         hex::encode(hmac.finalize().into_bytes())
     }
@@ -39,7 +104,7 @@ impl Authenticator {
     /// ```
     pub fn validate(&self, challenge: &Uuid, tag: &str) -> bool {
         if let Ok(tag) = hex::decode(tag) {
-            let mut hmac = self.0.clone();
+            let mut hmac = self.mac.clone();
             hmac.update(challenge.as_bytes());
             hmac.verify_slice(&tag).is_ok()
         } else {
@@ -48,28 +113,77 @@ impl Authenticator {
     }
 
     /// As the server, send a challenge to the client and validate their response.
+    ///
+    /// See [`Credentials::server_handshake`] for the messages exchanged.
     pub async fn server_handshake<T: AsyncRead + AsyncWrite + Unpin>(
         &self,
         stream: &mut Delimited<T>,
     ) -> Result<()> {
-        let challenge = Uuid::new_v4();
-        stream.send(ServerMessage::Challenge(challenge)).await?;
+        Credentials::default()
+            .server_handshake(Some(self), true, stream)
+            .await?;
+        Ok(())
+    }
+
+    /// As the client, answer a challenge and check that the server knows the
+    /// secret too.
+    ///
+    /// Afterwards, all messages on the stream are authenticated with a key
+    /// derived from the secret and both sides' nonces.
+    pub async fn client_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut Delimited<T>,
+    ) -> Result<()> {
+        let challenge = match stream.recv_timeout().await? {
+            Some(ServerMessage::Challenge(challenge)) => challenge,
+            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+            _ => bail!("expected authentication challenge, but no secret was required"),
+        };
+        // A negotiation altered by a man-in-the-middle fails the proofs.
+        let transcript = stream.transcript();
+        let nonce = Uuid::new_v4();
+        let tag = self
+            .client_proof(&challenge, &nonce, &transcript)
+            .finalize()
+            .into_bytes();
+        stream
+            .send(ClientMessage::AuthenticateMutual {
+                key_id: self.key_id.clone(),
+                nonce,
+                tag: hex::encode(tag),
+            })
+            .await?;
         match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(tag)) => {
-                ensure!(self.validate(&challenge, &tag), "invalid secret");
-                Ok(())
+            Some(ServerMessage::Proof(tag)) => {
+                let valid = hex::decode(tag).is_ok_and(|tag| {
+                    let proof = self.server_proof(&challenge, &nonce, &transcript);
+                    proof.verify_slice(&tag).is_ok()
+                });
+                ensure!(valid, "server could not prove that it knows the secret");
             }
-            _ => bail!("server requires secret, but no secret was provided"),
+            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+            _ => bail!("server did not prove that it knows the secret, please upgrade it"),
         }
+        stream.authenticate(self.message_auth(&challenge, &nonce, false));
+        Ok(())
     }
 
-    /// As the client, answer a challenge to attempt to authenticate with the server.
-    pub async fn client_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+    /// As the client, answer the challenge of an older server that does not
+    /// support mutual authentication.
+    ///
+    /// The server does not prove that it knows the secret, and later messages
+    /// are not authenticated. Only the shared secret can be used this way.
+    pub async fn legacy_client_handshake<T: AsyncRead + AsyncWrite + Unpin>(
         &self,
         stream: &mut Delimited<T>,
     ) -> Result<()> {
+        ensure!(
+            self.key_id.is_none(),
+            "server does not support named credentials, please upgrade it"
+        );
         let challenge = match stream.recv_timeout().await? {
             Some(ServerMessage::Challenge(challenge)) => challenge,
+            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
             _ => bail!("expected authentication challenge, but no secret was required"),
         };
         let tag = self.answer(&challenge);
@@ -77,3 +191,180 @@ impl Authenticator {
         Ok(())
     }
 }
+
+/// Keys authenticating the control messages sent in each direction after a
+/// mutual handshake.
+///
+/// Each message is tagged along with its position on the stream, so messages
+/// cannot be replayed, reordered, dropped or spliced in by a third party.
+pub struct MessageAuth {
+    send: Hmac<Sha256>,
+    recv: Hmac<Sha256>,
+    sent: u64,
+    received: u64,
+}
+
+impl MessageAuth {
+    /// Length of a hex encoded message tag.
+    pub const TAG_LEN: usize = 64;
+
+    fn new(send: Hmac<Sha256>, recv: Hmac<Sha256>) -> Self {
+        Self {
+            send,
+            recv,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Returns the hex encoded tag of the next outgoing message.
+    pub fn seal(&mut self, message: &[u8]) -> String {
+        let mut hmac = self.send.clone();
+        hmac.update(&self.sent.to_be_bytes());
+        hmac.update(message);
+        self.sent += 1;
+        hex::encode(hmac.finalize().into_bytes())
+    }
+
+    /// Verify the tag of the next incoming message.
+    pub fn open(&mut self, message: &[u8], tag: &[u8]) -> Result<()> {
+        let mut hmac = self.recv.clone();
+        hmac.update(&self.received.to_be_bytes());
+        hmac.update(message);
+        let tag = hex::decode(tag).unwrap_or_default();
+        ensure!(
+            hmac.verify_slice(&tag).is_ok(),
+            "message failed authentication"
+        );
+        self.received += 1;
+        Ok(())
+    }
+}
+
+/// Named client credentials accepted by the server, each with its own secret.
+///
+/// ```
+/// use bore_cli::auth::{Authenticator, Credentials};
+/// use uuid::Uuid;
+///
+/// let mut credentials = Credentials::default();
+/// credentials.insert("laptop", "laptop secret");
+///
+/// let challenge = Uuid::new_v4();
+/// let tag = Authenticator::new("laptop secret").answer(&challenge);
+/// assert!(credentials.validate("laptop", &challenge, &tag));
+/// assert!(!credentials.validate("phone", &challenge, &tag));
+/// ```
+#[derive(Clone, Default)]
+pub struct Credentials(HashMap<String, (Authenticator, Policy)>);
+
+impl Credentials {
+    /// Add a credential, replacing any existing one with the same key ID.
+    pub fn insert(&mut self, key_id: &str, secret: &str) {
+        self.insert_with_policy(key_id, secret, Policy::default());
+    }
+
+    /// Add a credential restricted by a policy.
+    pub fn insert_with_policy(&mut self, key_id: &str, secret: &str, policy: Policy) {
+        let auth = Authenticator::with_key_id(key_id, secret);
+        self.0.insert(key_id.to_string(), (auth, policy));
+    }
+
+    /// Returns the policy of a credential, if it exists.
+    pub fn policy(&self, key_id: &str) -> Option<&Policy> {
+        self.0.get(key_id).map(|(_, policy)| policy)
+    }
+
+    /// Returns whether a credential with this key ID exists.
+    pub fn contains(&self, key_id: &str) -> bool {
+        self.0.contains_key(key_id)
+    }
+
+    /// Returns whether there are no credentials.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Validate a reply to a challenge, made with the secret of a named credential.
+    pub fn validate(&self, key_id: &str, challenge: &Uuid, tag: &str) -> bool {
+        match self.0.get(key_id) {
+            Some((auth, _)) => auth.validate(challenge, tag),
+            None => false,
+        }
+    }
+
+    /// As the server, challenge a client holding a named credential or the shared secret.
+    ///
+    /// The client answers with a nonce of its own and a proof of the secret,
+    /// and the server replies with its own proof, after which all messages on
+    /// the stream are authenticated. Both proofs cover the
+    /// [`transcript`](Delimited::transcript) of the stream up to the challenge.
+    /// With `legacy`, older clients that only answer the challenge with the
+    /// shared secret are still accepted, without authenticating later messages.
+    ///
+    /// Returns the key ID the client authenticated with, or `None` for the shared secret.
+    pub async fn server_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        shared: Option<&Authenticator>,
+        legacy: bool,
+        stream: &mut Delimited<T>,
+    ) -> Result<Option<String>> {
+        self.server_handshake_with(shared, legacy, stream, || Ok(()))
+            .await
+    }
+
+    /// Like [`server_handshake`](Self::server_handshake), but calls `admit`
+    /// once the client has answered, before its answer is checked.
+    ///
+    /// An error from `admit` fails the handshake whether or not the answer
+    /// was correct, so the client learns nothing about it.
+    pub async fn server_handshake_with<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        shared: Option<&Authenticator>,
+        legacy: bool,
+        stream: &mut Delimited<T>,
+        admit: impl FnOnce() -> Result<()>,
+    ) -> Result<Option<String>> {
+        let challenge = Uuid::new_v4();
+        stream.send(ServerMessage::Challenge(challenge)).await?;
+        let transcript = stream.transcript();
+        let reply = stream.recv_timeout().await?;
+        admit()?;
+        match reply {
+            Some(ClientMessage::AuthenticateMutual { key_id, nonce, tag }) => {
+                let auth = match &key_id {
+                    Some(key_id) => self.0.get(key_id).map(|(auth, _)| auth),
+                    None => shared,
+                };
+                let auth = auth.filter(|auth| {
+                    let proof = auth.client_proof(&challenge, &nonce, &transcript);
+                    hex::decode(&tag).is_ok_and(|tag| proof.verify_slice(&tag).is_ok())
+                });
+                let Some(auth) = auth else {
+                    match key_id {
+                        Some(key_id) => bail!("invalid credentials for key {key_id:?}"),
+                        None => bail!("invalid secret"),
+                    }
+                };
+                let proof = auth
+                    .server_proof(&challenge, &nonce, &transcript)
+                    .finalize()
+                    .into_bytes();
+                stream
+                    .send(ServerMessage::Proof(hex::encode(proof)))
+                    .await?;
+                stream.authenticate(auth.message_auth(&challenge, &nonce, true));
+                Ok(key_id)
+            }
+            Some(ClientMessage::Authenticate(_)) if !legacy => {
+                bail!("server requires mutual authentication, please upgrade the client")
+            }
+            Some(ClientMessage::Authenticate(tag)) => {
+                let valid = shared.is_some_and(|auth| auth.validate(&challenge, &tag));
+                ensure!(valid, "invalid secret");
+                Ok(None)
+            }
+            _ => bail!("server requires secret, but no secret was provided"),
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Brute-force protection, delaying and banning addresses that fail to authenticate.
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use dashmap::DashMap;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::metrics::ACTIVE_BANS;
+
+/// Default number of failed authentications after which an address is banned.
+pub const MAX_AUTH_FAILURES: u32 = 5;
+
+/// Default delay before replying to the first failed authentication, doubled for each further one.
+pub const AUTH_FAILURE_DELAY: Duration = Duration::from_millis(250);
+
+/// Default upper bound on the delay after failed authentications.
+pub const MAX_AUTH_FAILURE_DELAY: Duration = Duration::from_secs(8);
+
+/// Default duration of a ban, which is also how long failures are remembered.
+pub const BAN_DURATION: Duration = Duration::from_secs(600);
+
+/// Default length of the prefix that IPv6 addresses are banned by, as a
+/// client usually holds a whole /64 network.
+pub const IPV6_BAN_PREFIX: u8 = 64;
+
+/// Default number of authentications that may be in progress at once from one address.
+pub const MAX_PENDING_AUTHS: usize = 16;
+
+/// Interval at which expired bans and old failures are dropped.
+pub const BAN_PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Thresholds for delaying and banning addresses after failed authentications.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BanSettings {
+    /// Number of failures after which an address is banned, or 0 to never ban.
+    pub max_failures: u32,
+
+    /// Delay before replying to the first failure, doubled for each further one.
+    pub delay: Duration,
+
+    /// Upper bound on the delay before replying to a failure.
+    pub max_delay: Duration,
+
+    /// Duration of a ban, and time after which failures are forgotten.
+    pub ban_duration: Duration,
+
+    /// Number of authentications that may be in progress at once from one
+    /// address, or 0 for no limit.
+    pub max_pending: usize,
+
+    /// Length of the prefix that IPv6 addresses are tracked by, so that all
+    /// addresses of a network count as one.
+    pub ipv6_prefix: u8,
+}
+
+impl Default for BanSettings {
+    fn default() -> Self {
+        Self {
+            max_failures: MAX_AUTH_FAILURES,
+            delay: AUTH_FAILURE_DELAY,
+            max_delay: MAX_AUTH_FAILURE_DELAY,
+            ban_duration: BAN_DURATION,
+            max_pending: MAX_PENDING_AUTHS,
+            ipv6_prefix: IPV6_BAN_PREFIX,
+        }
+    }
+}
+
+impl BanSettings {
+    /// Returns the delay before replying to the given number of consecutive failures.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use bore_cli::ban::BanSettings;
+    ///
+    /// let settings = BanSettings::default();
+    /// assert_eq!(settings.delay_after(1), Duration::from_millis(250));
+    /// assert_eq!(settings.delay_after(3), Duration::from_millis(1000));
+    /// assert_eq!(settings.delay_after(30), settings.max_delay);
+    /// ```
+    pub fn delay_after(&self, failures: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// Returns the network an address is tracked by: the address itself for
+    /// IPv4, and its prefix for IPv6.
+    ///
+    /// ```
+    /// use std::net::IpAddr;
+    /// use bore_cli::ban::BanSettings;
+    ///
+    /// let settings = BanSettings::default();
+    /// let ip: IpAddr = "2001:db8:0:1:2:3:4:5".parse().unwrap();
+    /// assert_eq!(settings.network(ip), "2001:db8:0:1::".parse::<IpAddr>().unwrap());
+    /// let ip: IpAddr = "::ffff:203.0.113.7".parse().unwrap();
+    /// assert_eq!(settings.network(ip), "203.0.113.7".parse::<IpAddr>().unwrap());
+    /// ```
+    pub fn network(&self, ip: IpAddr) -> IpAddr {
+        network(ip, self.ipv6_prefix)
+    }
+
+    /// Returns the prefix length of the network an address is tracked by.
+    fn prefix_len(&self, ip: IpAddr) -> u8 {
+        match ip.to_canonical() {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => self.ipv6_prefix.min(128),
+        }
+    }
+}
+
+/// Returns an IPv4 address unchanged, and the first address of the network
+/// with the given prefix length for an IPv6 address.
+fn network(ip: IpAddr, ipv6_prefix: u8) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => IpAddr::V4(ip),
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(ipv6_prefix.min(128)))
+                .unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+    }
+}
+
+/// Failed authentications of one address or IPv6 network.
+#[derive(Debug)]
+struct Record {
+    prefix_len: u8,
+    failures: u32,
+    last_failure: Instant,
+    banned_until: Option<Instant>,
+    banned_at: Option<SystemTime>,
+}
+
+/// A banned address, as listed by [`BanList::bans`].
+#[derive(Clone, Debug, Serialize)]
+pub struct Ban {
+    /// The banned address, or the first address of a banned IPv6 network.
+    pub ip: IpAddr,
+
+    /// Length of the prefix of the banned network, 32 for an IPv4 address.
+    pub prefix_len: u8,
+
+    /// When the ban started, in seconds since the Unix epoch.
+    pub since: u64,
+
+    /// Seconds until the ban is lifted.
+    pub remaining_secs: u64,
+}
+
+/// Addresses with recent failed authentications, shared by all connections of a server.
+#[derive(Debug, Default)]
+pub struct BanList {
+    records: DashMap<IpAddr, Record>,
+    pending: DashMap<IpAddr, usize>,
+}
+
+/// An authentication in progress, counted against its address until dropped.
+#[derive(Debug)]
+pub struct PendingAuth<'a> {
+    bans: &'a BanList,
+    network: IpAddr,
+}
+
+impl Drop for PendingAuth<'_> {
+    fn drop(&mut self) {
+        if let Some(mut pending) = self.bans.pending.get_mut(&self.network) {
+            *pending -= 1;
+        }
+        self.bans
+            .pending
+            .remove_if(&self.network, |_, pending| *pending == 0);
+    }
+}
+
+impl BanList {
+    /// Returns whether an address is currently banned.
+    pub fn is_banned(&self, ip: IpAddr, settings: &BanSettings) -> bool {
+        let now = Instant::now();
+        self.records
+            .get(&settings.network(ip))
+            .and_then(|record| record.banned_until)
+            .is_some_and(|until| until > now)
+    }
+
+    /// Count an authentication in progress, unless the address already has
+    /// the maximum number of them.
+    ///
+    /// Guesses made in parallel would otherwise all be answered before the
+    /// address is banned.
+    pub fn start_auth(&self, ip: IpAddr, settings: &BanSettings) -> Option<PendingAuth<'_>> {
+        let network = settings.network(ip);
+        let mut pending = self.pending.entry(network).or_insert(0);
+        if settings.max_pending > 0 && *pending >= settings.max_pending {
+            return None;
+        }
+        *pending += 1;
+        Some(PendingAuth {
+            bans: self,
+            network,
+        })
+    }
+
+    /// Record a failed authentication, returning how long to delay the reply.
+    ///
+    /// Bans the address once it reaches the maximum number of failures.
+    pub fn record_failure(&self, ip: IpAddr, settings: &BanSettings) -> Duration {
+        let now = Instant::now();
+        let mut record = self.records.entry(settings.network(ip)).or_insert(Record {
+            prefix_len: settings.prefix_len(ip),
+            failures: 0,
+            last_failure: now,
+            banned_until: None,
+            banned_at: None,
+        });
+        if now.duration_since(record.last_failure) > settings.ban_duration {
+            record.failures = 0;
+        }
+        record.failures += 1;
+        record.last_failure = now;
+
+        let failures = record.failures;
+        if settings.max_failures > 0 && failures >= settings.max_failures {
+            if record.banned_until.is_none() {
+                ACTIVE_BANS.inc();
+            }
+            record.banned_until = Some(now + settings.ban_duration);
+            record.banned_at = Some(SystemTime::now());
+            warn!(%ip, failures, "banned address after failed authentications");
+        }
+        settings.delay_after(failures)
+    }
+
+    /// Forget the failures of an address after it authenticates successfully.
+    pub fn record_success(&self, ip: IpAddr, settings: &BanSettings) {
+        self.records.remove_if(&settings.network(ip), |_, record| {
+            record.banned_until.is_none()
+        });
+    }
+
+    /// Lift the ban of an address, or of the IPv6 network containing it, and
+    /// forget its failures, returning whether it was banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let key = self.records.iter().find_map(|record| {
+            let key = *record.key();
+            (network(ip, record.prefix_len) == key).then_some(key)
+        });
+        match key.and_then(|key| self.records.remove(&key)) {
+            Some((_, record)) if record.banned_until.is_some() => {
+                ACTIVE_BANS.dec();
+                info!(%ip, "lifted ban");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the currently banned addresses.
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        let mut bans: Vec<Ban> = self
+            .records
+            .iter()
+            .filter_map(|entry| {
+                let until = entry.banned_until.filter(|until| *until > now)?;
+                let since = entry.banned_at?.duration_since(SystemTime::UNIX_EPOCH);
+                Some(Ban {
+                    ip: *entry.key(),
+                    prefix_len: entry.prefix_len,
+                    since: since.map_or(0, |since| since.as_secs()),
+                    remaining_secs: until.duration_since(now).as_secs(),
+                })
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.ip);
+        bans
+    }
+
+    /// Drop expired bans and failures older than the ban duration.
+    pub fn prune(&self, settings: &BanSettings) {
+        let now = Instant::now();
+        self.records.retain(|_, record| {
+            if let Some(until) = record.banned_until {
+                if until > now {
+                    return true;
+                }
+                ACTIVE_BANS.dec();
+                record.banned_until = None;
+                record.banned_at = None;
+            }
+            now.duration_since(record.last_failure) <= settings.ban_duration
+        });
+    }
+}
@@ -0,0 +1,131 @@
+//! A small certificate authority for issuing TLS certificates in-process.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose, SanType,
+};
+use time::{Duration, OffsetDateTime};
+
+/// Certificate authority that signs server and client certificates.
+pub struct CertificateAuthority(Certificate);
+
+/// A certificate and its private key, both PEM encoded.
+pub struct Issued {
+    /// The certificate, signed by the authority.
+    pub cert: String,
+
+    /// The certificate's private key, in PKCS#8 format.
+    pub key: String,
+}
+
+/// Parse a subject alternative name, as an IP address or else a DNS name.
+fn san(name: &str) -> SanType {
+    match name.parse::<IpAddr>() {
+        Ok(ip) => SanType::IpAddress(ip),
+        Err(_) => SanType::DnsName(name.to_string()),
+    }
+}
+
+/// Parameters valid from now for the given number of days.
+fn params(common_name: &str, days: u32) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params.not_before = OffsetDateTime::now_utc();
+    params.not_after = params.not_before + Duration::days(days.into());
+    params
+}
+
+impl CertificateAuthority {
+    /// Generate a new authority with a fresh key.
+    pub fn generate(common_name: &str, days: u32) -> Result<Self> {
+        let mut params = params(common_name, days);
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        Ok(Self(Certificate::from_params(params)?))
+    }
+
+    /// Load an authority from its PEM certificate and key, as written by [`save`](Self::save).
+    pub fn load(cert: &Path, key: &Path) -> Result<Self> {
+        let read = |path: &Path| {
+            fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))
+        };
+        let key_pair = KeyPair::from_pem(&read(key)?)
+            .with_context(|| format!("invalid CA key file {}", key.display()))?;
+        let params = CertificateParams::from_ca_cert_pem(&read(cert)?, key_pair)
+            .with_context(|| format!("invalid CA certificate file {}", cert.display()))?;
+        Ok(Self(Certificate::from_params(params)?))
+    }
+
+    /// Write the PEM certificate and key of the authority, refusing to overwrite files.
+    pub fn save(&self, cert: &Path, key: &Path) -> Result<()> {
+        let issued = Issued {
+            cert: self.0.serialize_pem()?,
+            key: self.0.serialize_private_key_pem(),
+        };
+        issued.save(cert, key)
+    }
+
+    /// Issue a certificate for the given DNS names and IP addresses.
+    ///
+    /// Server certificates are valid for TLS servers, and client certificates
+    /// for authenticating to a server with `--client-ca`.
+    pub fn issue(
+        &self,
+        common_name: &str,
+        sans: &[String],
+        client: bool,
+        days: u32,
+    ) -> Result<Issued> {
+        // webpki rejects certificates with an empty SAN extension.
+        ensure!(
+            !sans.is_empty(),
+            "at least one subject alternative name is required"
+        );
+        let mut params = params(common_name, days);
+        params.subject_alt_names = sans.iter().map(|name| san(name)).collect();
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![if client {
+            ExtendedKeyUsagePurpose::ClientAuth
+        } else {
+            ExtendedKeyUsagePurpose::ServerAuth
+        }];
+        params.use_authority_key_identifier_extension = true;
+        let cert = Certificate::from_params(params)?;
+        Ok(Issued {
+            cert: cert.serialize_pem_with_signer(&self.0)?,
+            key: cert.serialize_private_key_pem(),
+        })
+    }
+}
+
+impl Issued {
+    /// Write the certificate and key to new files, the key readable only by its owner.
+    pub fn save(&self, cert: &Path, key: &Path) -> Result<()> {
+        write_new(cert, &self.cert, 0o644)?;
+        write_new(key, &self.key, 0o600)
+    }
+}
+
+/// Write a file that must not exist yet.
+pub(crate) fn write_new(path: &Path, contents: &str, #[allow(unused)] mode: u32) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("could not create {}", path.display()))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("could not write {}", path.display()))
+}
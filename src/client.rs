@@ -1,20 +1,54 @@
 //! Client implementation for the `bore` service.
 
-use std::io;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 
+use futures_util::{future, StreamExt};
 use tokio::io::AsyncWriteExt;
-use tokio::{net::TcpStream, time::timeout};
-use tokio_rustls::{rustls, TlsConnector};
-use tracing::{error, info, info_span, warn, Instrument};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep_until, timeout, Instant};
+use tokio_rustls::TlsConnector;
+use tokio_yamux::{Session, StreamHandle};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
+use crate::access::AccessList;
 use crate::auth::Authenticator;
+use crate::noise::NoiseClient;
 use crate::shared::{
-    proxy, ClientMessage, Delimited, ServerMessage, StreamTrait, CONTROL_PORT, NETWORK_TIMEOUT,
+    mux_config, proxy, Capability, ClientMessage, Delimited, Prefixed, ProtocolInfo, ServerMessage,
+    StreamTrait, CONTROL_PORT, HEARTBEAT_INTERVAL, MAX_MISSED_HEARTBEATS, MAX_TUNNELS,
+    NETWORK_TIMEOUT, UDP_IDLE_TIMEOUT,
 };
+use crate::tls::ClientTls;
+use crate::udp::relay_flow;
+
+/// Encrypted transport for connections to the server.
+#[derive(Clone)]
+pub enum Transport {
+    /// TLS, verifying the server certificate.
+    Tls(ClientTls),
+
+    /// Noise, verifying the server's static public key.
+    Noise(NoiseClient),
+}
+
+impl From<ClientTls> for Transport {
+    fn from(tls: ClientTls) -> Self {
+        Transport::Tls(tls)
+    }
+}
+
+impl From<NoiseClient> for Transport {
+    fn from(noise: NoiseClient) -> Self {
+        Transport::Noise(noise)
+    }
+}
 
 /// State structure for the client.
 pub struct Client {
@@ -23,84 +57,455 @@ pub struct Client {
 
     /// Config structure for the client.
     config: ClientConfig,
+
+    /// Requests from control handles, sent to the server while listening.
+    requests: mpsc::Receiver<Request>,
+
+    /// Handle for sending requests, cloned by [`Client::control`].
+    control: ClientControl,
+}
+
+/// How a client detects that the server is no longer reachable.
+///
+/// Without multiplexing, the client pings the server on every interval, and
+/// gives up on the connection once no message arrived for `max_missed`
+/// intervals. Servers that do not answer pings must send heartbeats at least
+/// as often as this interval. Multiplexed sessions rely on their own keepalive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Liveness {
+    /// Interval between pings to the server.
+    pub interval: Duration,
+
+    /// Number of intervals without any message from the server, after which
+    /// the connection is considered dead.
+    pub max_missed: u32,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Liveness {
+            interval: HEARTBEAT_INTERVAL,
+            max_missed: MAX_MISSED_HEARTBEATS,
+        }
+    }
+}
+
+/// A local address to expose, along with the remote port to request for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tunnel {
+    /// Local host that is forwarded.
+    pub local_host: String,
+
+    /// Local port that is forwarded.
+    pub local_port: u16,
+
+    /// Port to select on the remote server, or 0 for any port.
+    pub port: u16,
+
+    /// Networks that may connect to the remote port, within what the server allows.
+    pub access: AccessList,
+
+    /// Whether to forward UDP datagrams instead of TCP connections.
+    pub udp: bool,
+
+    /// Hostname that the server routes HTTP requests by on its shared HTTP
+    /// port, instead of opening a port for the tunnel. An empty name asks
+    /// the server to generate one.
+    pub http: Option<String>,
+}
+
+impl FromStr for Tunnel {
+    type Err = anyhow::Error;
+
+    /// Parse a tunnel from the `local_host:local_port[:remote_port]` form.
+    ///
+    /// ```
+    /// use bore_cli::client::Tunnel;
+    ///
+    /// let tunnel: Tunnel = "[::1]:8000:9000".parse().unwrap();
+    /// assert_eq!((&tunnel.local_host[..], tunnel.local_port, tunnel.port), ("::1", 8000, 9000));
+    ///
+    /// let tunnel: Tunnel = "localhost:5432".parse().unwrap();
+    /// assert_eq!((&tunnel.local_host[..], tunnel.local_port, tunnel.port), ("localhost", 5432, 0));
+    /// ```
+    fn from_str(s: &str) -> Result<Self> {
+        let (local_host, ports) = match s.strip_prefix('[') {
+            Some(rest) => rest.split_once("]:").context("unterminated IPv6 address")?,
+            None => s
+                .split_once(':')
+                .context("expected local_host:local_port")?,
+        };
+        ensure!(!local_host.is_empty(), "missing local host");
+        let (local_port, port) = match ports.split_once(':') {
+            Some((local_port, port)) => (local_port, port.parse().context("invalid remote port")?),
+            None => (ports, 0),
+        };
+        Ok(Tunnel {
+            local_host: local_host.to_string(),
+            local_port: local_port.parse().context("invalid local port")?,
+            port,
+            access: AccessList::default(),
+            udp: false,
+            http: None,
+        })
+    }
+}
+
+/// Split a server address of the form `host[:port]` into host and control port.
+///
+/// IPv6 addresses need brackets when a port is given, as in `[::1]:7835`.
+fn parse_server_addr(to: &str) -> Result<(&str, u16)> {
+    if let Some(rest) = to.strip_prefix('[') {
+        let (host, port) = rest.split_once(']').context("unterminated IPv6 address")?;
+        let port = match port {
+            "" => CONTROL_PORT,
+            port => port
+                .strip_prefix(':')
+                .context("expected port after IPv6 address")?
+                .parse()
+                .context("invalid control port")?,
+        };
+        return Ok((host, port));
+    }
+    match to.split_once(':') {
+        // More than one colon is a bare IPv6 address without a port.
+        Some((host, port)) if !port.contains(':') => {
+            Ok((host, port.parse().context("invalid control port")?))
+        }
+        _ => Ok((to, CONTROL_PORT)),
+    }
 }
 
 /// Config structure for the client.
 struct ClientConfig {
-    /// Destination address of the server.
+    /// Destination host of the server.
     to: String,
 
-    // Local host that is forwarded.
-    local_host: String,
+    /// Control port of the server.
+    control_port: u16,
 
-    /// Local port that is forwarded.
-    local_port: u16,
+    /// Local addresses that are forwarded, one for each tunnel, including
+    /// those added while listening.
+    tunnels: RwLock<Vec<Tunnel>>,
+
+    /// Ports that are publicly available on the remote, one for each tunnel
+    /// requested when connecting.
+    remote_ports: Vec<u16>,
 
-    /// Port that is publicly available on the remote.
-    remote_port: u16,
+    /// Hostnames that the tunnels requested when connecting are routed by,
+    /// if they are HTTP tunnels.
+    remote_hosts: Vec<Option<String>>,
+
+    /// Session token issued by the server, required to accept connections.
+    session: Uuid,
 
     /// Optional secret used to authenticate clients.
     auth: Option<Authenticator>,
 
-    /// Optional tls configuration
-    tls: Option<TlsConnector>,
+    /// Whether the server supports mutual authentication.
+    mutual_auth: bool,
+
+    /// Optional encrypted transport.
+    transport: Option<Transport>,
+
+    /// Whether proxied streams are multiplexed over the control connection.
+    multiplex: bool,
+
+    /// How to detect that the server is no longer reachable.
+    liveness: Liveness,
+
+    /// Time without datagrams after which a UDP flow is closed, as the
+    /// server announced it.
+    udp_idle_timeout: Duration,
 }
 
 impl Client {
-    /// Create a new client.
+    /// Create a new client, requesting a remote port or 0 for any port.
     pub async fn new(
         local_host: &str,
         local_port: u16,
         to: &str,
+        port: u16,
         secret: Option<&str>,
     ) -> Result<Self> {
-        Client::new_with_tls(local_host, local_port, to, secret, None).await
+        Client::new_with_tls(local_host, local_port, to, port, secret, None, false).await
     }
 
-    /// Create a new client with tls is configurable.
+    /// Create a new client with tls and multiplexing configurable.
+    ///
+    /// With `multiplex`, all proxied streams are carried over the control
+    /// connection instead of opening a new connection to the server for each.
+    /// This requires TLS, so without it the client falls back to a new
+    /// connection per stream.
     pub async fn new_with_tls(
         local_host: &str,
         local_port: u16,
         to: &str,
+        port: u16,
         secret: Option<&str>,
         tls: Option<TlsConnector>,
+        multiplex: bool,
     ) -> Result<Self> {
-        let mut stream = Delimited::new(connect_with_timeout(to, CONTROL_PORT, &tls).await?);
+        let tunnel = Tunnel {
+            local_host: local_host.to_string(),
+            local_port,
+            port,
+            access: AccessList::default(),
+            udp: false,
+            http: None,
+        };
         let auth = secret.map(Authenticator::new);
+        let transport = tls.map(|tls| ClientTls::new(tls).into());
+        Client::with_tunnels(vec![tunnel], to, auth, transport, multiplex).await
+    }
+
+    /// Create a new client forwarding several tunnels over one control connection.
+    ///
+    /// The server address `to` may include a control port, as in `host:port`.
+    /// For a named client credential, pass an authenticator with a key ID.
+    pub async fn with_tunnels(
+        tunnels: Vec<Tunnel>,
+        to: &str,
+        auth: Option<Authenticator>,
+        transport: Option<Transport>,
+        multiplex: bool,
+    ) -> Result<Self> {
+        Client::connect(tunnels, to, auth, transport, multiplex, None).await
+    }
+
+    /// Create a new client that takes over the session of a lost connection,
+    /// given its [`session`](Client::session) token.
+    ///
+    /// If the server still holds the session's tunnels, they keep their
+    /// remote ports. Otherwise, a new session is started with new tunnels.
+    pub async fn resume(
+        tunnels: Vec<Tunnel>,
+        to: &str,
+        auth: Option<Authenticator>,
+        transport: Option<Transport>,
+        multiplex: bool,
+        session: Uuid,
+    ) -> Result<Self> {
+        Client::connect(tunnels, to, auth, transport, multiplex, Some(session)).await
+    }
+
+    /// Connect to the server, resuming a previous session if given its token.
+    pub(crate) async fn connect(
+        tunnels: Vec<Tunnel>,
+        to: &str,
+        auth: Option<Authenticator>,
+        transport: Option<Transport>,
+        multiplex: bool,
+        resume: Option<Uuid>,
+    ) -> Result<Self> {
+        ensure!(!tunnels.is_empty(), "no tunnels to expose");
+        ensure!(
+            tunnels.len() <= MAX_TUNNELS,
+            "at most {MAX_TUNNELS} tunnels may be exposed"
+        );
+
+        let udp = tunnels.iter().any(|tunnel| tunnel.udp);
+        ensure!(!(udp && multiplex), "udp tunnels cannot be multiplexed");
+
+        let (to, control_port) = parse_server_addr(to)?;
+        let mut stream = Delimited::new(connect_with_timeout(to, control_port, &transport).await?);
+        let protocol = ProtocolInfo::new(vec![
+            Capability::Multiplex,
+            Capability::MultiTunnel,
+            Capability::MutualAuth,
+            Capability::AccessLists,
+            Capability::TunnelControl,
+            Capability::Liveness,
+            Capability::Resume,
+            Capability::Udp,
+            Capability::Http,
+        ])
+        .client_negotiate(&mut stream)
+        .await?;
+        let mutual_auth = protocol.supports(Capability::MutualAuth);
         if let Some(auth) = &auth {
-            auth.client_handshake(&mut stream).await?;
+            if mutual_auth {
+                auth.client_handshake(&mut stream).await?;
+            } else {
+                // Without a server proof, a man-in-the-middle could pose as the server.
+                warn!("server does not support mutual authentication, please upgrade it");
+                auth.legacy_client_handshake(&mut stream).await?;
+            }
         }
 
-        info!("sending hello message to server");
-        stream.send(ClientMessage::Hello()).await?;
-        let remote_port = match stream.recv_timeout().await? {
-            Some(ServerMessage::Hello(remote_port)) => remote_port,
-            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
-            Some(ServerMessage::Challenge(_)) => {
-                bail!("server requires authentication, but no client secret was provided");
-            }
-            Some(_) => bail!("unexpected initial non-hello message"),
-            None => bail!("unexpected EOF"),
+        // Multiplexed streams are not authenticated by the control messages'
+        // tags, so they are only opened on an encrypted connection.
+        let multiplex = if multiplex && transport.is_none() {
+            warn!("multiplexing requires tls or noise, opening a connection per stream");
+            false
+        } else if multiplex && !protocol.supports(Capability::Multiplex) {
+            warn!("server does not support multiplexing, opening a connection per stream");
+            false
+        } else {
+            multiplex
         };
-        info!(remote_port, "connected to server");
-        info!("listening at {to}:{remote_port}");
+        if udp {
+            ensure!(
+                protocol.supports(Capability::Udp),
+                "server does not support udp tunnels"
+            );
+        }
+        let http = tunnels.iter().any(|tunnel| tunnel.http.is_some());
+        if http {
+            ensure!(
+                protocol.supports(Capability::Http),
+                "server does not support http tunnels"
+            );
+        }
+        let restricted = tunnels.iter().any(|tunnel| !tunnel.access.is_empty());
+        if restricted && !protocol.supports(Capability::AccessLists) {
+            bail!("server does not support tunnel access lists");
+        }
+        // Access lists can only be sent along with the tunnels they apply to,
+        // and tunnels can only be added on connections that index them.
+        let controllable = protocol.supports(Capability::TunnelControl);
+        // Only servers that hold tunnels for a grace period can resume sessions.
+        let resume = resume.filter(|_| protocol.supports(Capability::Resume));
+        let multi_tunnel =
+            tunnels.len() > 1 || restricted || controllable || udp || http || resume.is_some();
+        if multi_tunnel && !protocol.supports(Capability::MultiTunnel) {
+            bail!("server does not support multiple tunnels per connection");
+        }
 
+        info!(multiplex, "sending hello message to server");
+        let ports: Vec<u16> = tunnels.iter().map(|tunnel| tunnel.port).collect();
+        if multi_tunnel {
+            let access = if restricted {
+                tunnels.iter().map(|tunnel| tunnel.access.clone()).collect()
+            } else {
+                Vec::new()
+            };
+            let udp = if udp {
+                tunnels.iter().map(|tunnel| tunnel.udp).collect()
+            } else {
+                Vec::new()
+            };
+            let http = if http {
+                tunnels.iter().map(|tunnel| tunnel.http.clone()).collect()
+            } else {
+                Vec::new()
+            };
+            stream
+                .send(ClientMessage::HelloTunnels {
+                    ports,
+                    multiplex,
+                    access,
+                    resume,
+                    udp,
+                    http,
+                })
+                .await?;
+        } else if multiplex {
+            stream.send(ClientMessage::HelloMux(ports[0])).await?;
+        } else {
+            stream.send(ClientMessage::Hello(ports[0])).await?;
+        }
+        let (remote_ports, mut remote_hosts, session, udp_idle_timeout) =
+            match stream.recv_timeout().await? {
+                Some(ServerMessage::Hello(remote_port, session)) if !multi_tunnel => {
+                    (vec![remote_port], Vec::new(), session, UDP_IDLE_TIMEOUT)
+                }
+                Some(ServerMessage::HelloTunnels {
+                    ports,
+                    session,
+                    hosts,
+                    udp_idle_timeout_ms,
+                }) if multi_tunnel => {
+                    ensure!(
+                        ports.len() == tunnels.len()
+                            && (hosts.is_empty() || hosts.len() == tunnels.len()),
+                        "server opened wrong tunnel count"
+                    );
+                    let udp_idle_timeout =
+                        udp_idle_timeout_ms.map_or(UDP_IDLE_TIMEOUT, Duration::from_millis);
+                    (ports, hosts, session, udp_idle_timeout)
+                }
+                Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+                Some(ServerMessage::Challenge(_)) => {
+                    bail!("server requires authentication, but no client secret was provided");
+                }
+                Some(_) => bail!("unexpected initial non-hello message"),
+                None => bail!("unexpected EOF"),
+            };
+        match resume {
+            Some(token) if token == session => info!("resumed previous session"),
+            Some(_) => warn!("could not resume previous session, remote ports may have changed"),
+            None => (),
+        }
+        remote_hosts.resize(tunnels.len(), None);
+        for ((tunnel, remote_port), host) in tunnels.iter().zip(&remote_ports).zip(&remote_hosts) {
+            info!(remote_port, "connected to server");
+            let local = format!("{}:{}", tunnel.local_host, tunnel.local_port);
+            match host {
+                Some(host) => info!("listening at http://{host}:{remote_port} for {local}"),
+                None if tunnel.udp => info!("listening at {to}:{remote_port} for {local} (udp)"),
+                None => info!("listening at {to}:{remote_port} for {local}"),
+            }
+        }
+
+        let (requests_tx, requests) = mpsc::channel(16);
         Ok(Client {
             conn: Some(stream),
             config: ClientConfig {
                 to: to.to_string(),
-                local_host: local_host.to_string(),
-                local_port,
-                remote_port,
+                control_port,
+                tunnels: RwLock::new(tunnels),
+                remote_ports,
+                remote_hosts,
+                session,
                 auth,
-                tls,
+                mutual_auth,
+                transport,
+                multiplex,
+                liveness: Liveness::default(),
+                udp_idle_timeout,
+            },
+            requests,
+            control: ClientControl {
+                requests: requests_tx,
+                supported: controllable,
+                rtt: Arc::default(),
             },
         })
     }
 
+    /// Set how to detect that the server is no longer reachable.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.config.liveness = liveness;
+        self
+    }
+
     /// Returns the port publicly available on the remote.
+    ///
+    /// With several tunnels, this is the port of the first one.
     pub fn remote_port(&self) -> u16 {
-        self.config.remote_port
+        self.config.remote_ports[0]
+    }
+
+    /// Returns the ports publicly available on the remote, one for each tunnel.
+    pub fn remote_ports(&self) -> &[u16] {
+        &self.config.remote_ports
+    }
+
+    /// Returns the hostname that each tunnel is routed HTTP requests by on the
+    /// server's HTTP port, or `None` for tunnels with a port of their own.
+    pub fn remote_hosts(&self) -> &[Option<String>] {
+        &self.config.remote_hosts
+    }
+
+    /// Returns the session token issued by the server, for resuming the session.
+    pub fn session(&self) -> Uuid {
+        self.config.session
+    }
+
+    /// Returns a handle for adding and closing tunnels while the client listens.
+    pub fn control(&self) -> ClientControl {
+        self.control.clone()
     }
 
     /// Start the client, listening for new connections.
@@ -109,23 +514,120 @@ impl Client {
         let mut conn = self.conn.take().unwrap();
         let config = Arc::new(self.config);
 
+        // The first logical stream of a multiplexed connection carries the
+        // control messages, and each later one a forwarded connection.
+        let mut session = None;
+        if config.multiplex {
+            let mut mux = Session::new_client(conn.into_stream(), mux_config());
+            let control = timeout(NETWORK_TIMEOUT, mux.next())
+                .await
+                .context("timed out waiting for the control stream")?
+                .context("server closed the multiplexed connection")??;
+            conn = Delimited::new(Box::new(control));
+            session = Some(mux);
+        }
+        let accept = async {
+            match session {
+                Some(session) => accept_multiplexed(session, Arc::clone(&config)).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::pin!(accept);
+
+        // The server answers requests in the order they were sent.
+        let mut pending: VecDeque<Request> = VecDeque::new();
+        let liveness = config.liveness;
+        let deadline = liveness.interval * liveness.max_missed;
+        let mut last_seen = Instant::now();
+        let mut next_ping = last_seen + liveness.interval;
+        // Datagrams of each UDP flow go to its own local socket.
+        let mut flows: HashMap<u32, mpsc::Sender<Vec<u8>>> = HashMap::new();
+        let (replies_tx, mut replies) = mpsc::channel(64);
         loop {
-            match conn.recv().await? {
-                Some(ServerMessage::Hello(_)) => warn!("unexpected hello"),
-                Some(ServerMessage::Challenge(_)) => warn!("unexpected challenge"),
+            let message = tokio::select! {
+                message = conn.recv() => {
+                    last_seen = Instant::now();
+                    message?
+                }
+                Some(request) = self.requests.recv() => {
+                    conn.send(&request.message).await?;
+                    pending.push_back(request);
+                    continue;
+                }
+                _ = sleep_until(next_ping) => {
+                    if last_seen.elapsed() > deadline {
+                        warn!(?deadline, "server missed heartbeats");
+                        bail!("no message from the server in {deadline:?}, assuming it is gone");
+                    }
+                    if self.control.supported {
+                        let (request, _) = Request::new(ClientMessage::Ping(fastrand::u64(..)), None);
+                        conn.send(&request.message).await?;
+                        pending.push_back(request);
+                    }
+                    next_ping = Instant::now() + liveness.interval;
+                    flows.retain(|_, datagrams| !datagrams.is_closed());
+                    continue;
+                }
+                Some((flow, data)) = replies.recv() => {
+                    let data = hex::encode(data);
+                    conn.send(ClientMessage::Datagram { flow, data }).await?;
+                    continue;
+                }
+                result = &mut accept => return result,
+            };
+            match message {
+                Some(ServerMessage::Version(_)) => warn!("unexpected version"),
+                Some(ServerMessage::Hello(..) | ServerMessage::HelloTunnels { .. }) => {
+                    warn!("unexpected hello")
+                }
+                Some(ServerMessage::Challenge(_) | ServerMessage::Proof(_)) => {
+                    warn!("unexpected authentication")
+                }
                 Some(ServerMessage::Heartbeat) => (),
-                Some(ServerMessage::Connection(id)) => {
-                    let config = Arc::clone(&config);
-                    tokio::spawn(
-                        async move {
-                            info!("new connection");
-                            match handle_connection(&config, id).await {
-                                Ok(_) => info!("connection exited"),
-                                Err(err) => warn!(%err, "connection exited with error"),
-                            }
-                        }
-                        .instrument(info_span!("proxy", %id)),
-                    );
+                Some(ServerMessage::Ping(value)) => conn.send(ClientMessage::Pong(value)).await?,
+                Some(ServerMessage::Connection(id)) => spawn_connection(&config, 0, id),
+                Some(ServerMessage::TunnelConnection(tunnel, id)) => {
+                    spawn_connection(&config, tunnel, id)
+                }
+                Some(ServerMessage::Datagram { tunnel, flow, data }) => {
+                    let Ok(data) = hex::decode(data) else {
+                        warn!(flow, "invalid datagram from server");
+                        continue;
+                    };
+                    if flows.get(&flow).is_none_or(mpsc::Sender::is_closed) {
+                        match spawn_flow(&config, tunnel, flow, replies_tx.clone()) {
+                            Some(datagrams) => flows.insert(flow, datagrams),
+                            None => continue,
+                        };
+                    }
+                    if flows[&flow].try_send(data).is_err() {
+                        debug!(flow, "local service is behind, dropping datagram");
+                    }
+                }
+                Some(
+                    reply @ (ServerMessage::Pong(_)
+                    | ServerMessage::TunnelAdded { .. }
+                    | ServerMessage::TunnelClosed(_)
+                    | ServerMessage::Refused(_)),
+                ) => {
+                    let Some(request) = pending.pop_front() else {
+                        warn!("unexpected reply");
+                        continue;
+                    };
+                    if let ServerMessage::Pong(_) = reply {
+                        let rtt = request.sent.elapsed();
+                        debug!(?rtt, "measured round-trip time");
+                        *self.control.rtt.lock().unwrap() = Some(rtt);
+                    }
+                    if let (ServerMessage::TunnelAdded { tunnel, .. }, Some(added)) =
+                        (&reply, request.tunnel)
+                    {
+                        // Add the tunnel before reading any connection to it.
+                        let mut tunnels = config.tunnels.write().unwrap();
+                        ensure!(*tunnel == tunnels.len(), "server added tunnel out of order");
+                        tunnels.push(added);
+                    }
+                    request.reply.send(reply).ok();
                 }
                 Some(ServerMessage::Error(err)) => error!(%err, "server error"),
                 None => return Ok(()),
@@ -134,14 +636,229 @@ impl Client {
     }
 }
 
-async fn handle_connection(config: &ClientConfig, id: Uuid) -> Result<()> {
-    let mut remote_conn =
-        Delimited::new(connect_with_timeout(&config.to[..], CONTROL_PORT, &config.tls).await?);
+/// A request sent to the server on the control connection.
+struct Request {
+    message: ClientMessage,
+
+    /// The tunnel to forward to, if the request adds one.
+    tunnel: Option<Tunnel>,
+
+    /// Where to deliver the server's reply.
+    reply: oneshot::Sender<ServerMessage>,
+
+    /// When the request was made.
+    sent: Instant,
+}
+
+impl Request {
+    /// Create a request, along with a receiver for the server's reply.
+    fn new(
+        message: ClientMessage,
+        tunnel: Option<Tunnel>,
+    ) -> (Self, oneshot::Receiver<ServerMessage>) {
+        let (reply, reply_rx) = oneshot::channel();
+        let request = Request {
+            message,
+            tunnel,
+            reply,
+            sent: Instant::now(),
+        };
+        (request, reply_rx)
+    }
+}
+
+/// Handle for adding and closing the tunnels of a listening client, and
+/// measuring the round-trip time to the server.
+///
+/// Requests need a server that supports them.
+#[derive(Clone)]
+pub struct ClientControl {
+    requests: mpsc::Sender<Request>,
+    supported: bool,
+    rtt: Arc<Mutex<Option<Duration>>>,
+}
+
+impl ClientControl {
+    /// Returns the round-trip time to the server measured by the last answered ping.
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.rtt.lock().unwrap()
+    }
+
+    /// Ask the server for another tunnel, returning its index and public port.
+    ///
+    /// Connections to the tunnel are announced with its index, like those of
+    /// the tunnels requested when connecting.
+    pub async fn add_tunnel(&self, tunnel: Tunnel) -> Result<(usize, u16)> {
+        let message = ClientMessage::AddTunnel {
+            port: tunnel.port,
+            access: tunnel.access.clone(),
+            udp: tunnel.udp,
+            http: tunnel.http.clone(),
+        };
+        let local = format!("{}:{}", tunnel.local_host, tunnel.local_port);
+        match self.request(message, Some(tunnel)).await? {
+            ServerMessage::TunnelAdded { tunnel, port, host } => {
+                info!(tunnel, remote_port = port, host, "listening for {local}");
+                Ok((tunnel, port))
+            }
+            ServerMessage::Refused(message) => bail!("server refused tunnel: {message}"),
+            _ => bail!("unexpected reply to adding a tunnel"),
+        }
+    }
+
+    /// Ask the server to stop listening on the tunnel at an index.
+    pub async fn close_tunnel(&self, tunnel: usize) -> Result<()> {
+        match self
+            .request(ClientMessage::CloseTunnel(tunnel), None)
+            .await?
+        {
+            ServerMessage::TunnelClosed(closed) if closed == tunnel => Ok(()),
+            ServerMessage::Refused(message) => bail!("server refused to close tunnel: {message}"),
+            _ => bail!("unexpected reply to closing a tunnel"),
+        }
+    }
+
+    /// Measure the round-trip time to the server on the control connection.
+    pub async fn ping(&self) -> Result<Duration> {
+        let value = fastrand::u64(..);
+        let start = Instant::now();
+        match self.request(ClientMessage::Ping(value), None).await? {
+            ServerMessage::Pong(pong) if pong == value => Ok(start.elapsed()),
+            _ => bail!("unexpected reply to ping"),
+        }
+    }
+
+    async fn request(
+        &self,
+        message: ClientMessage,
+        tunnel: Option<Tunnel>,
+    ) -> Result<ServerMessage> {
+        ensure!(self.supported, "server does not support control requests");
+        let (request, reply_rx) = Request::new(message, tunnel);
+        let closed = || anyhow::anyhow!("client is not connected");
+        self.requests.send(request).await.map_err(|_| closed())?;
+        timeout(NETWORK_TIMEOUT, reply_rx)
+            .await
+            .context("timed out waiting for the server")?
+            .map_err(|_| closed())
+    }
+}
+
+/// Accept a forwarded connection for a tunnel in the background.
+fn spawn_connection(config: &Arc<ClientConfig>, tunnel: usize, id: Uuid) {
+    let config = Arc::clone(config);
+    tokio::spawn(
+        async move {
+            info!(tunnel, "new connection");
+            match handle_connection(&config, tunnel, id).await {
+                Ok(_) => info!("connection exited"),
+                Err(err) => warn!(%err, "connection exited with error"),
+            }
+        }
+        .instrument(info_span!("proxy", %id)),
+    );
+}
+
+/// Relay the datagrams of a new UDP flow to the local service in the background.
+///
+/// Returns the sender for the flow's datagrams, or `None` if the tunnel is
+/// not a UDP tunnel.
+fn spawn_flow(
+    config: &ClientConfig,
+    tunnel: usize,
+    flow: u32,
+    replies: mpsc::Sender<(u32, Vec<u8>)>,
+) -> Option<mpsc::Sender<Vec<u8>>> {
+    let (local_host, local_port) = match config.tunnels.read().unwrap().get(tunnel) {
+        Some(local) if local.udp => (local.local_host.clone(), local.local_port),
+        _ => {
+            warn!(tunnel, "datagram for a tunnel that does not forward udp");
+            return None;
+        }
+    };
+    let (datagrams_tx, datagrams) = mpsc::channel(64);
+    let idle = config.udp_idle_timeout;
+    tokio::spawn(
+        async move {
+            debug!("new udp flow");
+            let local = (&local_host[..], local_port);
+            if let Err(err) = relay_flow(local, flow, datagrams, replies, idle).await {
+                warn!(%err, "udp flow exited with error");
+            }
+        }
+        .instrument(info_span!("udp", tunnel, flow)),
+    );
+    Some(datagrams_tx)
+}
+
+/// Accept logical streams opened by the server for forwarded connections,
+/// until the multiplexed connection closes.
+async fn accept_multiplexed(
+    mut session: Session<Prefixed<Box<dyn StreamTrait>>>,
+    config: Arc<ClientConfig>,
+) -> Result<()> {
+    while let Some(stream) = session.next().await {
+        let remote_conn = Delimited::new(stream?);
+        let config = Arc::clone(&config);
+        tokio::spawn(
+            async move {
+                match handle_multiplexed(&config, remote_conn).await {
+                    Ok(_) => info!("connection exited"),
+                    Err(err) => warn!(%err, "connection exited with error"),
+                }
+            }
+            .instrument(info_span!("proxy")),
+        );
+    }
+    Ok(())
+}
+
+async fn handle_multiplexed(
+    config: &ClientConfig,
+    mut remote_conn: Delimited<StreamHandle>,
+) -> Result<()> {
+    let (tunnel, id) = match remote_conn.recv_timeout().await? {
+        Some(ServerMessage::Connection(id)) => (0, id),
+        Some(ServerMessage::TunnelConnection(tunnel, id)) => (tunnel, id),
+        Some(ServerMessage::Error(err)) => {
+            error!(%err, "server error");
+            return Ok(());
+        }
+        _ => bail!("expected connection id on multiplexed stream"),
+    };
+    info!(tunnel, %id, "new connection");
+    forward_local(config, tunnel, remote_conn).await
+}
+
+async fn handle_connection(config: &ClientConfig, tunnel: usize, id: Uuid) -> Result<()> {
+    let mut remote_conn = Delimited::new(
+        connect_with_timeout(&config.to[..], config.control_port, &config.transport).await?,
+    );
+    ProtocolInfo::new(vec![])
+        .client_negotiate(&mut remote_conn)
+        .await?;
     if let Some(auth) = &config.auth {
-        auth.client_handshake(&mut remote_conn).await?;
+        if config.mutual_auth {
+            auth.client_handshake(&mut remote_conn).await?;
+        } else {
+            auth.legacy_client_handshake(&mut remote_conn).await?;
+        }
     }
-    remote_conn.send(ClientMessage::Accept(id)).await?;
-    let mut local_conn = connect_with_timeout(&config.local_host, config.local_port, &None).await?;
+    remote_conn
+        .send(ClientMessage::Accept(id, config.session))
+        .await?;
+    forward_local(config, tunnel, remote_conn).await
+}
+
+/// Proxy an accepted remote stream to the local address of a tunnel.
+async fn forward_local<S: StreamTrait>(
+    config: &ClientConfig,
+    tunnel: usize,
+    remote_conn: Delimited<S>,
+) -> Result<()> {
+    let tunnel = config.tunnels.read().unwrap().get(tunnel).cloned();
+    let tunnel = tunnel.context("unknown tunnel")?;
+    let mut local_conn = connect_with_timeout(&tunnel.local_host, tunnel.local_port, &None).await?;
     let parts = remote_conn.into_parts();
     debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
     local_conn.write_all(&parts.read_buf).await?; // mostly of the cases, this will be empty
@@ -153,21 +870,20 @@ async fn handle_connection(config: &ClientConfig, id: Uuid) -> Result<()> {
 async fn connect_with_timeout(
     to: &str,
     port: u16,
-    tls: &Option<TlsConnector>,
+    transport: &Option<Transport>,
 ) -> Result<Box<dyn StreamTrait>> {
     let stream = match timeout(NETWORK_TIMEOUT, TcpStream::connect((to, port))).await {
         Ok(res) => res,
         Err(err) => Err(err.into()),
     }
     .with_context(|| format!("could not connect to {to}:{port}"))?;
-    match tls {
-        Some(connector) => {
-            let domain = rustls::ServerName::try_from(to)
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?;
-
-            let stream = connector.connect(domain, stream).await?;
+    match transport {
+        Some(Transport::Tls(tls)) => {
+            let server_name = tls.server_name(to)?;
+            let stream = tls.connector().connect(server_name, stream).await?;
             Ok(Box::new(stream))
         }
+        Some(Transport::Noise(noise)) => Ok(Box::new(noise.connect(stream).await?)),
         None => Ok(Box::new(stream)),
     }
 }
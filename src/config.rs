@@ -0,0 +1,886 @@
+//! Configuration files for the `bore` client and server.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::Deserialize;
+
+use crate::access::{AccessList, MAX_ACCESS_RULES};
+use crate::auth::Credentials;
+use crate::ban::BanSettings;
+use crate::client::{Liveness, Tunnel};
+use crate::http::{is_valid_hostname, normalize_hostname};
+use crate::noise::PublicKey;
+use crate::policy::Policy;
+use crate::shared::MAX_TUNNELS;
+use crate::supervisor::Reconnect;
+use crate::tls::Pin;
+
+/// File name of the client configuration, searched for in default locations.
+pub const CLIENT_CONFIG_FILE: &str = "bore.toml";
+
+/// Configuration file for `bore local`, with values that flags may override.
+///
+/// ```
+/// use bore_cli::config::ClientConfigFile;
+///
+/// let config: ClientConfigFile = r#"
+///     to = "bore.example.com"
+///
+///     [tunnels.web]
+///     local_port = 3000
+/// "#.parse().unwrap();
+/// assert_eq!(config.tunnels().unwrap()[0].local_host, "localhost");
+///
+/// let err = "[tunnels.web]\nlocal_port = 0".parse::<ClientConfigFile>().unwrap_err();
+/// assert!(err.to_string().contains("tunnels.web.local_port"));
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfigFile {
+    /// Address of the remote server to expose local ports to.
+    pub to: Option<String>,
+
+    /// Where to read the secret for authentication from.
+    pub secret: Option<SecretSource>,
+
+    /// ID of the named credential that the secret belongs to.
+    pub key_id: Option<String>,
+
+    /// TLS settings for connections to the server.
+    #[serde(default)]
+    pub tls: ClientTlsConfig,
+
+    /// Noise settings for connections to the server, instead of TLS.
+    #[serde(default)]
+    pub noise: ClientNoiseConfig,
+
+    /// Carry all proxied connections over the single control connection, which
+    /// must use TLS or Noise.
+    pub multiplex: Option<bool>,
+
+    /// Interval between pings to the server, in milliseconds.
+    pub heartbeat_interval_ms: Option<u64>,
+
+    /// Number of intervals without a message from the server, after which
+    /// the client reconnects.
+    pub max_missed_heartbeats: Option<u32>,
+
+    /// How to wait between attempts to reach the server.
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+
+    /// Local addresses to expose, by tunnel name.
+    #[serde(default)]
+    pub tunnels: BTreeMap<String, TunnelConfig>,
+}
+
+/// Source of a secret; exactly one of the fields must be set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecretSource {
+    /// The secret itself.
+    pub value: Option<String>,
+
+    /// Path to a file containing the secret, with surrounding whitespace trimmed.
+    pub file: Option<PathBuf>,
+
+    /// Name of an environment variable containing the secret.
+    pub env: Option<String>,
+}
+
+/// TLS settings of the client configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientTlsConfig {
+    /// Enable tls support for the tunnel.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Path to cafile file for self signed certificates.
+    pub cafile: Option<PathBuf>,
+
+    /// Name to verify the server certificate against, instead of the host of `to`.
+    pub server_name: Option<String>,
+
+    /// SHA-256 hashes of the server certificate or public key to accept,
+    /// instead of verifying it with a CA.
+    #[serde(default)]
+    pub pin_sha256: Vec<String>,
+
+    /// Path to a client certificate to present to the server, or a PKCS#12
+    /// bundle without `client_key`.
+    pub client_cert: Option<PathBuf>,
+
+    /// Path to the PEM key of the client certificate.
+    pub client_key: Option<PathBuf>,
+
+    /// Where to read the password of a PKCS#12 client certificate from.
+    pub client_cert_password: Option<SecretSource>,
+}
+
+impl ClientTlsConfig {
+    /// Returns the parsed server certificate pins.
+    pub fn pins(&self) -> Result<Vec<Pin>> {
+        self.pin_sha256.iter().map(|pin| pin.parse()).collect()
+    }
+}
+
+/// Noise settings of the client configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientNoiseConfig {
+    /// Public key of the server, which enables Noise if set.
+    pub server_key: Option<String>,
+
+    /// Path to the client's private key, by default a new key per run.
+    pub key: Option<PathBuf>,
+}
+
+impl ClientNoiseConfig {
+    /// Returns the parsed server public key, if set.
+    pub fn server_key(&self) -> Result<Option<PublicKey>> {
+        self.server_key.as_deref().map(str::parse).transpose()
+    }
+}
+
+/// Reconnection settings of the client configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReconnectConfig {
+    /// Delay before reconnecting, doubled after each failed attempt, in milliseconds.
+    pub initial_delay_ms: Option<u64>,
+
+    /// Upper bound on the delay before reconnecting, in milliseconds.
+    pub max_delay_ms: Option<u64>,
+
+    /// Number of consecutive failed attempts after which the client gives up.
+    pub max_retries: Option<u32>,
+}
+
+impl ReconnectConfig {
+    /// Returns the settings, with defaults for values that are not set.
+    pub fn settings(&self) -> Reconnect {
+        let default = Reconnect::default();
+        Reconnect {
+            delay: self
+                .initial_delay_ms
+                .map_or(default.delay, Duration::from_millis),
+            max_delay: self
+                .max_delay_ms
+                .map_or(default.max_delay, Duration::from_millis),
+            max_retries: self.max_retries.or(default.max_retries),
+        }
+    }
+}
+
+/// A named tunnel of the client configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelConfig {
+    /// The local host to expose.
+    #[serde(default = "default_local_host")]
+    pub local_host: String,
+
+    /// The local port to expose.
+    pub local_port: u16,
+
+    /// Optional port on the remote server to select.
+    #[serde(default)]
+    pub remote_port: u16,
+
+    /// Networks that may connect to the remote port, any network if empty.
+    #[serde(default)]
+    pub allow: Vec<String>,
+
+    /// Networks that may not connect to the remote port.
+    #[serde(default)]
+    pub deny: Vec<String>,
+
+    /// Forward UDP datagrams instead of TCP connections.
+    #[serde(default)]
+    pub udp: bool,
+
+    /// Route HTTP requests for a generated hostname on the server's HTTP
+    /// port, instead of opening a remote port.
+    #[serde(default)]
+    pub http: bool,
+
+    /// Route HTTP requests for this hostname, or this subdomain of the
+    /// server's domain if it has no dot, which implies `http`.
+    pub hostname: Option<String>,
+}
+
+fn default_local_host() -> String {
+    "localhost".into()
+}
+
+/// Configuration file for `bore server`, reloaded on `SIGHUP`.
+///
+/// ```
+/// use bore_cli::config::ServerConfigFile;
+///
+/// let config: ServerConfigFile = "min_port = 20000\nmax_port = 20100".parse().unwrap();
+/// assert_eq!(config.port_range(), Some(20000..=20100));
+///
+/// let err = "min_port = 30000\nmax_port = 20000".parse::<ServerConfigFile>().unwrap_err();
+/// assert!(err.to_string().starts_with("max_port:"));
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfigFile {
+    /// TCP port used for control connections, only read at startup.
+    pub control_port: Option<u16>,
+
+    /// Addresses to accept control connections on, only read at startup.
+    pub bind_addrs: Option<Vec<IpAddr>>,
+
+    /// Address to bind public tunnel listeners to.
+    pub bind_tunnels: Option<IpAddr>,
+
+    /// Address of the metrics server, only read at startup.
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Address of the admin server for managing bans, only read at startup.
+    pub admin_addr: Option<SocketAddr>,
+
+    /// Port shared by HTTP tunnels, routed by hostname, only read at startup.
+    pub http_port: Option<u16>,
+
+    /// Base domain of the hostnames of HTTP tunnels, required with `http_port`.
+    pub http_domain: Option<String>,
+
+    /// Interval between heartbeats on control connections, in milliseconds.
+    pub heartbeat_interval_ms: Option<u64>,
+
+    /// Number of heartbeat intervals without a message from a client that
+    /// answers pings, after which its control connection is closed.
+    pub max_missed_heartbeats: Option<u32>,
+
+    /// Time after which unaccepted incoming connections are discarded, in seconds.
+    pub stale_timeout_secs: Option<u64>,
+
+    /// Time that the tunnels of a disconnected client are held for it to
+    /// resume its session, in seconds, or 0 to close them right away.
+    pub resume_grace_secs: Option<u64>,
+
+    /// Time without datagrams after which the peer of a UDP tunnel is forgotten, in seconds.
+    pub udp_idle_timeout_secs: Option<u64>,
+
+    /// Minimum accepted TCP port number.
+    pub min_port: Option<u16>,
+
+    /// Maximum accepted TCP port number.
+    pub max_port: Option<u16>,
+
+    /// Where to read the secret for authentication from.
+    pub secret: Option<SecretSource>,
+
+    /// Path to a file of named client credentials.
+    pub credentials: Option<PathBuf>,
+
+    /// Reject older clients that authenticate without a mutual handshake.
+    pub require_mutual_auth: Option<bool>,
+
+    /// TLS settings for control connections.
+    #[serde(default)]
+    pub tls: ServerTlsConfig,
+
+    /// Noise settings for control connections, instead of TLS.
+    #[serde(default)]
+    pub noise: ServerNoiseConfig,
+
+    /// Thresholds for delaying and banning clients that fail to authenticate.
+    #[serde(default)]
+    pub bans: BansConfig,
+
+    /// Networks that may connect to the control port and to tunnels.
+    #[serde(default)]
+    pub access: ServerAccessConfig,
+}
+
+/// TLS settings of the server configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTlsConfig {
+    /// Enable tls support for the tunnel.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Path to cert file, or a PKCS#12 bundle without `key`.
+    pub cert: Option<PathBuf>,
+
+    /// Path to key file, in PKCS#8, PKCS#1 or SEC1 PEM format.
+    pub key: Option<PathBuf>,
+
+    /// Where to read the password of a PKCS#12 bundle from.
+    pub password: Option<SecretSource>,
+
+    /// Path to CA certificates that client certificates must be signed by.
+    pub client_ca: Option<PathBuf>,
+
+    /// Time allowed for a client to complete the TLS handshake, in milliseconds.
+    pub handshake_timeout_ms: Option<u64>,
+
+    /// Maximum number of TLS handshakes in progress at once.
+    pub max_handshakes: Option<usize>,
+}
+
+/// Noise settings of the server configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerNoiseConfig {
+    /// Path to the server's private key, which enables Noise if set.
+    pub key: Option<PathBuf>,
+
+    /// Public keys of the clients allowed to connect, any client if empty.
+    #[serde(default)]
+    pub clients: Vec<String>,
+}
+
+impl ServerNoiseConfig {
+    /// Returns the parsed client public keys.
+    pub fn clients(&self) -> Result<Vec<PublicKey>> {
+        self.clients.iter().map(|key| key.parse()).collect()
+    }
+}
+
+/// Brute-force protection settings of the server configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BansConfig {
+    /// Number of failed authentications after which an address is banned, or 0 to never ban.
+    pub max_failures: Option<u32>,
+
+    /// Delay before replying to the first failure, doubled for each further one, in milliseconds.
+    pub delay_ms: Option<u64>,
+
+    /// Upper bound on the delay before replying to a failure, in milliseconds.
+    pub max_delay_ms: Option<u64>,
+
+    /// Duration of a ban, and time after which failures are forgotten, in seconds.
+    pub ban_secs: Option<u64>,
+
+    /// Number of authentications that may be in progress at once from one address, or 0 for no limit.
+    pub max_pending: Option<usize>,
+
+    /// Length of the prefix that IPv6 addresses are tracked by.
+    pub ipv6_prefix: Option<u8>,
+}
+
+impl BansConfig {
+    /// Returns the settings, with defaults for values that are not set.
+    pub fn settings(&self) -> BanSettings {
+        let default = BanSettings::default();
+        BanSettings {
+            max_failures: self.max_failures.unwrap_or(default.max_failures),
+            delay: self.delay_ms.map_or(default.delay, Duration::from_millis),
+            max_delay: self
+                .max_delay_ms
+                .map_or(default.max_delay, Duration::from_millis),
+            ban_duration: self
+                .ban_secs
+                .map_or(default.ban_duration, Duration::from_secs),
+            max_pending: self.max_pending.unwrap_or(default.max_pending),
+            ipv6_prefix: self.ipv6_prefix.unwrap_or(default.ipv6_prefix),
+        }
+    }
+}
+
+/// Access lists of the server configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerAccessConfig {
+    /// Networks that may open control connections.
+    #[serde(default)]
+    pub control: AccessConfig,
+
+    /// Networks that may connect to any tunnel, which clients may restrict further.
+    #[serde(default)]
+    pub tunnels: AccessConfig,
+}
+
+/// Networks in CIDR notation, or single addresses, that may or may not connect.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Networks allowed to connect, any network if empty.
+    #[serde(default)]
+    pub allow: Vec<String>,
+
+    /// Networks denied, even if they are allowed.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl AccessConfig {
+    /// Returns the parsed access list.
+    pub fn list(&self) -> Result<AccessList> {
+        AccessList::parse(&self.allow, &self.deny)
+    }
+}
+
+/// File of named client credentials for `bore server`, reloaded on `SIGHUP`.
+///
+/// ```
+/// use bore_cli::config::CredentialsFile;
+///
+/// let file: CredentialsFile = r#"
+///     [keys.laptop]
+///     value = "s3cret"
+///     allowed_ports = ["20000-20099", "8080"]
+///     max_tunnels = 2
+/// "#.parse().unwrap();
+/// let credentials = file.credentials().unwrap();
+/// assert_eq!(credentials.policy("laptop").unwrap().port_ranges, vec![20000..=20099, 8080..=8080]);
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CredentialsFile {
+    /// Secret and policy of each credential, by key ID.
+    #[serde(default)]
+    pub keys: BTreeMap<String, KeyConfig>,
+}
+
+/// A named credential of the credentials file.
+///
+/// Exactly one of `value`, `file` or `env` must be set, as in [`SecretSource`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    /// The secret itself.
+    pub value: Option<String>,
+
+    /// Path to a file containing the secret, with surrounding whitespace trimmed.
+    pub file: Option<PathBuf>,
+
+    /// Name of an environment variable containing the secret.
+    pub env: Option<String>,
+
+    /// Remote ports that may be requested, as `port` or `min-max`; any port if empty.
+    #[serde(default)]
+    pub allowed_ports: Vec<String>,
+
+    /// Maximum number of tunnels open at once.
+    pub max_tunnels: Option<usize>,
+
+    /// Maximum number of proxied connections open at once.
+    pub max_connections: Option<usize>,
+
+    /// Only allow encrypted control connections, over TLS or Noise.
+    #[serde(default)]
+    pub require_tls: bool,
+}
+
+/// Read and parse a configuration file, returning it along with its directory.
+fn read_config<T>(path: &Path) -> Result<(T, &Path)>
+where
+    T: FromStr<Err = anyhow::Error>,
+{
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("could not read config file {}", path.display()))?;
+    let config = text
+        .parse()
+        .with_context(|| format!("invalid config file {}", path.display()))?;
+    Ok((config, path.parent().unwrap_or_else(|| Path::new(""))))
+}
+
+/// Resolve a path from a configuration file against the file's directory.
+fn resolve(base: &Path, path: &mut Option<PathBuf>) {
+    if let Some(path) = path {
+        *path = base.join(&path);
+    }
+}
+
+fn validate_liveness(heartbeat_interval_ms: Option<u64>, max_missed: Option<u32>) -> Result<()> {
+    ensure!(
+        heartbeat_interval_ms != Some(0),
+        "heartbeat_interval_ms: must be positive"
+    );
+    ensure!(
+        max_missed != Some(0),
+        "max_missed_heartbeats: must be positive"
+    );
+    Ok(())
+}
+
+fn validate_secret(secret: &Option<SecretSource>) -> Result<()> {
+    if let Some(secret) = secret {
+        ensure!(
+            secret.sources() == 1,
+            "secret: exactly one of `value`, `file` or `env` must be set"
+        );
+    }
+    Ok(())
+}
+
+impl ClientConfigFile {
+    /// Read and validate a configuration file.
+    ///
+    /// Relative paths in the file are resolved against the file's directory.
+    pub fn load(path: &Path) -> Result<Self> {
+        let (mut config, base): (Self, _) = read_config(path)?;
+        if let Some(secret) = &mut config.secret {
+            resolve(base, &mut secret.file);
+        }
+        resolve(base, &mut config.tls.cafile);
+        resolve(base, &mut config.tls.client_cert);
+        resolve(base, &mut config.tls.client_key);
+        if let Some(password) = &mut config.tls.client_cert_password {
+            resolve(base, &mut password.file);
+        }
+        resolve(base, &mut config.noise.key);
+        Ok(config)
+    }
+
+    /// Search the default locations for a configuration file.
+    ///
+    /// These are `bore.toml` in the working directory, then `bore/bore.toml`
+    /// in `$XDG_CONFIG_HOME` or `~/.config`.
+    pub fn find() -> Option<PathBuf> {
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        let mut candidates = vec![PathBuf::from(CLIENT_CONFIG_FILE)];
+        if let Some(dir) = config_dir {
+            candidates.push(dir.join("bore").join(CLIENT_CONFIG_FILE));
+        }
+        candidates.into_iter().find(|path| path.is_file())
+    }
+
+    /// Returns the configured tunnels, ordered by name.
+    pub fn tunnels(&self) -> Result<Vec<Tunnel>> {
+        self.tunnels
+            .iter()
+            .map(|(name, tunnel)| {
+                Ok(Tunnel {
+                    local_host: tunnel.local_host.clone(),
+                    local_port: tunnel.local_port,
+                    port: tunnel.remote_port,
+                    access: AccessList::parse(&tunnel.allow, &tunnel.deny)
+                        .with_context(|| format!("tunnels.{name}"))?,
+                    udp: tunnel.udp,
+                    http: (tunnel.http || tunnel.hostname.is_some())
+                        .then(|| tunnel.hostname.clone().unwrap_or_default()),
+                })
+            })
+            .collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        validate_secret(&self.secret)?;
+        if let Some(key_id) = &self.key_id {
+            ensure!(!key_id.is_empty(), "key_id: must not be empty");
+            ensure!(self.secret.is_some(), "key_id: requires a secret");
+        }
+        ensure!(
+            self.tls.client_key.is_none() || self.tls.client_cert.is_some(),
+            "tls.client_key: requires tls.client_cert"
+        );
+        self.tls.pins().context("tls.pin_sha256")?;
+        ensure!(
+            self.tls.pin_sha256.is_empty() || self.tls.cafile.is_none(),
+            "tls.pin_sha256: cannot be used with tls.cafile"
+        );
+        let server_key = self.noise.server_key().context("noise.server_key")?;
+        ensure!(
+            server_key.is_some() || self.noise.key.is_none(),
+            "noise.key: requires noise.server_key"
+        );
+        ensure!(
+            server_key.is_none() || !self.tls.enabled,
+            "noise.server_key: cannot be used with tls"
+        );
+        ensure!(
+            self.tunnels.len() <= MAX_TUNNELS,
+            "tunnels: at most {MAX_TUNNELS} tunnels may be exposed"
+        );
+        for (name, tunnel) in &self.tunnels {
+            ensure!(
+                !tunnel.local_host.is_empty(),
+                "tunnels.{name}.local_host: must not be empty"
+            );
+            ensure!(
+                tunnel.local_port != 0,
+                "tunnels.{name}.local_port: must be a nonzero port"
+            );
+            ensure!(
+                tunnel.allow.len() + tunnel.deny.len() <= MAX_ACCESS_RULES,
+                "tunnels.{name}: at most {MAX_ACCESS_RULES} networks may be listed"
+            );
+            ensure!(
+                !tunnel.udp || self.multiplex != Some(true),
+                "tunnels.{name}.udp: cannot be used with multiplex"
+            );
+            if let Some(hostname) = &tunnel.hostname {
+                ensure!(
+                    is_valid_hostname(&normalize_hostname(hostname)),
+                    "tunnels.{name}.hostname: invalid hostname"
+                );
+            }
+            ensure!(
+                !tunnel.udp || (!tunnel.http && tunnel.hostname.is_none()),
+                "tunnels.{name}.udp: cannot be used with http"
+            );
+        }
+        self.tunnels()?;
+        let reconnect = self.reconnect.settings();
+        ensure!(
+            !reconnect.delay.is_zero(),
+            "reconnect.initial_delay_ms: must be positive"
+        );
+        ensure!(
+            reconnect.max_delay >= reconnect.delay,
+            "reconnect.max_delay_ms: must not be below reconnect.initial_delay_ms"
+        );
+        validate_liveness(self.heartbeat_interval_ms, self.max_missed_heartbeats)
+    }
+
+    /// Returns the liveness settings, with defaults for values that are not set.
+    pub fn liveness(&self) -> Liveness {
+        let default = Liveness::default();
+        Liveness {
+            interval: self
+                .heartbeat_interval_ms
+                .map_or(default.interval, Duration::from_millis),
+            max_missed: self.max_missed_heartbeats.unwrap_or(default.max_missed),
+        }
+    }
+}
+
+impl FromStr for ClientConfigFile {
+    type Err = anyhow::Error;
+
+    /// Parse and validate the contents of a configuration file.
+    fn from_str(s: &str) -> Result<Self> {
+        let config: Self = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl ServerConfigFile {
+    /// Read and validate a configuration file.
+    ///
+    /// Relative paths in the file are resolved against the file's directory.
+    pub fn load(path: &Path) -> Result<Self> {
+        let (mut config, base): (Self, _) = read_config(path)?;
+        if let Some(secret) = &mut config.secret {
+            resolve(base, &mut secret.file);
+        }
+        resolve(base, &mut config.credentials);
+        resolve(base, &mut config.tls.cert);
+        resolve(base, &mut config.tls.key);
+        resolve(base, &mut config.tls.client_ca);
+        if let Some(password) = &mut config.tls.password {
+            resolve(base, &mut password.file);
+        }
+        resolve(base, &mut config.noise.key);
+        Ok(config)
+    }
+
+    /// Returns the configured port range, if either bound is set.
+    pub fn port_range(&self) -> Option<RangeInclusive<u16>> {
+        if self.min_port.is_none() && self.max_port.is_none() {
+            return None;
+        }
+        Some(self.min_port.unwrap_or(1024)..=self.max_port.unwrap_or(65535))
+    }
+
+    fn validate(&self) -> Result<()> {
+        validate_secret(&self.secret)?;
+        ensure!(
+            !self.bind_addrs.as_ref().is_some_and(Vec::is_empty),
+            "bind_addrs: must not be empty"
+        );
+        if let Some(range) = self.port_range() {
+            ensure!(!range.is_empty(), "max_port: must not be below min_port");
+        }
+        validate_liveness(self.heartbeat_interval_ms, self.max_missed_heartbeats)?;
+        ensure!(
+            self.udp_idle_timeout_secs != Some(0),
+            "udp_idle_timeout_secs: must be positive"
+        );
+        ensure!(
+            self.http_port != Some(0),
+            "http_port: must be a nonzero port"
+        );
+        if let Some(domain) = &self.http_domain {
+            ensure!(
+                is_valid_hostname(&normalize_hostname(domain)),
+                "http_domain: invalid domain"
+            );
+        }
+        if self.tls.enabled {
+            ensure!(
+                self.tls.cert.is_some(),
+                "tls.cert: must be set if tls is enabled"
+            );
+        }
+        ensure!(
+            self.tls.handshake_timeout_ms != Some(0),
+            "tls.handshake_timeout_ms: must be positive"
+        );
+        ensure!(
+            self.tls.max_handshakes != Some(0),
+            "tls.max_handshakes: must be positive"
+        );
+        self.noise.clients().context("noise.clients")?;
+        ensure!(
+            self.noise.clients.is_empty() || self.noise.key.is_some(),
+            "noise.clients: requires noise.key"
+        );
+        ensure!(
+            self.noise.key.is_none() || !self.tls.enabled,
+            "noise.key: cannot be used with tls"
+        );
+        let bans = self.bans.settings();
+        ensure!(
+            bans.max_delay >= bans.delay,
+            "bans.max_delay_ms: must not be below bans.delay_ms"
+        );
+        ensure!(
+            !bans.ban_duration.is_zero(),
+            "bans.ban_secs: must be positive"
+        );
+        ensure!(
+            (1..=128).contains(&bans.ipv6_prefix),
+            "bans.ipv6_prefix: must be between 1 and 128"
+        );
+        self.access.control.list().context("access.control")?;
+        self.access.tunnels.list().context("access.tunnels")?;
+        Ok(())
+    }
+}
+
+impl FromStr for ServerConfigFile {
+    type Err = anyhow::Error;
+
+    /// Parse and validate the contents of a configuration file.
+    fn from_str(s: &str) -> Result<Self> {
+        let config: Self = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl CredentialsFile {
+    /// Read and validate a credentials file.
+    ///
+    /// Relative paths in the file are resolved against the file's directory.
+    pub fn load(path: &Path) -> Result<Self> {
+        let (mut file, base): (Self, _) = read_config(path)?;
+        for key in file.keys.values_mut() {
+            resolve(base, &mut key.file);
+        }
+        Ok(file)
+    }
+
+    /// Read the secret and policy of every credential.
+    pub fn credentials(&self) -> Result<Credentials> {
+        let mut credentials = Credentials::default();
+        for (key_id, key) in &self.keys {
+            let secret = key
+                .secret()
+                .read()
+                .with_context(|| format!("keys.{key_id}"))?;
+            credentials.insert_with_policy(key_id, &secret, key.policy()?);
+        }
+        Ok(credentials)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (key_id, key) in &self.keys {
+            ensure!(!key_id.is_empty(), "keys: key ID must not be empty");
+            ensure!(
+                key.secret().sources() == 1,
+                "keys.{key_id}: exactly one of `value`, `file` or `env` must be set"
+            );
+            key.policy()
+                .with_context(|| format!("keys.{key_id}.allowed_ports"))?;
+        }
+        Ok(())
+    }
+}
+
+impl KeyConfig {
+    fn secret(&self) -> SecretSource {
+        SecretSource {
+            value: self.value.clone(),
+            file: self.file.clone(),
+            env: self.env.clone(),
+        }
+    }
+
+    fn policy(&self) -> Result<Policy> {
+        let port_ranges = self
+            .allowed_ports
+            .iter()
+            .map(|range| parse_port_range(range))
+            .collect::<Result<_>>()?;
+        Ok(Policy {
+            port_ranges,
+            max_tunnels: self.max_tunnels,
+            max_connections: self.max_connections,
+            require_tls: self.require_tls,
+        })
+    }
+}
+
+/// Parse a port range of the form `port` or `min-max`.
+fn parse_port_range(range: &str) -> Result<RangeInclusive<u16>> {
+    let (min, max) = range.split_once('-').unwrap_or((range, range));
+    let parse = |port: &str| port.trim().parse::<u16>();
+    let ports = parse(min)
+        .and_then(|min| Ok(min..=parse(max)?))
+        .with_context(|| format!("invalid port range {range:?}"))?;
+    ensure!(!ports.is_empty(), "empty port range {range:?}");
+    Ok(ports)
+}
+
+impl FromStr for CredentialsFile {
+    type Err = anyhow::Error;
+
+    /// Parse and validate the contents of a credentials file.
+    fn from_str(s: &str) -> Result<Self> {
+        let file: Self = toml::from_str(s)?;
+        file.validate()?;
+        Ok(file)
+    }
+}
+
+impl SecretSource {
+    /// Returns the number of sources that are set.
+    fn sources(&self) -> usize {
+        [
+            self.value.is_some(),
+            self.file.is_some(),
+            self.env.is_some(),
+        ]
+        .iter()
+        .filter(|set| **set)
+        .count()
+    }
+
+    /// Read the secret from its source.
+    pub fn read(&self) -> Result<String> {
+        if let Some(value) = &self.value {
+            Ok(value.clone())
+        } else if let Some(file) = &self.file {
+            let secret = fs::read_to_string(file)
+                .with_context(|| format!("secret.file: could not read {}", file.display()))?;
+            Ok(secret.trim().to_string())
+        } else if let Some(name) = &self.env {
+            env::var(name).with_context(|| format!("secret.env: variable {name} is not set"))
+        } else {
+            bail!("secret: no source set")
+        }
+    }
+}
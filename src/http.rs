@@ -0,0 +1,164 @@
+//! HTTP tunnels, routed by hostname on a port shared by all clients.
+//!
+//! The server reads the head of each request on its HTTP port, and hands the
+//! connection to the tunnel that registered the `Host` it names, replaying
+//! the head before the rest of the connection. Since later requests on the
+//! connection may name another host, the service is asked to close it after
+//! the first.
+
+use std::io;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Maximum length of the head of a request, up to the end of its headers.
+pub const MAX_HEAD_LENGTH: usize = 8192;
+
+/// Maximum number of routed connections waiting for their tunnel to take them.
+pub const HTTP_BACKLOG: usize = 16;
+
+/// Length of the random label of a generated hostname.
+const GENERATED_LABEL_LENGTH: usize = 8;
+
+/// Read the head of an HTTP request, up to and including the empty line
+/// after its headers.
+///
+/// Fails if the connection is closed before, or the head is longer than
+/// [`MAX_HEAD_LENGTH`].
+pub async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<BytesMut> {
+    let mut head = BytesMut::with_capacity(1024);
+    loop {
+        if head.windows(4).any(|window| window == b"\r\n\r\n") {
+            return Ok(head);
+        }
+        if head.len() >= MAX_HEAD_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too long",
+            ));
+        }
+        if stream.read_buf(&mut head).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Returns the hostname that the `Host` header of a request head names,
+/// lowercased and without a port.
+///
+/// ```
+/// use bore_cli::http::request_host;
+///
+/// let head = b"GET / HTTP/1.1\r\nHOST: App.Example.com:8080\r\n\r\n";
+/// assert_eq!(request_host(head).as_deref(), Some("app.example.com"));
+/// assert_eq!(request_host(b"GET / HTTP/1.0\r\n\r\n"), None);
+/// ```
+pub fn request_host(head: &[u8]) -> Option<String> {
+    let head = std::str::from_utf8(head).ok()?;
+    let value = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("host").then(|| value.trim())
+    })?;
+    let host = match value.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => value,
+    };
+    let host = normalize_hostname(host);
+    is_valid_hostname(&host).then_some(host)
+}
+
+/// Rewrite the head of a request so that the service closes the connection
+/// after answering it, keeping any bytes read past the head.
+///
+/// Requests that upgrade the connection, as for WebSockets, are left as they
+/// are, since no further requests follow on it.
+///
+/// ```
+/// use bore_cli::http::close_after_request;
+///
+/// let head = b"GET / HTTP/1.1\r\nHost: app\r\nConnection: keep-alive\r\n\r\n";
+/// let expected = b"GET / HTTP/1.1\r\nHost: app\r\nConnection: close\r\n\r\n";
+/// assert_eq!(&close_after_request(head)[..], expected);
+///
+/// let upgrade = b"GET / HTTP/1.1\r\nHost: app\r\nConnection: Upgrade\r\n\r\n";
+/// assert_eq!(&close_after_request(upgrade)[..], upgrade);
+/// ```
+pub fn close_after_request(head: &[u8]) -> BytesMut {
+    let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return head.into();
+    };
+    let mut rewritten = BytesMut::with_capacity(head.len() + 32);
+    for (index, line) in head[..end].split(|&b| b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let (name, value) = match line.iter().position(|&b| b == b':') {
+            Some(colon) if index > 0 => (&line[..colon], &line[colon + 1..]),
+            _ => (line, &[][..]),
+        };
+        if name.eq_ignore_ascii_case(b"connection") {
+            let value = String::from_utf8_lossy(value).to_ascii_lowercase();
+            if value.split(',').any(|token| token.trim() == "upgrade") {
+                return head.into();
+            }
+            continue;
+        }
+        if name.eq_ignore_ascii_case(b"keep-alive") {
+            continue;
+        }
+        rewritten.extend_from_slice(line);
+        rewritten.extend_from_slice(b"\r\n");
+    }
+    rewritten.extend_from_slice(b"Connection: close\r\n\r\n");
+    rewritten.extend_from_slice(&head[end + 4..]);
+    rewritten
+}
+
+/// Lowercase a hostname, and remove the dot that may end a fully qualified name.
+pub fn normalize_hostname(host: &str) -> String {
+    host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase()
+}
+
+/// Returns whether a name is a valid DNS hostname, of lowercase letters,
+/// digits and inner hyphens.
+///
+/// ```
+/// use bore_cli::http::is_valid_hostname;
+///
+/// assert!(is_valid_hostname("my-app.bore.example.com"));
+/// assert!(!is_valid_hostname("-app.example.com"));
+/// assert!(!is_valid_hostname("app..example.com"));
+/// ```
+pub fn is_valid_hostname(host: &str) -> bool {
+    host.len() <= 253
+        && host.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        })
+}
+
+/// Returns a random subdomain of a base domain.
+pub fn generate_hostname(domain: &str) -> String {
+    let label: String = (0..GENERATED_LABEL_LENGTH)
+        .map(|_| fastrand::alphanumeric().to_ascii_lowercase())
+        .collect();
+    format!("{label}.{domain}")
+}
+
+/// Returns a complete response with an HTML page explaining an error, after
+/// which the connection is closed.
+///
+/// The detail is inserted as is, so it must not contain untrusted markup.
+pub fn error_response(status: u16, reason: &str, detail: &str) -> String {
+    let body = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{status} {reason}</title></head>\n<body>\n\
+         <h1>{reason}</h1>\n<p>{detail}</p>\n<hr>\n<p>bore</p>\n</body>\n</html>\n"
+    );
+    format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
@@ -15,9 +15,19 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+pub mod access;
 pub mod auth;
+pub mod ban;
 pub mod byte_counter;
+pub mod ca;
 pub mod client;
+pub mod config;
+pub mod http;
 pub mod metrics;
+pub mod noise;
+pub mod policy;
 pub mod server;
 pub mod shared;
+pub mod supervisor;
+pub mod tls;
+pub mod udp;
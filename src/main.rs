@@ -1,19 +1,39 @@
-use anyhow::{Ok, Result};
+use anyhow::{ensure, Context, Ok, Result};
 use bore_cli::{
-    byte_counter::bytes_per_second_calculator, client::Client, metrics::start_metric_server,
-    server::Server,
+    access::{parse_net, AccessList},
+    auth::Authenticator,
+    byte_counter::bytes_per_second_calculator,
+    ca::CertificateAuthority,
+    client::{Liveness, Transport, Tunnel},
+    config::{ClientConfigFile, CredentialsFile, SecretSource, ServerConfigFile},
+    http::{is_valid_hostname, normalize_hostname},
+    metrics::{start_admin_server, start_metric_server, ADMIN_ADDR, METRICS_ADDR},
+    noise::{Keypair, NoiseClient, NoiseServer, PublicKey},
+    server::{Server, ServerSettings, SettingsHandle},
+    shared::{
+        CONTROL_PORT, HANDSHAKE_TIMEOUT, HEARTBEAT_INTERVAL, MAX_HANDSHAKES, MAX_MISSED_HEARTBEATS,
+        RESUME_GRACE, STALE_TIMEOUT, UDP_IDLE_TIMEOUT,
+    },
+    supervisor::Supervisor,
+    tls::{
+        load_certs, load_identity, watch_identity, CertResolver, ClientTls, IdentityFiles, Pin,
+        ServerVerifier, CERT_POLL_INTERVAL,
+    },
 };
-use clap::{Parser, Subcommand};
-use rustls_pemfile::certs;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use ipnet::IpNet;
 use std::{
-    fs::File,
-    io::{self, BufReader},
-    path::PathBuf,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio_rustls::{
-    rustls::{self, Certificate, OwnedTrustAnchor, PrivateKey},
-    webpki, TlsAcceptor, TlsConnector,
+    rustls::{self, server::AllowAnyAuthenticatedClient, Certificate, PrivateKey},
+    TlsAcceptor, TlsConnector,
 };
 use tracing::{error, info, info_span, Instrument};
 
@@ -30,20 +50,28 @@ enum Command {
     /// Starts a local proxy to the remote server.
     Local {
         /// The local port to expose.
-        local_port: u16,
+        local_port: Option<u16>,
 
         /// The local host to expose.
         #[clap(short, long, value_name = "HOST", default_value = "localhost")]
         local_host: String,
 
-        /// Address of the remote server to expose local ports to.
+        /// Address of the remote server to expose local ports to, as host[:port].
         #[clap(short, long)]
-        to: String,
+        to: Option<String>,
+
+        /// Optional port on the remote server to select.
+        #[clap(short, long, default_value_t = 0)]
+        port: u16,
 
         /// Optional secret for authentication.
         #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
         secret: Option<String>,
 
+        /// ID of the named credential that the secret belongs to.
+        #[clap(long, env = "BORE_KEY_ID")]
+        key_id: Option<String>,
+
         /// Enable tls support for the tunnel.
         #[clap(long)]
         tls: bool,
@@ -51,41 +79,449 @@ enum Command {
         /// Path to cafile file for self signed certifactes, if tls is enabled.
         #[clap(long)]
         cafile: Option<PathBuf>,
+
+        /// Name to verify the server certificate against, instead of the host of --to.
+        #[clap(long, value_name = "NAME")]
+        tls_server_name: Option<String>,
+
+        /// Only accept a server whose certificate or public key has this SHA-256 hash, may be repeated.
+        #[clap(long, value_name = "HASH", conflicts_with = "cafile")]
+        pin_sha256: Vec<Pin>,
+
+        /// Path to a client certificate to present to the server, if tls is enabled.
+        ///
+        /// Without --client-key, this is read as a PKCS#12 bundle.
+        #[clap(long)]
+        client_cert: Option<PathBuf>,
+
+        /// Path to the PEM key of the client certificate.
+        #[clap(long, requires = "client_cert")]
+        client_key: Option<PathBuf>,
+
+        /// Password of the client certificate's PKCS#12 bundle.
+        #[clap(long, env = "BORE_CLIENT_CERT_PASSWORD", hide_env_values = true)]
+        client_cert_password: Option<String>,
+
+        /// Encrypt connections with Noise, accepting only the server with this public key.
+        #[clap(long, value_name = "SERVER_KEY", conflicts_with = "tls")]
+        noise: Option<PublicKey>,
+
+        /// Path to the client's Noise private key, by default a new key per run.
+        #[clap(long, value_name = "PATH")]
+        noise_key: Option<PathBuf>,
+
+        /// Carry all proxied connections over the single control connection, which
+        /// must use TLS or Noise.
+        #[clap(long)]
+        multiplex: bool,
+
+        /// Interval between pings to the server, in milliseconds [default: 2000].
+        #[clap(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+        heartbeat_interval_ms: Option<u64>,
+
+        /// Reconnect after this many intervals without a message from the server [default: 3].
+        #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        max_missed_heartbeats: Option<u32>,
+
+        /// Give up after this many consecutive failed attempts to reach the server.
+        #[clap(long, value_name = "N")]
+        max_retries: Option<u32>,
+
+        /// Additional local address to expose, as local_host:local_port[:remote_port].
+        #[clap(short, long, value_name = "ADDRESS")]
+        expose: Vec<Tunnel>,
+
+        /// Only let this network connect to the exposed ports, may be repeated.
+        #[clap(long, value_name = "CIDR", value_parser = parse_net)]
+        allow: Vec<IpNet>,
+
+        /// Do not let this network connect to the exposed ports, may be repeated.
+        #[clap(long, value_name = "CIDR", value_parser = parse_net)]
+        deny: Vec<IpNet>,
+
+        /// Forward UDP datagrams instead of TCP connections on the exposed ports.
+        #[clap(long, conflicts_with = "multiplex")]
+        udp: bool,
+
+        /// Route HTTP requests for a generated hostname on the server's HTTP port, instead of opening a remote port.
+        #[clap(long, conflicts_with = "udp")]
+        http: bool,
+
+        /// Route HTTP requests for this hostname, or this subdomain of the server's domain.
+        #[clap(long, value_name = "NAME", conflicts_with = "udp")]
+        hostname: Option<String>,
+
+        /// Path to a config file, by default bore.toml in the working or config directory.
+        #[clap(short, long)]
+        config: Option<PathBuf>,
     },
 
     /// Runs the remote proxy server.
-    Server {
-        /// Optional secret for authentication.
-        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
-        secret: Option<String>,
+    Server(ServerArgs),
 
-        /// Enable tls support for the tunnel.
+    /// Generates TLS certificates with a built-in certificate authority.
+    Cert {
+        #[clap(subcommand)]
+        command: CertCommand,
+    },
+
+    /// Generates a Noise keypair, written to PATH and PATH.pub.
+    Keygen {
+        /// Path to write the private key to.
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CertCommand {
+    /// Creates a new certificate authority, as ca.crt and ca.key.
+    Init {
+        /// Directory to write the files to.
+        #[clap(long, default_value = "certs")]
+        dir: PathBuf,
+
+        /// Common name of the authority.
+        #[clap(long, default_value = "bore CA")]
+        name: String,
+
+        /// Number of days the certificate is valid for.
+        #[clap(long, default_value_t = 3650)]
+        days: u32,
+    },
+
+    /// Issues a server or client certificate signed by the authority.
+    Issue {
+        /// DNS name or IP address that the certificate is valid for, may be repeated.
+        #[clap(long = "san", value_name = "NAME", required = true)]
+        sans: Vec<String>,
+
+        /// Common name of the certificate [default: the first --san].
         #[clap(long)]
-        tls: bool,
+        name: Option<String>,
 
-        /// Path to cert file.
+        /// Issue a client certificate for --client-cert, instead of a server certificate.
         #[clap(long)]
-        cert: Option<PathBuf>,
+        client: bool,
 
-        /// Path to key file.
+        /// Name of the written .crt and .key files [default: the common name].
         #[clap(long)]
-        key: Option<PathBuf>,
+        out: Option<String>,
+
+        /// Directory containing the authority, and to write the files to.
+        #[clap(long, default_value = "certs")]
+        dir: PathBuf,
+
+        /// Number of days the certificate is valid for.
+        #[clap(long, default_value_t = 365)]
+        days: u32,
     },
 }
 
-fn load_certs(path: &PathBuf) -> io::Result<Vec<Certificate>> {
-    certs(&mut BufReader::new(File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))
-        .map(|mut certs| certs.drain(..).map(Certificate).collect())
+#[derive(clap::Args, Debug, Clone)]
+struct ServerArgs {
+    /// Minimum accepted TCP port number [default: 1024].
+    #[clap(long)]
+    min_port: Option<u16>,
+
+    /// Maximum accepted TCP port number [default: 65535].
+    #[clap(long)]
+    max_port: Option<u16>,
+
+    /// Optional secret for authentication.
+    #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+    secret: Option<String>,
+
+    /// Path to a file of named client credentials, reloaded on SIGHUP.
+    #[clap(long)]
+    credentials: Option<PathBuf>,
+
+    /// Reject older clients that authenticate without a mutual handshake.
+    #[clap(long)]
+    require_mutual_auth: bool,
+
+    /// TCP port used for control connections [default: 7835].
+    #[clap(long)]
+    control_port: Option<u16>,
+
+    /// IP address to accept control connections on, may be repeated [default: 0.0.0.0].
+    #[clap(long, value_name = "IP")]
+    bind_addr: Vec<IpAddr>,
+
+    /// IP address to bind public tunnel listeners to [default: 0.0.0.0].
+    #[clap(long, value_name = "IP")]
+    bind_tunnels: Option<IpAddr>,
+
+    /// Port to accept HTTP requests on, routed to tunnels by hostname.
+    #[clap(long, value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..))]
+    http_port: Option<u16>,
+
+    /// Base domain that hostnames of HTTP tunnels are subdomains of, required with --http-port.
+    #[clap(long, value_name = "DOMAIN")]
+    http_domain: Option<String>,
+
+    /// Enable tls support for the tunnel.
+    #[clap(long)]
+    tls: bool,
+
+    /// Path to cert file, or a PKCS#12 bundle without --key.
+    #[clap(long)]
+    cert: Option<PathBuf>,
+
+    /// Path to key file, in PKCS#8, PKCS#1 or SEC1 PEM format.
+    #[clap(long)]
+    key: Option<PathBuf>,
+
+    /// Password of the PKCS#12 bundle given as --cert.
+    #[clap(long, env = "BORE_CERT_PASSWORD", hide_env_values = true)]
+    cert_password: Option<String>,
+
+    /// Require client certificates signed by the CA certificates in this file.
+    #[clap(long)]
+    client_ca: Option<PathBuf>,
+
+    /// Encrypt control connections with Noise, using the private key in this file.
+    #[clap(long, value_name = "PATH", conflicts_with = "tls")]
+    noise_key: Option<PathBuf>,
+
+    /// Public key of a client allowed to connect with Noise, may be repeated [default: any].
+    #[clap(long, value_name = "KEY")]
+    noise_client: Vec<PublicKey>,
+
+    /// Path to a config file, reloaded on SIGHUP.
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+}
+
+impl ServerArgs {
+    /// Load the config file, if one was given.
+    fn config_file(&self) -> Result<ServerConfigFile> {
+        match &self.config {
+            Some(path) => ServerConfigFile::load(path),
+            None => Ok(ServerConfigFile::default()),
+        }
+    }
 }
 
-fn load_keys(path: &PathBuf) -> Result<PrivateKey> {
-    let mut key_reader = std::io::BufReader::new(std::fs::File::open(path).unwrap());
-    let key = rustls_pemfile::pkcs8_private_keys(&mut key_reader)
-        .map_err(|_| error!("unable to load private key"))
-        .unwrap()
-        .remove(0);
-    Ok(rustls::PrivateKey(key))
+fn tls_connector(
+    cafile: &Option<PathBuf>,
+    pins: Vec<Pin>,
+    identity: Option<(Vec<Certificate>, PrivateKey)>,
+) -> Result<TlsConnector> {
+    let verifier = match cafile {
+        _ if !pins.is_empty() => ServerVerifier::pinned(pins),
+        Some(cafile) => ServerVerifier::with_ca_certs(&load_certs(cafile)?)
+            .with_context(|| format!("invalid CA certificate in {}", cafile.display()))?,
+        None => ServerVerifier::webpki_roots(),
+    };
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let config = match identity {
+        Some((certs, key)) => config
+            .with_single_cert(certs, key)
+            .context("invalid client certificate")?,
+        // No client certificate is configured, so none is offered in the handshake.
+        None => config.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn tls_acceptor(resolver: Arc<CertResolver>, client_ca: Option<&PathBuf>) -> Result<TlsAcceptor> {
+    let config = rustls::ServerConfig::builder().with_safe_defaults();
+    let config = match client_ca {
+        Some(client_ca) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(&cert).context("invalid client CA certificate")?;
+            }
+            ensure!(!roots.is_empty(), "no client CA certificates found");
+            config.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => config.with_no_client_auth(),
+    };
+    Ok(TlsAcceptor::from(Arc::new(
+        config.with_cert_resolver(resolver),
+    )))
+}
+
+/// Addresses to accept control connections on, from flags or the config file.
+fn bind_addrs(args: &ServerArgs, file: &ServerConfigFile) -> Vec<IpAddr> {
+    if !args.bind_addr.is_empty() {
+        return args.bind_addr.clone();
+    }
+    let default = || vec![Ipv4Addr::UNSPECIFIED.into()];
+    file.bind_addrs.clone().unwrap_or_else(default)
+}
+
+/// Build the server settings from flags, falling back to the config file.
+fn server_settings(args: &ServerArgs, file: &ServerConfigFile) -> Result<ServerSettings> {
+    let min_port = args.min_port.or(file.min_port).unwrap_or(1024);
+    let max_port = args.max_port.or(file.max_port).unwrap_or(65535);
+    let port_range = min_port..=max_port;
+    ensure!(!port_range.is_empty(), "port range is empty");
+
+    let secret = match &args.secret {
+        Some(secret) => Some(secret.clone()),
+        None => file.secret.as_ref().map(SecretSource::read).transpose()?,
+    };
+    let credentials = match args.credentials.as_ref().or(file.credentials.as_ref()) {
+        Some(path) => CredentialsFile::load(path)?.credentials()?,
+        None => Default::default(),
+    };
+    let tls = if args.tls || file.tls.enabled {
+        let cert = args.cert.as_ref().or(file.tls.cert.as_ref());
+        let cert = cert.context("cert path must be set, if tls is enabled")?;
+        let key = args.key.as_ref().or(file.tls.key.as_ref());
+        let password = match &args.cert_password {
+            Some(password) => Some(password.clone()),
+            None => file
+                .tls
+                .password
+                .as_ref()
+                .map(SecretSource::read)
+                .transpose()?,
+        };
+        let files = IdentityFiles {
+            cert: cert.clone(),
+            key: key.cloned(),
+            password,
+        };
+        let identity = files.load()?;
+        info!(sha256 = %Pin::of(&identity.cert[0].0), "loaded tls certificate");
+        let resolver = Arc::new(CertResolver::new(identity));
+        let client_ca = args.client_ca.as_ref().or(file.tls.client_ca.as_ref());
+        let acceptor = tls_acceptor(resolver.clone(), client_ca)?;
+
+        // The watcher stops once these settings are replaced and dropped.
+        let resolver = Arc::downgrade(&resolver);
+        tokio::spawn(watch_identity(files, resolver, CERT_POLL_INTERVAL));
+        Some(acceptor)
+    } else {
+        ensure!(
+            args.client_ca.is_none() && file.tls.client_ca.is_none(),
+            "client CA requires tls to be enabled"
+        );
+        None
+    };
+    let noise = match args.noise_key.as_ref().or(file.noise.key.as_ref()) {
+        Some(path) => {
+            ensure!(tls.is_none(), "tls and noise cannot both be enabled");
+            let key = Keypair::load(path)?;
+            let clients = match &args.noise_client {
+                clients if !clients.is_empty() => clients.clone(),
+                _ => file.noise.clients()?,
+            };
+            info!(public_key = %key.public(), clients = clients.len(), "loaded noise key");
+            Some(NoiseServer::new(key, clients))
+        }
+        None => {
+            ensure!(
+                args.noise_client.is_empty(),
+                "noise clients require a noise key"
+            );
+            None
+        }
+    };
+
+    let http_domain = args.http_domain.as_ref().or(file.http_domain.as_ref());
+    let http_domain = http_domain.map(|domain| normalize_hostname(domain));
+    if let Some(domain) = &http_domain {
+        ensure!(is_valid_hostname(domain), "invalid http domain: {domain}");
+    }
+
+    Ok(ServerSettings {
+        bind_tunnels: args
+            .bind_tunnels
+            .or(file.bind_tunnels)
+            .unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+        credentials,
+        require_mutual_auth: args.require_mutual_auth || file.require_mutual_auth.unwrap_or(false),
+        tls,
+        noise,
+        handshake_timeout: file
+            .tls
+            .handshake_timeout_ms
+            .map_or(HANDSHAKE_TIMEOUT, Duration::from_millis),
+        max_handshakes: file.tls.max_handshakes.unwrap_or(MAX_HANDSHAKES),
+        bans: file.bans.settings(),
+        control_access: file.access.control.list()?,
+        tunnel_access: file.access.tunnels.list()?,
+        heartbeat_interval: file
+            .heartbeat_interval_ms
+            .map_or(HEARTBEAT_INTERVAL, Duration::from_millis),
+        max_missed_heartbeats: file.max_missed_heartbeats.unwrap_or(MAX_MISSED_HEARTBEATS),
+        stale_timeout: file
+            .stale_timeout_secs
+            .map_or(STALE_TIMEOUT, Duration::from_secs),
+        resume_grace: file
+            .resume_grace_secs
+            .map_or(RESUME_GRACE, Duration::from_secs),
+        udp_idle_timeout: file
+            .udp_idle_timeout_secs
+            .map_or(UDP_IDLE_TIMEOUT, Duration::from_secs),
+        http_domain,
+        ..ServerSettings::new(port_range, secret.as_deref())
+    })
+}
+
+/// Create a certificate authority or issue a certificate from it.
+fn cert_command(command: CertCommand) -> Result<()> {
+    match command {
+        CertCommand::Init { dir, name, days } => {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("could not create {}", dir.display()))?;
+            let (cert, key) = (dir.join("ca.crt"), dir.join("ca.key"));
+            CertificateAuthority::generate(&name, days)?.save(&cert, &key)?;
+            info!(cert = %cert.display(), key = %key.display(), "created certificate authority");
+        }
+        CertCommand::Issue {
+            sans,
+            name,
+            client,
+            out,
+            dir,
+            days,
+        } => {
+            let ca = CertificateAuthority::load(&dir.join("ca.crt"), &dir.join("ca.key"))
+                .context("no certificate authority, run `bore cert init` first")?;
+            let name = name.unwrap_or_else(|| sans[0].clone());
+            let out = out.unwrap_or_else(|| name.clone());
+            let (cert, key) = (
+                dir.join(format!("{out}.crt")),
+                dir.join(format!("{out}.key")),
+            );
+            ca.issue(&name, &sans, client, days)?.save(&cert, &key)?;
+            info!(cert = %cert.display(), key = %key.display(), "issued certificate");
+        }
+    }
+    Ok(())
+}
+
+/// Generate a Noise keypair and print its public key.
+fn keygen(path: &Path) -> Result<()> {
+    let key = Keypair::generate();
+    key.save(path)?;
+    info!(path = %path.display(), "created noise keypair");
+    println!("{}", key.public());
+    Ok(())
+}
+
+/// Reload the config, credentials and certificate files on SIGHUP, keeping
+/// the old settings if any is invalid.
+#[cfg(unix)]
+async fn reload_on_hangup(mut hangup: Signal, args: ServerArgs, settings: SettingsHandle) {
+    while hangup.recv().await.is_some() {
+        match args
+            .config_file()
+            .and_then(|file| server_settings(&args, &file))
+        {
+            std::result::Result::Ok(new_settings) => {
+                settings.set(new_settings);
+                info!("reloaded configuration");
+            }
+            Err(err) => error!("failed to reload configuration: {err:#}"),
+        }
+    }
 }
 
 #[tokio::main]
@@ -95,118 +531,279 @@ async fn run(command: Command) -> Result<()> {
             local_host,
             local_port,
             to,
+            port,
             secret,
+            key_id,
             tls,
             cafile,
+            tls_server_name,
+            pin_sha256,
+            client_cert,
+            client_key,
+            client_cert_password,
+            noise,
+            noise_key,
+            multiplex,
+            heartbeat_interval_ms,
+            max_missed_heartbeats,
+            max_retries,
+            expose,
+            allow,
+            deny,
+            udp,
+            http,
+            hostname,
+            config,
         } => {
-            info!("staring proxy client");
-            loop {
-                let client = if tls {
-                    info!("using tls client");
-                    let mut root_cert_store = rustls::RootCertStore::empty();
-                    match &cafile {
-                        Some(cafile) => {
-                            let mut pem = BufReader::new(File::open(cafile)?);
-                            let certs = rustls_pemfile::certs(&mut pem)?;
-                            let trust_anchors = certs.iter().map(|cert| {
-                                let ta = webpki::TrustAnchor::try_from_cert_der(&cert[..]).unwrap();
-                                OwnedTrustAnchor::from_subject_spki_name_constraints(
-                                    ta.subject,
-                                    ta.spki,
-                                    ta.name_constraints,
-                                )
-                            });
-                            root_cert_store.add_server_trust_anchors(trust_anchors);
-                        }
-                        None => {
-                            root_cert_store.add_server_trust_anchors(
-                                webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
-                                    OwnedTrustAnchor::from_subject_spki_name_constraints(
-                                        ta.subject,
-                                        ta.spki,
-                                        ta.name_constraints,
-                                    )
-                                }),
-                            );
-                        }
-                    }
-                    let config = rustls::ClientConfig::builder()
-                        .with_safe_defaults()
-                        .with_root_certificates(root_cert_store)
-                        .with_no_client_auth(); // i guess this was previously the default?
-                    let connector = TlsConnector::from(Arc::new(config));
-
-                    match Client::new_with_tls(
-                        &local_host,
+            let file = match config.or_else(ClientConfigFile::find) {
+                Some(path) => {
+                    info!(path = %path.display(), "using config file");
+                    ClientConfigFile::load(&path)?
+                }
+                None => ClientConfigFile::default(),
+            };
+
+            // Flags take precedence over values from the config file.
+            let liveness = file.liveness();
+            let liveness = Liveness {
+                interval: heartbeat_interval_ms.map_or(liveness.interval, Duration::from_millis),
+                max_missed: max_missed_heartbeats.unwrap_or(liveness.max_missed),
+            };
+            let mut reconnect = file.reconnect.settings();
+            reconnect.max_retries = max_retries.or(reconnect.max_retries);
+            let mut tunnels = expose;
+            if let Some(local_port) = local_port {
+                tunnels.insert(
+                    0,
+                    Tunnel {
+                        local_host,
                         local_port,
-                        &to,
-                        secret.as_deref(),
-                        Some(connector),
+                        port,
+                        access: AccessList::default(),
+                        udp: false,
+                        http: None,
+                    },
+                );
+            }
+            if hostname.is_some() && tunnels.len() > 1 {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--hostname only applies to a single exposed port",
                     )
-                    .await
-                    {
-                        std::result::Result::Ok(client) => client,
-                        Err(err) => {
-                            error!("failed to create tls client: {:?}", err);
-                            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                            continue;
-                        }
-                    }
-                } else {
-                    match Client::new(&local_host, local_port, &to, secret.as_deref()).await {
-                        std::result::Result::Ok(client) => client,
-                        Err(err) => {
-                            error!("failed to create tcp client: {:?}", err);
-                            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                            continue;
-                        }
+                    .exit();
+            }
+            let access = AccessList { allow, deny };
+            let http = (http || hostname.is_some()).then(|| hostname.unwrap_or_default());
+            for tunnel in &mut tunnels {
+                tunnel.access = access.clone();
+                tunnel.udp = udp;
+                tunnel.http = http.clone();
+            }
+            if tunnels.is_empty() {
+                if !access.is_empty() {
+                    Args::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            "--allow and --deny only apply to ports exposed with flags, \
+                             set allow and deny per tunnel in the config file",
+                        )
+                        .exit();
+                }
+                if udp {
+                    Args::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            "--udp only applies to ports exposed with flags, \
+                             set udp per tunnel in the config file",
+                        )
+                        .exit();
+                }
+                if http.is_some() {
+                    Args::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            "--http and --hostname only apply to ports exposed with flags, \
+                             set http or hostname per tunnel in the config file",
+                        )
+                        .exit();
+                }
+                tunnels = file.tunnels()?;
+            }
+            if tunnels.is_empty() {
+                Args::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "no local port to expose, pass <LOCAL_PORT>, --expose or a config file",
+                    )
+                    .exit();
+            }
+            let Some(to) = to.or(file.to) else {
+                Args::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "no server address, pass --to or set `to` in a config file",
+                    )
+                    .exit();
+            };
+            let secret = match secret {
+                Some(secret) => Some(secret),
+                None => file.secret.as_ref().map(SecretSource::read).transpose()?,
+            };
+            let auth = match (key_id.or(file.key_id), secret) {
+                (Some(key_id), Some(secret)) => Some(Authenticator::with_key_id(&key_id, &secret)),
+                (Some(_), None) => Args::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "a key ID requires a secret, pass --secret or set one in a config file",
+                    )
+                    .exit(),
+                (None, secret) => secret.as_deref().map(Authenticator::new),
+            };
+            let tls = tls || file.tls.enabled;
+            let pins = match pin_sha256 {
+                pins if !pins.is_empty() => pins,
+                _ => file.tls.pins()?,
+            };
+            let cafile = cafile.or(file.tls.cafile);
+            let tls_server_name = tls_server_name.or(file.tls.server_name);
+            let client_cert = client_cert.or(file.tls.client_cert);
+            let client_key = client_key.or(file.tls.client_key);
+            if client_cert.is_some() && !tls {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "a client certificate requires tls, pass --tls or enable it in a config file",
+                    )
+                    .exit();
+            }
+            let noise = match noise {
+                Some(key) => Some(key),
+                None => file.noise.server_key()?,
+            };
+            if noise.is_some() && tls {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "noise cannot be used with tls, enable only one of them",
+                    )
+                    .exit();
+            }
+            let noise_key = noise_key.or(file.noise.key);
+            let multiplex = multiplex || file.multiplex.unwrap_or(false);
+            if multiplex && tunnels.iter().any(|tunnel| tunnel.udp) {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "udp tunnels cannot be multiplexed",
+                    )
+                    .exit();
+            }
+
+            info!("staring proxy client");
+            let transport: Option<Transport> = if let Some(server_key) = noise {
+                info!("using noise client");
+                let key = match &noise_key {
+                    Some(path) => Keypair::load(path)?,
+                    None => Keypair::generate(),
+                };
+                info!(public_key = %key.public(), "using noise key");
+                Some(NoiseClient::new(key, server_key).into())
+            } else if tls {
+                info!("using tls client");
+                let identity = match &client_cert {
+                    Some(cert) => {
+                        let password = match client_cert_password {
+                            Some(password) => Some(password),
+                            None => file
+                                .tls
+                                .client_cert_password
+                                .as_ref()
+                                .map(SecretSource::read)
+                                .transpose()?,
+                        };
+                        let key = client_key.as_deref();
+                        Some(load_identity(cert, key, password.as_deref())?)
                     }
+                    None => None,
                 };
-
-                match client.listen().await {
-                    std::result::Result::Ok(_) => info!("client exited"),
-                    Err(err) => error!("client exited with error: {:?}", err),
+                let tls = ClientTls::new(tls_connector(&cafile, pins, identity)?);
+                match &tls_server_name {
+                    Some(name) => Some(tls.with_server_name(name)?.into()),
+                    None => Some(tls.into()),
                 }
+            } else {
+                None
+            };
+            let supervisor = Supervisor {
+                auth,
+                transport,
+                multiplex,
+                liveness,
+                reconnect,
+                ..Supervisor::new(tunnels, &to)
+            };
+            supervisor.run().await?;
+        }
+        Command::Server(args) => {
+            let file = args.config_file()?;
+            let settings = match server_settings(&args, &file) {
+                std::result::Result::Ok(settings) => settings,
+                Err(err) => Args::command()
+                    .error(ErrorKind::InvalidValue, format!("{err:#}"))
+                    .exit(),
+            };
 
-                error!("client exited");
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            let control_port = args.control_port.or(file.control_port);
+            let control_addrs = bind_addrs(&args, &file)
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, control_port.unwrap_or(CONTROL_PORT)))
+                .collect();
+            let http_addr = args
+                .http_port
+                .or(file.http_port)
+                .map(|port| SocketAddr::new(settings.bind_tunnels, port));
+            ensure!(
+                http_addr.is_none() || settings.http_domain.is_some(),
+                "routing http by hostname requires an http domain"
+            );
+            let mut server = Server::with_settings(control_addrs, settings);
+            if let Some(addr) = http_addr {
+                server = server.with_http(addr);
             }
-        }
-        Command::Server {
-            secret,
-            tls,
-            cert,
-            key,
-        } => {
+
+            let metrics_addr = file.metrics_addr.unwrap_or(METRICS_ADDR);
             tokio::spawn(
                 async move {
-                    start_metric_server().await;
+                    start_metric_server(metrics_addr).await;
                 }
                 .instrument(info_span!("metrics")),
             );
+            let admin_addr = file.admin_addr.unwrap_or(ADMIN_ADDR);
+            let bans = server.bans();
+            tokio::spawn(
+                async move {
+                    start_admin_server(admin_addr, bans).await;
+                }
+                .instrument(info_span!("admin")),
+            );
             bytes_per_second_calculator();
 
-            let server = if tls {
-                let certs = load_certs(&cert.ok_or_else(|| {
-                    io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "cert path must be set, if tls is enabled",
-                    )
-                })?)?;
-                let keys = load_keys(&key.unwrap())?;
-
-                let config = rustls::ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_no_client_auth()
-                    .with_single_cert(certs, keys)
-                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
-                let acceptor = TlsAcceptor::from(Arc::new(config));
-                Server::new_with_tls(secret.as_deref(), Some(acceptor))
-            } else {
-                Server::new(secret.as_deref())
-            };
+            #[cfg(unix)]
+            if args.config.is_some()
+                || file.credentials.is_some()
+                || args.credentials.is_some()
+                || args.tls
+                || file.tls.enabled
+                || args.noise_key.is_some()
+            {
+                let hangup = signal(SignalKind::hangup())?;
+                tokio::spawn(reload_on_hangup(hangup, args, server.settings()));
+            }
             server.listen().await?;
         }
+        Command::Cert { command } => cert_command(command)?,
+        Command::Keygen { path } => keygen(&path)?,
     }
 
     Ok(())
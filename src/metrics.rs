@@ -1,10 +1,22 @@
 //! Metrics for the server
 
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+
 use lazy_static::lazy_static;
-use prometheus::{IntCounter, IntGauge, Registry};
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry};
 use tracing::info;
+use warp::http::StatusCode;
 use warp::Filter;
 
+use crate::ban::BanList;
+
+/// Default address of the metrics server.
+pub const METRICS_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234));
+
+/// Default address of the admin server.
+pub const ADMIN_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1235));
+
 lazy_static! {
     /// Count of total control channel connections
     pub static ref TOTAL_CONNECTIONS: IntGauge = IntGauge::new("total_connections", "Total TCP connections").expect("metric can be created");
@@ -15,6 +27,63 @@ lazy_static! {
     /// Count of heartbets sent
     pub static ref HEARTBEATS: IntCounter = IntCounter::new("heartbeats", "Count of total Heartbeats sent").expect("metric can be created");
 
+    /// Count of control connections closed because the client stopped answering pings
+    pub static ref HEARTBEAT_TIMEOUTS: IntCounter = IntCounter::new("heartbeat_timeouts", "Count of control connections closed after missed heartbeats").expect("metric can be created");
+
+    /// Round-trip times of pings on control connections, in seconds
+    pub static ref CONTROL_RTT: Histogram = Histogram::with_opts(HistogramOpts::new("control_rtt_seconds", "Round-trip time of pings on control connections")).expect("metric can be created");
+
+    /// Count of sessions resumed by a reconnecting client
+    pub static ref RESUMED_SESSIONS: IntCounter = IntCounter::new("resumed_sessions", "Count of sessions resumed by a reconnecting client").expect("metric can be created");
+
+    /// Count of UDP flows opened by peers of UDP tunnels
+    pub static ref UDP_FLOWS: IntCounter = IntCounter::new("udp_flows", "Count of UDP flows opened").expect("metric can be created");
+
+    /// Count of UDP datagrams dropped for being too long or exceeding the flow limit
+    pub static ref DROPPED_DATAGRAMS: IntCounter = IntCounter::new("dropped_datagrams", "Count of UDP datagrams dropped").expect("metric can be created");
+
+    /// Count of connections to the HTTP port, routed to a tunnel or answered with an error status
+    pub static ref HTTP_CONNECTIONS: IntCounterVec = IntCounterVec::new(Opts::new("http_connections", "Count of connections to the shared HTTP port"), &["outcome"]).expect("metric can be created");
+
+    /// Count of accepts rejected for naming another session's connection
+    pub static ref REJECTED_ACCEPTS: IntCounter = IntCounter::new("rejected_accepts", "Count of accepts rejected for a mismatched session").expect("metric can be created");
+
+    /// Count of control connections authenticated with a named credential, by key ID
+    pub static ref AUTHENTICATED_CONNECTIONS: IntCounterVec = IntCounterVec::new(Opts::new("authenticated_connections", "Count of connections authenticated with a named credential"), &["key_id"]).expect("metric can be created");
+
+    /// Count of control connections with a verified client certificate, by subject
+    pub static ref CERTIFICATE_CONNECTIONS: IntCounterVec = IntCounterVec::new(Opts::new("certificate_connections", "Count of connections with a verified client certificate"), &["subject"]).expect("metric can be created");
+
+    /// Count of failed authentication handshakes
+    pub static ref AUTH_FAILURES: IntCounter = IntCounter::new("auth_failures", "Count of failed authentication handshakes").expect("metric can be created");
+
+    /// Count of tunnels closed because their credential was revoked
+    pub static ref REVOKED_SESSIONS: IntCounter = IntCounter::new("revoked_sessions", "Count of tunnels closed for a revoked credential").expect("metric can be created");
+
+    /// Count of requests denied by the policy of a credential, by key ID and reason
+    pub static ref POLICY_DENIALS: IntCounterVec = IntCounterVec::new(Opts::new("policy_denials", "Count of requests denied by a credential's policy"), &["key_id", "reason"]).expect("metric can be created");
+
+    /// Count of TLS handshakes in progress
+    pub static ref TLS_HANDSHAKES: IntGauge = IntGauge::new("tls_handshakes", "TLS handshakes in progress").expect("metric can be created");
+
+    /// Count of failed TLS handshakes, by reason
+    pub static ref TLS_HANDSHAKE_FAILURES: IntCounterVec = IntCounterVec::new(Opts::new("tls_handshake_failures", "Count of failed TLS handshakes"), &["reason"]).expect("metric can be created");
+
+    /// Gauge of Noise handshakes in progress
+    pub static ref NOISE_HANDSHAKES: IntGauge = IntGauge::new("noise_handshakes", "Noise handshakes in progress").expect("metric can be created");
+
+    /// Count of failed Noise handshakes, by reason
+    pub static ref NOISE_HANDSHAKE_FAILURES: IntCounterVec = IntCounterVec::new(Opts::new("noise_handshake_failures", "Count of failed Noise handshakes"), &["reason"]).expect("metric can be created");
+
+    /// Gauge of addresses banned after failed authentications
+    pub static ref ACTIVE_BANS: IntGauge = IntGauge::new("active_bans", "Addresses banned after failed authentications").expect("metric can be created");
+
+    /// Count of connections dropped for coming from a banned address
+    pub static ref BANNED_CONNECTIONS: IntCounter = IntCounter::new("banned_connections", "Count of connections dropped from banned addresses").expect("metric can be created");
+
+    /// Count of connections dropped by access lists, by listener
+    pub static ref ACCESS_DENIED_CONNECTIONS: IntCounterVec = IntCounterVec::new(Opts::new("access_denied_connections", "Count of connections dropped by access lists"), &["listener"]).expect("metric can be created");
+
     /// Metric for incoming bytes
     pub static ref INCOMING_BYTES: IntCounter =
     IntCounter::new("incoming_bytes", "Total incoming bytes")
@@ -65,13 +134,39 @@ pub fn metrics_handler() -> String {
 }
 
 /// Function to start the metric http server
-pub async fn start_metric_server() {
-    info!("starting metric server");
+pub async fn start_metric_server(addr: SocketAddr) {
+    info!(?addr, "starting metric server");
 
     register_metrics();
 
-    let routes = warp::path("metrics").map(metrics_handler);
-    warp::serve(routes).run(([127, 0, 0, 1], 1234)).await;
+    let metrics = warp::path("metrics").map(metrics_handler);
+    warp::serve(metrics).run(addr).await;
+}
+
+/// Function to start the admin http server
+///
+/// It lists banned addresses at `GET /bans`, and lifts a ban with
+/// `DELETE /bans/<ip>`. Unlike metrics, these must not be reachable by
+/// whoever scrapes the metrics server, so they are served on their own address.
+pub async fn start_admin_server(addr: SocketAddr, bans: Arc<BanList>) {
+    info!(?addr, "starting admin server");
+
+    let with_bans = warp::any().map(move || Arc::clone(&bans));
+    let list_bans = warp::path!("bans")
+        .and(warp::get())
+        .and(with_bans.clone())
+        .map(|bans: Arc<BanList>| warp::reply::json(&bans.bans()));
+    let lift_ban = warp::path!("bans" / IpAddr)
+        .and(warp::delete())
+        .and(with_bans)
+        .map(|ip, bans: Arc<BanList>| {
+            if bans.unban(ip) {
+                StatusCode::NO_CONTENT
+            } else {
+                StatusCode::NOT_FOUND
+            }
+        });
+    warp::serve(list_bans.or(lift_ban)).run(addr).await;
 }
 
 /// Function to register the prometheus metrics
@@ -88,6 +183,82 @@ fn register_metrics() {
         .register(Box::new(HEARTBEATS.clone()))
         .expect("failed to register metric");
 
+    REGISTRY
+        .register(Box::new(HEARTBEAT_TIMEOUTS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(CONTROL_RTT.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(RESUMED_SESSIONS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(UDP_FLOWS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(DROPPED_DATAGRAMS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(HTTP_CONNECTIONS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(REJECTED_ACCEPTS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(AUTHENTICATED_CONNECTIONS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(CERTIFICATE_CONNECTIONS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(AUTH_FAILURES.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(REVOKED_SESSIONS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(POLICY_DENIALS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(TLS_HANDSHAKES.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(TLS_HANDSHAKE_FAILURES.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(NOISE_HANDSHAKES.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(NOISE_HANDSHAKE_FAILURES.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(ACTIVE_BANS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(BANNED_CONNECTIONS.clone()))
+        .expect("failed to register metric");
+
+    REGISTRY
+        .register(Box::new(ACCESS_DENIED_CONNECTIONS.clone()))
+        .expect("failed to register metric");
+
     REGISTRY
         .register(Box::new(INCOMING_BYTES.clone()))
         .expect("failed to register metric");
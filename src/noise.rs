@@ -0,0 +1,363 @@
+//! Encrypted transport based on the Noise protocol, as an alternative to TLS.
+//!
+//! Connections use the `XK` handshake pattern: the client knows the server's
+//! static public key in advance, and sends its own static key encrypted, so
+//! the server may restrict connections to known clients. No certificates or
+//! CAs are involved.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context as TaskContext, Poll};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::ca::write_new;
+
+/// Noise protocol name, fixing the handshake pattern and algorithms.
+pub const NOISE_PARAMS: &str = "Noise_XK_25519_ChaChaPoly_BLAKE2s";
+
+/// Maximum length of a Noise message, including the authentication tag.
+const MAX_MESSAGE_LEN: usize = 65535;
+
+/// Length of the authentication tag appended to each encrypted message.
+const TAG_LEN: usize = 16;
+
+fn builder<'a>() -> Builder<'a> {
+    Builder::new(NOISE_PARAMS.parse().expect("valid noise parameters"))
+}
+
+/// Public key of a Noise static keypair, written in hex.
+///
+/// ```
+/// use bore_cli::noise::Keypair;
+///
+/// let public = Keypair::generate().public();
+/// assert_eq!(public.to_string().parse::<bore_cli::noise::PublicKey>().unwrap(), public);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; 32]);
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let key = hex::decode(s.trim()).context("public key is not a hex string")?;
+        let key = key
+            .try_into()
+            .map_err(|_| anyhow!("public key must be 32 bytes long"))?;
+        Ok(Self(key))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// Static keypair identifying a server or client.
+#[derive(Clone)]
+pub struct Keypair {
+    private: [u8; 32],
+    public: PublicKey,
+}
+
+impl Keypair {
+    /// Generate a new random keypair.
+    pub fn generate() -> Self {
+        let keypair = builder()
+            .generate_keypair()
+            .expect("keypair can be generated");
+        Self {
+            private: keypair.private.try_into().expect("32 byte private key"),
+            public: PublicKey(keypair.public.try_into().expect("32 byte public key")),
+        }
+    }
+
+    /// Create a keypair from its private key.
+    pub fn from_private(private: [u8; 32]) -> Self {
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("curve25519 is supported");
+        dh.set(&private);
+        let public = PublicKey(dh.pubkey().try_into().expect("32 byte public key"));
+        Self { private, public }
+    }
+
+    /// Load a private key written by [`save`](Self::save).
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("could not read key file {}", path.display()))?;
+        let private = hex::decode(data.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .with_context(|| format!("invalid noise private key in {}", path.display()))?;
+        Ok(Self::from_private(private))
+    }
+
+    /// Write the private key to a new file readable only by its owner, and the
+    /// public key next to it with a `.pub` extension.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_new(path, &format!("{}\n", hex::encode(self.private)), 0o600)?;
+        let mut public_path = path.as_os_str().to_owned();
+        public_path.push(".pub");
+        write_new(
+            Path::new(&public_path),
+            &format!("{}\n", self.public),
+            0o644,
+        )
+    }
+
+    /// Returns the public key.
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+}
+
+/// Noise settings of a client: its own keypair and the server's public key.
+#[derive(Clone)]
+pub struct NoiseClient {
+    key: Keypair,
+    server: PublicKey,
+}
+
+impl NoiseClient {
+    /// Connect to the server with the given public key, using a client keypair.
+    pub fn new(key: Keypair, server: PublicKey) -> Self {
+        Self { key, server }
+    }
+
+    /// Run the handshake as initiator, failing unless the server holds its key.
+    pub async fn connect<S>(&self, mut stream: S) -> Result<NoiseStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut state = builder()
+            .local_private_key(&self.key.private)
+            .remote_public_key(&self.server.0)
+            .build_initiator()?;
+        write_handshake(&mut stream, &mut state).await?;
+        read_handshake(&mut stream, &mut state)
+            .await
+            .context("server does not hold the expected noise key")?;
+        write_handshake(&mut stream, &mut state).await?;
+        Ok(NoiseStream::new(stream, state.into_transport_mode()?))
+    }
+}
+
+/// Noise settings of a server: its keypair and the clients it accepts.
+#[derive(Clone)]
+pub struct NoiseServer {
+    key: Keypair,
+    clients: Vec<PublicKey>,
+}
+
+impl NoiseServer {
+    /// Accept clients with the given public keys, or any client if empty.
+    pub fn new(key: Keypair, clients: Vec<PublicKey>) -> Self {
+        Self { key, clients }
+    }
+
+    /// Returns the server's public key, which clients need to connect.
+    pub fn public(&self) -> PublicKey {
+        self.key.public
+    }
+
+    /// Run the handshake as responder, returning the stream and the client's key.
+    pub async fn accept<S>(&self, mut stream: S) -> Result<(NoiseStream<S>, PublicKey)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut state = builder()
+            .local_private_key(&self.key.private)
+            .build_responder()?;
+        read_handshake(&mut stream, &mut state).await?;
+        write_handshake(&mut stream, &mut state).await?;
+        read_handshake(&mut stream, &mut state).await?;
+
+        let client = state
+            .get_remote_static()
+            .and_then(|key| key.try_into().ok())
+            .map(PublicKey)
+            .context("client sent no static key")?;
+        if !self.clients.is_empty() && !self.clients.contains(&client) {
+            bail!("client key {client} is not allowed");
+        }
+        Ok((
+            NoiseStream::new(stream, state.into_transport_mode()?),
+            client,
+        ))
+    }
+}
+
+async fn write_handshake<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    state: &mut HandshakeState,
+) -> Result<()> {
+    let mut message = vec![0; MAX_MESSAGE_LEN];
+    let len = state.write_message(&[], &mut message)?;
+    stream.write_u16(len as u16).await?;
+    stream.write_all(&message[..len]).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_handshake<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    state: &mut HandshakeState,
+) -> Result<()> {
+    let len = stream.read_u16().await?;
+    let mut message = vec![0; len.into()];
+    stream.read_exact(&mut message).await?;
+    let mut payload = vec![0; MAX_MESSAGE_LEN];
+    let len = state.read_message(&message, &mut payload)?;
+    ensure!(len == 0, "unexpected handshake payload");
+    Ok(())
+}
+
+/// Stream encrypted with a Noise session, sending length-prefixed messages.
+pub struct NoiseStream<S> {
+    inner: S,
+    state: TransportState,
+
+    /// Received bytes not yet decrypted, possibly an incomplete message.
+    incoming: Vec<u8>,
+
+    /// Decrypted bytes not yet read, starting at `read_pos`.
+    plaintext: Vec<u8>,
+    read_pos: usize,
+
+    /// Encrypted message not yet written, starting at `write_pos`.
+    outgoing: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S> NoiseStream<S> {
+    fn new(inner: S, state: TransportState) -> Self {
+        Self {
+            inner,
+            state,
+            incoming: Vec::new(),
+            plaintext: Vec::new(),
+            read_pos: 0,
+            outgoing: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    /// Decrypt the first message in `incoming`, if it was fully received.
+    fn decrypt_incoming(&mut self) -> io::Result<bool> {
+        let Some(header) = self.incoming.get(..2) else {
+            return Ok(false);
+        };
+        let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+        let Some(message) = self.incoming.get(2..2 + len) else {
+            return Ok(false);
+        };
+        self.plaintext.resize(len, 0);
+        let len = self
+            .state
+            .read_message(message, &mut self.plaintext)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.plaintext.truncate(len);
+        self.read_pos = 0;
+        self.incoming.drain(..2 + message.len());
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> NoiseStream<S> {
+    /// Write out the pending encrypted message.
+    fn poll_write_outgoing(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.outgoing.len() {
+            let n =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.write_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.plaintext.len() {
+                let available = &this.plaintext[this.read_pos..];
+                let n = available.len().min(buf.remaining());
+                buf.put_slice(&available[..n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.decrypt_incoming()? {
+                continue;
+            }
+
+            let mut chunk = [0; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                if this.incoming.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.incoming.extend_from_slice(chunk_buf.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Accept the data once it is encrypted; it is written out on the next
+        // write or flush.
+        let payload = &buf[..buf.len().min(MAX_MESSAGE_LEN - TAG_LEN)];
+        this.outgoing.resize(2 + payload.len() + TAG_LEN, 0);
+        let len = this
+            .state
+            .write_message(payload, &mut this.outgoing[2..])
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        this.outgoing[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        this.outgoing.truncate(2 + len);
+        this.write_pos = 0;
+        let _ = this.poll_write_outgoing(cx)?;
+        Poll::Ready(Ok(payload.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
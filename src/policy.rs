@@ -0,0 +1,158 @@
+//! Authorization policies for clients with named credentials.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+/// What a client holding a credential may do on the server.
+///
+/// The default policy allows everything that the server settings allow.
+///
+/// ```
+/// use bore_cli::policy::Policy;
+///
+/// let policy = Policy {
+///     port_ranges: vec![20000..=20099, 8080..=8080],
+///     ..Policy::default()
+/// };
+/// assert!(policy.allows_port(8080));
+/// assert!(!policy.allows_port(8081));
+/// assert_eq!(policy.port_ranges(&(1024..=20049)), vec![20000..=20049, 8080..=8080]);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// Remote ports that may be requested, or any port in the server's range if empty.
+    pub port_ranges: Vec<RangeInclusive<u16>>,
+
+    /// Maximum number of tunnels open at once, across all control connections.
+    pub max_tunnels: Option<usize>,
+
+    /// Maximum number of proxied connections open at once, across all tunnels.
+    pub max_connections: Option<usize>,
+
+    /// Only allow encrypted control connections, over TLS or Noise.
+    pub require_tls: bool,
+}
+
+impl Policy {
+    /// Returns whether a specific remote port may be requested.
+    pub fn allows_port(&self, port: u16) -> bool {
+        self.port_ranges.is_empty() || self.port_ranges.iter().any(|range| range.contains(&port))
+    }
+
+    /// Returns the ranges of remote ports allowed both by the policy and the server.
+    pub fn port_ranges(&self, server_range: &RangeInclusive<u16>) -> Vec<RangeInclusive<u16>> {
+        if self.port_ranges.is_empty() {
+            return vec![server_range.clone()];
+        }
+        self.port_ranges
+            .iter()
+            .map(|range| {
+                *range.start().max(server_range.start())..=*range.end().min(server_range.end())
+            })
+            .filter(|range| !range.is_empty())
+            .collect()
+    }
+}
+
+/// Reason that a request was denied by a policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Denial {
+    /// The control connection does not use TLS, but the policy requires it.
+    Transport,
+
+    /// A requested remote port is outside the allowed ranges.
+    Port,
+
+    /// Too many tunnels are already open.
+    Tunnels,
+
+    /// Too many proxied connections are already open.
+    Connections,
+}
+
+impl Denial {
+    /// Short name of the reason, used as a metric label.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Denial::Transport => "transport",
+            Denial::Port => "port",
+            Denial::Tunnels => "tunnels",
+            Denial::Connections => "connections",
+        }
+    }
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Denial::Transport => "policy requires a tls or noise connection",
+            Denial::Port => "port not allowed by policy",
+            Denial::Tunnels => "tunnel limit of policy reached",
+            Denial::Connections => "connection limit of policy reached",
+        })
+    }
+}
+
+/// Count of a resource currently held by each credential.
+#[derive(Clone, Default)]
+pub(crate) struct Usage(Arc<DashMap<String, usize>>);
+
+impl Usage {
+    /// Reserve some units for a credential, unless that would exceed the limit.
+    ///
+    /// Clients without a named credential are not counted and always succeed.
+    pub(crate) fn acquire(
+        &self,
+        key_id: Option<&str>,
+        count: usize,
+        limit: Option<usize>,
+    ) -> Option<UsageGuard> {
+        if let Some(key_id) = key_id {
+            let mut held = self.0.entry(key_id.to_string()).or_insert(0);
+            if limit.is_some_and(|limit| *held + count > limit) {
+                return None;
+            }
+            *held += count;
+        }
+        Some(UsageGuard {
+            usage: self.clone(),
+            key_id: key_id.map(String::from),
+            count,
+        })
+    }
+}
+
+/// Units of a resource held by a credential, released when dropped.
+pub(crate) struct UsageGuard {
+    usage: Usage,
+    key_id: Option<String>,
+    count: usize,
+}
+
+impl UsageGuard {
+    /// Split into guards of one unit each, which are released separately.
+    pub(crate) fn split(mut self) -> Vec<UsageGuard> {
+        let count = std::mem::take(&mut self.count);
+        (0..count)
+            .map(|_| UsageGuard {
+                usage: self.usage.clone(),
+                key_id: self.key_id.clone(),
+                count: 1,
+            })
+            .collect()
+    }
+}
+
+impl Drop for UsageGuard {
+    fn drop(&mut self) {
+        if let Some(key_id) = &self.key_id {
+            if let Some(mut held) = self.usage.0.get_mut(key_id) {
+                *held -= self.count;
+            }
+            self.usage.0.remove_if(key_id, |_, held| *held == 0);
+        }
+    }
+}
@@ -1,178 +1,1970 @@
 //! Server implementation for the `bore` service.
 
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{self, Poll};
 use std::time::Duration;
 
-use anyhow::Result;
-use dashmap::DashMap;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{sleep, timeout};
-use tokio_rustls::TlsAcceptor;
-use tracing::{debug, info, info_span, warn, Instrument};
+use anyhow::{bail, ensure, Context, Result};
+use bytes::BytesMut;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures_util::future::{self, select_all};
+use futures_util::StreamExt;
+use prometheus::{IntCounterVec, IntGauge};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, timeout, Instant};
+use tokio_rustls::{rustls::Certificate, TlsAcceptor};
+use tokio_yamux::{Control, Session};
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 
-use crate::auth::Authenticator;
+use crate::access::{AccessList, MAX_ACCESS_RULES};
+use crate::auth::{Authenticator, Credentials};
+use crate::ban::{BanList, BanSettings, BAN_PRUNE_INTERVAL};
 use crate::byte_counter;
-use crate::metrics::{CONNECTED_CLIENTS, HEARTBEATS, TOTAL_CONNECTIONS};
-use crate::shared::{proxy, ClientMessage, Delimited, ServerMessage, StreamTrait, CONTROL_PORT};
+use crate::http::{self, HTTP_BACKLOG};
+use crate::metrics::{
+    ACCESS_DENIED_CONNECTIONS, AUTHENTICATED_CONNECTIONS, AUTH_FAILURES, BANNED_CONNECTIONS,
+    CERTIFICATE_CONNECTIONS, CONNECTED_CLIENTS, CONTROL_RTT, DROPPED_DATAGRAMS, HEARTBEATS,
+    HEARTBEAT_TIMEOUTS, HTTP_CONNECTIONS, NOISE_HANDSHAKES, NOISE_HANDSHAKE_FAILURES,
+    POLICY_DENIALS, REJECTED_ACCEPTS, RESUMED_SESSIONS, REVOKED_SESSIONS, TLS_HANDSHAKES,
+    TLS_HANDSHAKE_FAILURES, TOTAL_CONNECTIONS,
+};
+use crate::noise::NoiseServer;
+use crate::policy::{Denial, Policy, Usage, UsageGuard};
+use crate::shared::{
+    mux_config, proxy, Capability, ClientMessage, Delimited, Prefixed, ProtocolInfo, ServerMessage,
+    StreamTrait, CONTROL_PORT, HANDSHAKE_TIMEOUT, HEARTBEAT_INTERVAL, MAX_DATAGRAM_LENGTH,
+    MAX_HANDSHAKES, MAX_MISSED_HEARTBEATS, MAX_TUNNELS, NETWORK_TIMEOUT, RESUME_GRACE,
+    STALE_TIMEOUT, UDP_IDLE_TIMEOUT,
+};
+use crate::udp::{FlowTable, DATAGRAM_BACKLOG};
 
 /// State structure for the server.
 pub struct Server {
+    /// Addresses that control connections are accepted on.
+    control_addrs: Vec<SocketAddr>,
+
+    /// Settings that may be replaced while the server is running.
+    settings: SettingsHandle,
+
+    /// Concurrent map of IDs to incoming connections, with their owning session.
+    conns: Arc<DashMap<Uuid, (Uuid, Incoming, UsageGuard)>>,
+
+    /// Address of the HTTP port shared by tunnels routed by hostname, if any.
+    http_addr: Option<SocketAddr>,
+
+    /// Tunnels routed by hostname, by their hostname.
+    hosts: Arc<HostRoutes>,
+
+    /// Tunnels currently open by each named credential.
+    tunnel_usage: Usage,
+
+    /// Proxied connections currently open by each named credential.
+    connection_usage: Usage,
+
+    /// Number of TLS or Noise handshakes in progress.
+    handshakes: Arc<AtomicUsize>,
+
+    /// Addresses with failed authentications, kept across settings reloads.
+    bans: Arc<BanList>,
+
+    /// Control connections of sessions that a reconnecting client may resume.
+    live_sessions: DashMap<Uuid, LiveSession>,
+
+    /// Tunnels of disconnected sessions, held until resumed or expired.
+    reservations: Arc<DashMap<Uuid, Reservation>>,
+}
+
+/// Settings of the server that can be reloaded without dropping live tunnels.
+///
+/// New values apply to connections and tunnels accepted after the change, as
+/// well as to the heartbeat and stale connection timing of existing tunnels.
+pub struct ServerSettings {
+    /// Range of TCP ports that can be forwarded.
+    pub port_range: RangeInclusive<u16>,
+
+    /// Address that public tunnel listeners are bound to.
+    pub bind_tunnels: IpAddr,
+
     /// Optional secret used to authenticate clients.
-    auth: Option<Authenticator>,
+    pub auth: Option<Authenticator>,
+
+    /// Named client credentials, accepted alongside the shared secret.
+    ///
+    /// Tunnels of a client whose credential is removed are closed.
+    pub credentials: Credentials,
 
-    /// Concurrent map of IDs to incoming connections.
-    conns: Arc<DashMap<Uuid, TcpStream>>,
+    /// Whether clients must authenticate with a mutual handshake, rejecting
+    /// older clients whose later messages are not authenticated.
+    pub require_mutual_auth: bool,
 
     /// Optional tls configuration
-    tls: Option<TlsAcceptor>,
+    pub tls: Option<TlsAcceptor>,
+
+    /// Optional Noise configuration, used when TLS is not configured.
+    pub noise: Option<NoiseServer>,
+
+    /// Time allowed for a client to complete the TLS or Noise handshake.
+    pub handshake_timeout: Duration,
+
+    /// Maximum number of TLS or Noise handshakes in progress, beyond which new
+    /// connections are dropped.
+    pub max_handshakes: usize,
+
+    /// Thresholds for delaying and banning clients that fail to authenticate.
+    pub bans: BanSettings,
+
+    /// Networks that may open control connections.
+    pub control_access: AccessList,
+
+    /// Networks that may connect to any public tunnel port, further
+    /// restricted by the access lists clients request for their tunnels.
+    pub tunnel_access: AccessList,
+
+    /// Interval between heartbeats on control connections.
+    pub heartbeat_interval: Duration,
+
+    /// Number of heartbeat intervals without a message from a client that
+    /// answers pings, after which its control connection is closed.
+    pub max_missed_heartbeats: u32,
+
+    /// Time after which unaccepted incoming connections are discarded.
+    pub stale_timeout: Duration,
+
+    /// Time that the tunnels of a disconnected client are held for it to
+    /// resume its session, or zero to close them right away.
+    pub resume_grace: Duration,
+
+    /// Time without datagrams in either direction after which the server
+    /// forgets the peer of a UDP tunnel.
+    pub udp_idle_timeout: Duration,
+
+    /// Base domain that generated hostnames are subdomains of, and that
+    /// hostnames requested by clients must be within.
+    pub http_domain: Option<String>,
+}
+
+impl ServerSettings {
+    /// Create settings with a specified range of forwardable ports and default timing.
+    pub fn new(port_range: RangeInclusive<u16>, secret: Option<&str>) -> Self {
+        assert!(!port_range.is_empty(), "must provide at least one port");
+        ServerSettings {
+            port_range,
+            bind_tunnels: Ipv4Addr::UNSPECIFIED.into(),
+            auth: secret.map(Authenticator::new),
+            credentials: Credentials::default(),
+            require_mutual_auth: false,
+            tls: None,
+            noise: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            max_handshakes: MAX_HANDSHAKES,
+            bans: BanSettings::default(),
+            control_access: AccessList::default(),
+            tunnel_access: AccessList::default(),
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            max_missed_heartbeats: MAX_MISSED_HEARTBEATS,
+            stale_timeout: STALE_TIMEOUT,
+            resume_grace: RESUME_GRACE,
+            udp_idle_timeout: UDP_IDLE_TIMEOUT,
+            http_domain: None,
+        }
+    }
+}
+
+/// Shared handle for reading and replacing the settings of a running server.
+#[derive(Clone)]
+pub struct SettingsHandle(Arc<RwLock<Arc<ServerSettings>>>);
+
+impl SettingsHandle {
+    /// Returns the current settings.
+    pub fn get(&self) -> Arc<ServerSettings> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+
+    /// Replace the settings, affecting new connections from now on.
+    pub fn set(&self, settings: ServerSettings) {
+        *self.0.write().unwrap() = Arc::new(settings);
+    }
 }
 
 impl Server {
-    /// Create a new server with a specified minimum port number.
-    pub fn new(secret: Option<&str>) -> Self {
-        Server::new_with_tls(secret, None)
+    /// Create a new server with a specified range of forwardable ports.
+    pub fn new(port_range: RangeInclusive<u16>, secret: Option<&str>) -> Self {
+        Server::new_with_tls(port_range, secret, None)
     }
 
-    /// Create a new server with a specified minimum port number and tls is configurable.
-    pub fn new_with_tls(secret: Option<&str>, tls: Option<TlsAcceptor>) -> Self {
+    /// Create a new server with a specified range of forwardable ports and tls is configurable.
+    pub fn new_with_tls(
+        port_range: RangeInclusive<u16>,
+        secret: Option<&str>,
+        tls: Option<TlsAcceptor>,
+    ) -> Self {
+        let settings = ServerSettings {
+            tls,
+            ..ServerSettings::new(port_range, secret)
+        };
+        let control_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, CONTROL_PORT));
+        Server::with_settings(vec![control_addr], settings)
+    }
+
+    /// Create a new server accepting control connections on each of the given
+    /// addresses, with reloadable settings.
+    pub fn with_settings(control_addrs: Vec<SocketAddr>, settings: ServerSettings) -> Self {
+        assert!(!control_addrs.is_empty(), "must provide a control address");
         Server {
+            control_addrs,
+            settings: SettingsHandle(Arc::new(RwLock::new(Arc::new(settings)))),
             conns: Arc::new(DashMap::new()),
-            auth: secret.map(Authenticator::new),
-            tls,
+            http_addr: None,
+            hosts: Arc::new(DashMap::new()),
+            tunnel_usage: Usage::default(),
+            connection_usage: Usage::default(),
+            handshakes: Arc::default(),
+            bans: Arc::default(),
+            live_sessions: DashMap::new(),
+            reservations: Arc::new(DashMap::new()),
         }
     }
 
+    /// Also accept HTTP requests on a port shared by all clients, routing each
+    /// to the tunnel that registered the hostname it is for.
+    ///
+    /// Clients are told this port, so it must be given explicitly. Hostnames
+    /// are only registered within the `http_domain` of the settings.
+    pub fn with_http(mut self, addr: SocketAddr) -> Self {
+        assert!(addr.port() != 0, "must provide an http port");
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// Returns a handle for replacing the settings while the server is running.
+    pub fn settings(&self) -> SettingsHandle {
+        self.settings.clone()
+    }
+
+    /// Returns the list of banned addresses, for inspecting and lifting bans.
+    pub fn bans(&self) -> Arc<BanList> {
+        Arc::clone(&self.bans)
+    }
+
     /// Start the server, listening for new connections.
     pub async fn listen(self) -> Result<()> {
         let this = Arc::new(self);
-        let addr = SocketAddr::from(([0, 0, 0, 0], CONTROL_PORT));
-        let listener = TcpListener::bind(&addr).await?;
-        info!(?addr, "server listening");
+        let mut listeners = Vec::with_capacity(this.control_addrs.len());
+        for &addr in &this.control_addrs {
+            let listener =
+                bind_listener(addr).with_context(|| format!("could not listen on {addr}"))?;
+            info!(?addr, "server listening");
+            listeners.push(listener);
+        }
+        if let Some(addr) = this.http_addr {
+            let listener =
+                bind_listener(addr).with_context(|| format!("could not listen on {addr}"))?;
+            info!(?addr, "routing http requests by hostname");
+            tokio::spawn(Arc::clone(&this).route_http(listener));
+        }
+
+        let (bans, settings) = (this.bans(), this.settings());
+        tokio::spawn(async move {
+            loop {
+                sleep(BAN_PRUNE_INTERVAL).await;
+                bans.prune(&settings.get().bans);
+            }
+        });
 
         loop {
-            let (stream, addr) = listener.accept().await?;
+            let (stream, addr) = accept_any(&listeners).await?;
+            let settings = this.settings.get();
+            if !settings.control_access.permits(addr.ip()) {
+                debug!(?addr, "dropping control connection denied by access list");
+                ACCESS_DENIED_CONNECTIONS
+                    .with_label_values(&["control"])
+                    .inc();
+                continue;
+            }
+            if this.bans.is_banned(addr.ip(), &settings.bans) {
+                debug!(?addr, "dropping connection from banned address");
+                BANNED_CONNECTIONS.inc();
+                continue;
+            }
             let this = Arc::clone(&this);
-            let stream: Box<dyn StreamTrait> = match &this.tls {
-                Some(acceptor) => {
-                    let stream = match acceptor.accept(stream).await {
-                        Ok(stream) => stream,
-                        Err(err) => {
-                            warn!(%err,"failed to accept tls connection");
+
+            // Handshakes run in the connection's task, so slow clients cannot
+            // hold up the accept loop, but their number is limited.
+            let encryption = match (&settings.tls, &settings.noise) {
+                (Some(acceptor), _) => Some(Encryption::Tls(acceptor.clone())),
+                (None, Some(noise)) => Some(Encryption::Noise(noise.clone())),
+                (None, None) => None,
+            };
+            let handshake = match encryption {
+                Some(encryption) => {
+                    let gauge = encryption.handshakes();
+                    match HandshakeGuard::acquire(&this.handshakes, gauge, settings.max_handshakes)
+                    {
+                        Some(guard) => Some((encryption, guard, settings.handshake_timeout)),
+                        None => {
+                            warn!(
+                                ?addr,
+                                "too many {} handshakes in progress, dropping connection",
+                                encryption.name()
+                            );
+                            encryption
+                                .failures()
+                                .with_label_values(&["overloaded"])
+                                .inc();
                             continue;
                         }
-                    };
-                    Box::new(stream)
+                    }
                 }
-                None => Box::new(stream),
+                None => None,
             };
             tokio::spawn(
                 async move {
+                    let encrypted = handshake.is_some();
+                    let (stream, subject) = match handshake {
+                        Some((Encryption::Tls(acceptor), guard, limit)) => {
+                            match accept_tls(&acceptor, stream, limit, guard).await {
+                                Some(accepted) => accepted,
+                                None => return,
+                            }
+                        }
+                        Some((Encryption::Noise(noise), guard, limit)) => {
+                            match accept_noise(&noise, stream, limit, guard).await {
+                                Some(stream) => (stream, None),
+                                None => return,
+                            }
+                        }
+                        None => (Box::new(stream) as Box<dyn StreamTrait>, None),
+                    };
+
                     info!("incoming connection");
                     TOTAL_CONNECTIONS.inc();
-                    if let Err(err) = this.handle_connection(stream).await {
+                    if let Err(err) = this
+                        .handle_connection(stream, addr.ip(), encrypted, subject)
+                        .await
+                    {
                         warn!(%err, "connection exited with error");
                     } else {
                         info!("connection exited");
                     }
                     TOTAL_CONNECTIONS.dec();
                 }
-                .instrument(info_span!("control", ?addr)),
+                .instrument(info_span!(
+                    "control",
+                    ?addr,
+                    key_id = field::Empty,
+                    subject = field::Empty,
+                    client_key = field::Empty
+                )),
+            );
+        }
+    }
+
+    /// Hand connections to the HTTP port to the tunnels their requests are for.
+    async fn route_http(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(%err, "failed to accept http connection");
+                    sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            if !self.settings.get().tunnel_access.permits(addr.ip()) {
+                debug!(?addr, "dropping http connection denied by access list");
+                ACCESS_DENIED_CONNECTIONS.with_label_values(&["http"]).inc();
+                continue;
+            }
+            let this = Arc::clone(&self);
+            tokio::spawn(
+                async move { this.route_connection(stream, addr).await }
+                    .instrument(info_span!("http", ?addr)),
             );
         }
     }
 
-    async fn handle_connection(&self, stream: Box<dyn StreamTrait>) -> Result<()> {
+    /// Read the head of a request, and queue the connection for the tunnel
+    /// registered for its host, or answer with an error page.
+    async fn route_connection(&self, mut stream: TcpStream, addr: SocketAddr) {
+        let head = match timeout(NETWORK_TIMEOUT, http::read_head(&mut stream)).await {
+            Ok(Ok(head)) => head,
+            Ok(Err(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                let detail = "The request headers are too long.";
+                return reject_http(stream, 431, "Request Header Fields Too Large", detail).await;
+            }
+            Ok(Err(err)) => {
+                debug!(%err, "failed to read request");
+                return;
+            }
+            Err(_) => {
+                debug!("timed out reading request");
+                return;
+            }
+        };
+        let Some(host) = http::request_host(&head) else {
+            let detail = "The request does not name a valid host.";
+            return reject_http(stream, 400, "Bad Request", detail).await;
+        };
+        let route = self.hosts.get(&host).map(|route| route.clone());
+        let Some(route) = route else {
+            debug!(host, "no tunnel for host");
+            // Valid hostnames contain no markup.
+            let detail = format!("No tunnel is registered for <b>{host}</b>.");
+            return reject_http(stream, 404, "Not Found", &detail).await;
+        };
+        let Ok(permit) = route.try_reserve() else {
+            warn!(host, "tunnel is not taking connections, refusing request");
+            let detail = "The tunnel is busy, please try again later.";
+            return reject_http(stream, 503, "Service Unavailable", detail).await;
+        };
+        debug!(host, "routing request");
+        HTTP_CONNECTIONS.with_label_values(&["routed"]).inc();
+        // Later requests on the connection could be for another host, so the
+        // service is asked to close it after answering this one.
+        let head = http::close_after_request(&head);
+        permit.send((Prefixed::new(head, stream), addr));
+    }
+
+    async fn handle_connection(
+        &self,
+        stream: Box<dyn StreamTrait>,
+        ip: IpAddr,
+        encrypted: bool,
+        subject: Option<String>,
+    ) -> Result<()> {
         let mut stream = Delimited::new(stream);
+        if let Some(subject) = &subject {
+            Span::current().record("subject", &subject[..]);
+            CERTIFICATE_CONNECTIONS.with_label_values(&[subject]).inc();
+        }
 
-        if let Some(auth) = &self.auth {
-            if let Err(err) = auth.server_handshake(&mut stream).await {
-                warn!(%err, "server handshake failed");
+        let mut capabilities = vec![
+            Capability::MultiTunnel,
+            Capability::MutualAuth,
+            Capability::AccessLists,
+            Capability::TunnelControl,
+            Capability::Liveness,
+            Capability::Resume,
+            Capability::Udp,
+            Capability::Http,
+        ];
+        // Multiplexed streams are not authenticated by the control messages'
+        // tags, so a man-in-the-middle could open them on a plaintext connection.
+        if encrypted {
+            capabilities.insert(0, Capability::Multiplex);
+        }
+        // Without a grace period, no tunnels are held for resuming sessions.
+        if self.settings.get().resume_grace.is_zero() {
+            capabilities.retain(|&capability| capability != Capability::Resume);
+        }
+        let protocol = match ProtocolInfo::new(capabilities)
+            .server_negotiate(&mut stream)
+            .await
+        {
+            Ok(protocol) => protocol,
+            Err(err) => {
+                warn!(%err, "protocol negotiation failed");
                 stream.send(ServerMessage::Error(err.to_string())).await?;
                 return Ok(());
             }
+        };
+        debug!(?protocol, "negotiated protocol");
+
+        let settings = self.settings.get();
+        let mut key_id = None;
+        if settings.auth.is_some() || !settings.credentials.is_empty() {
+            let Some(_pending) = self.bans.start_auth(ip, &settings.bans) else {
+                warn!("too many authentications in progress from this address");
+                stream
+                    .send(ServerMessage::Error(
+                        "too many authentications in progress, try again later".into(),
+                    ))
+                    .await?;
+                return Ok(());
+            };
+            // The address may have been banned while the client was answering,
+            // by guesses made in parallel, and then even a right answer is refused.
+            let handshake = settings.credentials.server_handshake_with(
+                settings.auth.as_ref(),
+                !settings.require_mutual_auth,
+                &mut stream,
+                || {
+                    ensure!(
+                        !self.bans.is_banned(ip, &settings.bans),
+                        "address is banned, try again later"
+                    );
+                    Ok(())
+                },
+            );
+            match handshake.await {
+                _ if self.bans.is_banned(ip, &settings.bans) => {
+                    warn!("refusing authentication from banned address");
+                    BANNED_CONNECTIONS.inc();
+                    stream
+                        .send(ServerMessage::Error(
+                            "address is banned, try again later".into(),
+                        ))
+                        .await?;
+                    return Ok(());
+                }
+                Ok(Some(id)) => {
+                    Span::current().record("key_id", &id[..]);
+                    AUTHENTICATED_CONNECTIONS.with_label_values(&[&id]).inc();
+                    self.bans.record_success(ip, &settings.bans);
+                    key_id = Some(id);
+                }
+                Ok(None) => self.bans.record_success(ip, &settings.bans),
+                Err(err) => {
+                    warn!(%err, "server handshake failed");
+                    AUTH_FAILURES.inc();
+                    // Slow down guessing, more so with each failure.
+                    sleep(self.bans.record_failure(ip, &settings.bans)).await;
+                    stream.send(ServerMessage::Error(err.to_string())).await?;
+                    return Ok(());
+                }
+            }
+        }
+        if self.policy(key_id.as_deref()).require_tls && !encrypted {
+            return deny(&mut stream, key_id.as_deref(), Denial::Transport).await;
         }
 
         match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(_)) => {
+            Some(ClientMessage::Authenticate(_) | ClientMessage::AuthenticateMutual { .. }) => {
                 warn!("unexpected authenticate");
                 Ok(())
             }
-            Some(ClientMessage::Hello()) => {
-                CONNECTED_CLIENTS.inc();
-                info!("new client connected");
+            Some(ClientMessage::Version(_)) => {
+                warn!("unexpected version");
+                Ok(())
+            }
+            Some(
+                ClientMessage::Ping(_)
+                | ClientMessage::Pong(_)
+                | ClientMessage::AddTunnel { .. }
+                | ClientMessage::CloseTunnel(_)
+                | ClientMessage::Datagram { .. },
+            ) => {
+                warn!("unexpected control request before hello");
+                stream
+                    .send(ServerMessage::Error("expected hello".into()))
+                    .await?;
+                Ok(())
+            }
+            Some(ClientMessage::Hello(port)) => {
+                let request = TunnelRequest::single(port, false);
+                self.handle_tunnel(stream, request, &protocol, key_id).await
+            }
+            Some(ClientMessage::HelloMux(_)) if !protocol.supports(Capability::Multiplex) => {
+                warn!("multiplexing requested, but not negotiated");
+                stream
+                    .send(ServerMessage::Error(
+                        "multiplexing was not negotiated".into(),
+                    ))
+                    .await?;
+                Ok(())
+            }
+            Some(ClientMessage::HelloMux(port)) => {
+                let request = TunnelRequest::single(port, true);
+                self.handle_tunnel(stream, request, &protocol, key_id).await
+            }
+            Some(ClientMessage::HelloTunnels {
+                ports,
+                multiplex,
+                access,
+                resume,
+                udp,
+                http,
+            }) => {
+                let request = TunnelRequest {
+                    ports,
+                    access,
+                    multiplex,
+                    multi_tunnel: true,
+                    resume,
+                    udp,
+                    http,
+                };
+                if let Err(err) = check_tunnels(&protocol, &request) {
+                    warn!(%err, "invalid tunnel request");
+                    stream.send(ServerMessage::Error(err.to_string())).await?;
+                    return Ok(());
+                }
+                self.handle_tunnel(stream, request, &protocol, key_id).await
+            }
+            Some(ClientMessage::Accept(id, session)) => {
+                info!(%id, "forwarding connection");
+                match self
+                    .conns
+                    .remove_if(&id, |_, (owner, ..)| *owner == session)
+                {
+                    Some((_, (_, mut stream2, _held))) => {
+                        let parts = stream.into_parts();
+                        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
+                        stream2.write_all(&parts.read_buf).await?;
 
-                // ask the kernel for a available port
-                let listener = match TcpListener::bind(("0.0.0.0", 0)).await {
-                    Ok(listener) => listener,
-                    Err(_) => {
-                        warn!("could not bind to local port");
+                        let stream = byte_counter::CountingStream::new(parts.io);
+                        proxy(stream, stream2).await?
+                    }
+                    None if self.conns.contains_key(&id) => {
+                        warn!(%id, "connection belongs to another session");
+                        REJECTED_ACCEPTS.inc();
                         stream
-                            .send(ServerMessage::Error("port already in use".into()))
+                            .send(ServerMessage::Error(
+                                "connection not owned by session".into(),
+                            ))
                             .await?;
-                        CONNECTED_CLIENTS.dec();
+                    }
+                    None => warn!(%id, "missing connection"),
+                }
+                Ok(())
+            }
+            None => {
+                warn!("unexpected EOF");
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_tunnel(
+        &self,
+        mut stream: Delimited<Box<dyn StreamTrait>>,
+        request: TunnelRequest,
+        protocol: &ProtocolInfo,
+        key_id: Option<String>,
+    ) -> Result<()> {
+        let key_id = key_id.as_deref();
+        let resumed = match request.resume {
+            Some(session) => {
+                let tunnels = self.take_reservation(session, key_id, &request);
+                tunnels.await.map(|tunnels| (tunnels, session))
+            }
+            None => None,
+        };
+        let (mut tunnels, session) = match resumed {
+            Some(resumed) => {
+                info!("resuming session");
+                RESUMED_SESSIONS.inc();
+                resumed
+            }
+            None => match self.open_tunnels(&mut stream, &request, key_id).await? {
+                Some(tunnels) => (tunnels, Uuid::new_v4()),
+                None => return Ok(()),
+            },
+        };
+        let mut access = request.access.into_iter();
+        for tunnel in tunnels.iter_mut().flatten() {
+            tunnel.access = access.next().unwrap_or_default();
+        }
+        let ports = tunnels
+            .iter()
+            .flatten()
+            .map(|tunnel| Ok(tunnel.listener.local_addr()?.port()))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut hosts: Vec<_> = tunnels
+            .iter()
+            .flatten()
+            .map(|tunnel| tunnel.listener.host().map(String::from))
+            .collect();
+        if hosts.iter().all(Option::is_none) {
+            hosts.clear();
+        }
+        CONNECTED_CLIENTS.inc();
+        info!(
+            ?ports,
+            multiplex = request.multiplex,
+            "new client connected"
+        );
+        for host in hosts.iter().flatten() {
+            info!(host, "routing http requests to client");
+        }
+
+        // Another connection resuming the session stops this one, and waits
+        // for it to exit.
+        let resumable = protocol.supports(Capability::Resume);
+        let (stop_tx, stop) = oneshot::channel();
+        let (_exited, exited) = oneshot::channel();
+        let mut state = TunnelState {
+            tunnels,
+            multi_tunnel: request.multi_tunnel,
+            session,
+            ping: None,
+            last_seen: Instant::now(),
+            stop,
+            flows: FlowTable::default(),
+        };
+        let live = LiveSession {
+            key_id: key_id.map(String::from),
+            _stop: stop_tx,
+            exited,
+        };
+        let _live = if resumable {
+            self.live_sessions.insert(session, live);
+            None
+        } else {
+            Some(live)
+        };
+        // The client closes its end of a UDP flow when the server forgets it.
+        let udp_idle_timeout_ms = protocol
+            .supports(Capability::Udp)
+            .then(|| self.settings.get().udp_idle_timeout.as_millis() as u64);
+        let result = async {
+            if request.multi_tunnel {
+                stream
+                    .send(ServerMessage::HelloTunnels {
+                        ports,
+                        session,
+                        hosts,
+                        udp_idle_timeout_ms,
+                    })
+                    .await?;
+            } else {
+                stream.send(ServerMessage::Hello(ports[0], session)).await?;
+            }
+            if request.multiplex {
+                self.multiplexed_tunnel(stream, &mut state, protocol, key_id)
+                    .await
+            } else {
+                self.control_loop(stream, &mut state, protocol, key_id, None)
+                    .await
+            }
+        };
+        let result = result.await;
+        CONNECTED_CLIENTS.dec();
+        if resumable {
+            // The tunnels are held before the session stops being live, so a
+            // connection resuming it finds them either way.
+            if !self.is_revoked(key_id) {
+                self.reserve(session, state.tunnels, key_id);
+            }
+            self.live_sessions.remove(&session);
+        }
+        result
+    }
+
+    /// Bind listeners for the tunnels a client requested, unless its policy
+    /// denies them, in which case the client is told and `None` returned.
+    async fn open_tunnels(
+        &self,
+        stream: &mut Delimited<Box<dyn StreamTrait>>,
+        request: &TunnelRequest,
+        key_id: Option<&str>,
+    ) -> Result<Option<Vec<Option<OpenTunnel>>>> {
+        let policy = self.policy(key_id);
+        let denied = request.ports.iter().enumerate().any(|(index, &port)| {
+            port != 0 && request.kind(index).binds_port() && !policy.allows_port(port)
+        });
+        if denied {
+            deny(stream, key_id, Denial::Port).await?;
+            return Ok(None);
+        }
+        let usage = self
+            .tunnel_usage
+            .acquire(key_id, request.ports.len(), policy.max_tunnels);
+        let Some(usage) = usage else {
+            deny(stream, key_id, Denial::Tunnels).await?;
+            return Ok(None);
+        };
+
+        let mut tunnels = Vec::with_capacity(request.ports.len());
+        for (index, (&port, usage)) in request.ports.iter().zip(usage.split()).enumerate() {
+            match self
+                .create_listener(port, request.kind(index), &policy)
+                .await
+            {
+                Ok(listener) => tunnels.push(Some(OpenTunnel {
+                    listener,
+                    access: AccessList::default(),
+                    _usage: usage,
+                })),
+                Err(err) => {
+                    warn!(port, err, "could not bind to local port");
+                    stream.send(ServerMessage::Error(err.into())).await?;
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(Some(tunnels))
+    }
+
+    /// Hold the tunnels of a disconnected session for the grace period, so
+    /// that the client can resume it on the same ports.
+    fn reserve(&self, session: Uuid, tunnels: Vec<Option<OpenTunnel>>, key_id: Option<&str>) {
+        let grace = self.settings.get().resume_grace;
+        if grace.is_zero() || tunnels.iter().all(Option::is_none) {
+            return;
+        }
+        info!(%session, ?grace, "holding tunnels for the client to resume");
+        let expires = Instant::now() + grace;
+        let reservation = Reservation {
+            tunnels,
+            key_id: key_id.map(String::from),
+            expires,
+        };
+        self.reservations.insert(session, reservation);
+
+        let reservations = Arc::clone(&self.reservations);
+        tokio::spawn(async move {
+            sleep_until(expires).await;
+            if reservations
+                .remove_if(&session, |_, reservation| reservation.expires <= expires)
+                .is_some()
+            {
+                info!(%session, "released tunnels of session that was not resumed");
+            }
+        });
+    }
+
+    /// Take the tunnels the server holds for a session, if the client has
+    /// the same credential and asks for the same ports and protocols.
+    ///
+    /// A control connection still running the session is stopped first, as
+    /// the client has evidently lost it.
+    async fn take_reservation(
+        &self,
+        session: Uuid,
+        key_id: Option<&str>,
+        request: &TunnelRequest,
+    ) -> Option<Vec<Option<OpenTunnel>>> {
+        let owned = |owner: &Option<String>| owner.as_deref() == key_id;
+        let live = self
+            .live_sessions
+            .remove_if(&session, |_, live| owned(&live.key_id));
+        // The stopped connection holds its tunnels, if any, before it exits.
+        if let Some((_, live)) = live {
+            info!(%session, "taking over session from previous control connection");
+            drop(live._stop);
+            timeout(NETWORK_TIMEOUT, live.exited).await.ok();
+        }
+        let (_, reservation) = self
+            .reservations
+            .remove_if(&session, |_, reservation| owned(&reservation.key_id))?;
+        let mut tunnels = reservation.tunnels;
+
+        // Tunnels added later are released, as are all of them if the client
+        // changed its requested ports or closed one in the meantime.
+        let ports = &request.ports;
+        let kept = tunnels.len() >= ports.len()
+            && tunnels
+                .iter()
+                .zip(ports)
+                .enumerate()
+                .all(|(index, (tunnel, &port))| {
+                    tunnel.as_ref().is_some_and(|tunnel| {
+                        let addr = tunnel.listener.local_addr();
+                        (port == 0 || addr.is_ok_and(|addr| addr.port() == port))
+                            && tunnel.listener.serves(request.kind(index))
+                    })
+                });
+        if !kept {
+            info!(%session, "held tunnels do not match request, opening new ones");
+            return None;
+        }
+        tunnels.truncate(ports.len());
+        Some(tunnels)
+    }
+
+    /// Announce connections to the client on the control connection, while
+    /// sending heartbeats and answering the client's requests as they arrive.
+    ///
+    /// With `mux`, connections are forwarded over logical streams of a
+    /// multiplexed connection instead of being accepted by the client.
+    async fn control_loop(
+        &self,
+        mut stream: Delimited<Box<dyn StreamTrait>>,
+        state: &mut TunnelState,
+        protocol: &ProtocolInfo,
+        key_id: Option<&str>,
+        mux: Option<&Control>,
+    ) -> Result<()> {
+        // Clients that answer pings are dropped once they stop answering.
+        let liveness = protocol.supports(Capability::Liveness);
+        let mut next_heartbeat = Instant::now();
+        loop {
+            tokio::select! {
+                _ = sleep_until(next_heartbeat) => {
+                    let settings = self.settings.get();
+                    let deadline = settings.heartbeat_interval * settings.max_missed_heartbeats;
+                    if liveness && state.last_seen.elapsed() > deadline {
+                        warn!("client missed heartbeats, closing control connection");
+                        HEARTBEAT_TIMEOUTS.inc();
                         return Ok(());
                     }
-                };
-                let port = listener.local_addr()?.port();
-                stream.send(ServerMessage::Hello(port)).await?;
 
-                loop {
                     debug!("sending connection heartbeat");
                     HEARTBEATS.inc();
-
-                    if stream.send(ServerMessage::Heartbeat).await.is_err() {
+                    let heartbeat = if liveness {
+                        let value = fastrand::u64(..);
+                        state.ping = Some((value, Instant::now()));
+                        ServerMessage::Ping(value)
+                    } else {
+                        ServerMessage::Heartbeat
+                    };
+                    if stream.send(heartbeat).await.is_err() {
                         // Assume that the TCP connection has been dropped.
-                        CONNECTED_CLIENTS.dec();
                         return Ok(());
                     }
-                    const TIMEOUT: Duration = Duration::from_millis(2000);
-                    if let Ok(result) = timeout(TIMEOUT, listener.accept()).await {
-                        let (stream2, addr) = result?;
-                        info!(?addr, ?port, "new connection");
+                    if self.is_revoked(key_id) {
+                        return close_revoked(stream).await;
+                    }
+                    state.flows.expire(settings.udp_idle_timeout);
+                    next_heartbeat = Instant::now() + settings.heartbeat_interval;
+                }
+                _ = &mut state.stop => {
+                    info!("session resumed on another connection");
+                    return Ok(());
+                }
+                result = self.accept_tunnel(&state.tunnels) => {
+                    let (tunnel, stream2, addr) = result?;
+                    info!(?addr, tunnel, "new connection");
 
-                        let id = Uuid::new_v4();
-                        let conns = Arc::clone(&self.conns);
+                    let max_connections = self.policy(key_id).max_connections;
+                    let usage = self.connection_usage.acquire(key_id, 1, max_connections);
+                    let Some(held) = usage else {
+                        deny(&mut stream, key_id, Denial::Connections).await?;
+                        continue;
+                    };
 
-                        conns.insert(id, stream2);
-                        tokio::spawn(async move {
-                            // Remove stale entries to avoid memory leaks.
-                            sleep(Duration::from_secs(10)).await;
-                            if conns.remove(&id).is_some() {
-                                warn!(%id, "removed stale connection");
-                            }
-                        });
-                        stream.send(ServerMessage::Connection(id)).await?;
+                    let id = Uuid::new_v4();
+                    if let Some(mux) = mux {
+                        forward_multiplexed(mux.clone(), state.multi_tunnel, tunnel, id, stream2, held);
+                        continue;
                     }
+                    let conns = Arc::clone(&self.conns);
+
+                    conns.insert(id, (state.session, stream2, held));
+                    let stale_timeout = self.settings.get().stale_timeout;
+                    tokio::spawn(async move {
+                        // Remove stale entries to avoid memory leaks.
+                        sleep(stale_timeout).await;
+                        if conns.remove(&id).is_some() {
+                            warn!(%id, "removed stale connection");
+                        }
+                    });
+                    stream
+                        .send(connection_message(state.multi_tunnel, tunnel, id))
+                        .await?;
+                }
+                (tunnel, peer, data) = self.recv_datagram(&state.tunnels) => {
+                    let Some(flow) = state.flows.open(tunnel, peer) else {
+                        debug!(?peer, tunnel, "too many udp flows, dropping datagram");
+                        DROPPED_DATAGRAMS.inc();
+                        continue;
+                    };
+                    let data = hex::encode(data);
+                    stream
+                        .send(ServerMessage::Datagram { tunnel, flow, data })
+                        .await?;
                 }
+                message = stream.recv() => match message {
+                    Ok(Some(message)) => {
+                        state.last_seen = Instant::now();
+                        let reply = self.handle_request(state, message, protocol, key_id).await;
+                        if let Some(reply) = reply {
+                            stream.send(reply).await?;
+                        }
+                    }
+                    Ok(None) => {
+                        info!("client disconnected");
+                        return Ok(());
+                    }
+                    Err(err) => {
+                        warn!(%err, "closing control connection");
+                        return Ok(());
+                    }
+                },
             }
-            Some(ClientMessage::Accept(id)) => {
-                info!(%id, "forwarding connection");
-                match self.conns.remove(&id) {
-                    Some((_, mut stream2)) => {
-                        let parts = stream.into_parts();
-                        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-                        stream2.write_all(&parts.read_buf).await?;
+        }
+    }
 
-                        let stream = byte_counter::CountingStream::new(parts.io);
-                        proxy(stream, stream2).await?
+    /// Answer a request that the client sent after its hello, if it needs an answer.
+    async fn handle_request(
+        &self,
+        state: &mut TunnelState,
+        message: ClientMessage,
+        protocol: &ProtocolInfo,
+        key_id: Option<&str>,
+    ) -> Option<ServerMessage> {
+        let reply = match message {
+            ClientMessage::Ping(value) => ServerMessage::Pong(value),
+            ClientMessage::Pong(value) => {
+                match state.ping {
+                    Some((ping, sent)) if ping == value => {
+                        state.ping = None;
+                        let rtt = sent.elapsed();
+                        debug!(?rtt, "measured round-trip time");
+                        CONTROL_RTT.observe(rtt.as_secs_f64());
                     }
-                    None => warn!(%id, "missing connection"),
+                    _ => debug!("ignoring unexpected pong"),
                 }
-                Ok(())
+                return None;
             }
-            None => {
-                warn!("unexpected EOF");
-                Ok(())
+            ClientMessage::AddTunnel { .. } | ClientMessage::CloseTunnel(_)
+                if !state.multi_tunnel =>
+            {
+                ServerMessage::Refused("tunnels can only be changed after HelloTunnels".into())
+            }
+            ClientMessage::AddTunnel {
+                port,
+                access,
+                udp,
+                http,
+            } => {
+                let added = match TunnelKind::new(udp, http.as_deref()) {
+                    Ok(kind) => {
+                        let added = self.add_tunnel(state, port, kind, access, protocol, key_id);
+                        added.await
+                    }
+                    Err(err) => Err(err),
+                };
+                match added {
+                    Ok((tunnel, port, host)) => {
+                        info!(tunnel, port, host, "added tunnel");
+                        ServerMessage::TunnelAdded { tunnel, port, host }
+                    }
+                    Err(err) => {
+                        warn!(%err, "refused to add tunnel");
+                        ServerMessage::Refused(err.to_string())
+                    }
+                }
+            }
+            ClientMessage::CloseTunnel(tunnel) => {
+                match state.tunnels.get_mut(tunnel).and_then(Option::take) {
+                    Some(_) => {
+                        info!(tunnel, "closed tunnel");
+                        state.flows.close_tunnel(tunnel);
+                        ServerMessage::TunnelClosed(tunnel)
+                    }
+                    None => ServerMessage::Refused(format!("tunnel {tunnel} is not open")),
+                }
+            }
+            ClientMessage::Datagram { flow, data } => {
+                reply_datagram(state, flow, &data).await;
+                return None;
+            }
+            _ => {
+                warn!("ignoring unexpected message from client");
+                return None;
+            }
+        };
+        Some(reply)
+    }
+
+    /// Open another tunnel on a control connection, returning its index,
+    /// public port and hostname, if it is routed by hostname.
+    async fn add_tunnel(
+        &self,
+        state: &mut TunnelState,
+        port: u16,
+        kind: TunnelKind<'_>,
+        access: AccessList,
+        protocol: &ProtocolInfo,
+        key_id: Option<&str>,
+    ) -> Result<(usize, u16, Option<String>)> {
+        ensure!(
+            protocol.supports(Capability::TunnelControl),
+            "tunnel control was not negotiated"
+        );
+        ensure!(
+            kind != TunnelKind::Udp || protocol.supports(Capability::Udp),
+            "udp tunnels were not negotiated"
+        );
+        ensure!(
+            !matches!(kind, TunnelKind::Http(_)) || protocol.supports(Capability::Http),
+            "http tunnels were not negotiated"
+        );
+        ensure!(
+            state.tunnels.len() < MAX_TUNNELS,
+            "at most {MAX_TUNNELS} tunnels may be opened per connection"
+        );
+        check_access(protocol, std::slice::from_ref(&access))?;
+        let policy = self.policy(key_id);
+        if port != 0 && kind.binds_port() && !policy.allows_port(port) {
+            bail!(record_denial(key_id, Denial::Port));
+        }
+        let usage = self.tunnel_usage.acquire(key_id, 1, policy.max_tunnels);
+        let Some(usage) = usage else {
+            bail!(record_denial(key_id, Denial::Tunnels));
+        };
+        let listener = self
+            .create_listener(port, kind, &policy)
+            .await
+            .map_err(anyhow::Error::msg)?;
+        let port = listener.local_addr()?.port();
+        let host = listener.host().map(String::from);
+        state.tunnels.push(Some(OpenTunnel {
+            listener,
+            access,
+            _usage: usage,
+        }));
+        Ok((state.tunnels.len() - 1, port, host))
+    }
+
+    /// Accept the next connection on any open TCP or HTTP tunnel that the
+    /// server's and the tunnel's access lists permit, dropping any others.
+    ///
+    /// Waits indefinitely while no such tunnel is open.
+    async fn accept_tunnel(
+        &self,
+        tunnels: &[Option<OpenTunnel>],
+    ) -> io::Result<(usize, Incoming, SocketAddr)> {
+        loop {
+            let accepts: Vec<_> = tunnels
+                .iter()
+                .enumerate()
+                .filter_map(|(index, tunnel)| {
+                    let tunnel = tunnel.as_ref()?;
+                    if let Listener::Udp(_) = tunnel.listener {
+                        return None;
+                    }
+                    Some(Box::pin(async move {
+                        (index, tunnel, tunnel.listener.accept().await)
+                    }))
+                })
+                .collect();
+            if accepts.is_empty() {
+                return future::pending().await;
+            }
+            let ((index, tunnel, result), ..) = select_all(accepts).await;
+            let (stream, addr) = result?;
+            let global = &self.settings.get().tunnel_access;
+            if global.permits(addr.ip()) && tunnel.access.permits(addr.ip()) {
+                return Ok((index, stream, addr));
             }
+            debug!(
+                ?addr,
+                tunnel = index,
+                "dropping tunnel connection denied by access list"
+            );
+            ACCESS_DENIED_CONNECTIONS
+                .with_label_values(&["tunnel"])
+                .inc();
         }
     }
+
+    /// Receive the next datagram on any open UDP tunnel from a peer that the
+    /// server's and the tunnel's access lists permit, dropping any others.
+    ///
+    /// Waits indefinitely while no UDP tunnel is open.
+    async fn recv_datagram(&self, tunnels: &[Option<OpenTunnel>]) -> (usize, SocketAddr, Vec<u8>) {
+        loop {
+            let (index, tunnel, (peer, data)) = future::poll_fn(|cx| {
+                for (index, tunnel) in tunnels.iter().enumerate() {
+                    let Some(tunnel) = tunnel else { continue };
+                    let Listener::Udp(udp) = &tunnel.listener else {
+                        continue;
+                    };
+                    if let Poll::Ready(Some(datagram)) = udp.poll_recv(cx) {
+                        return Poll::Ready((index, tunnel, datagram));
+                    }
+                }
+                Poll::Pending
+            })
+            .await;
+            let global = &self.settings.get().tunnel_access;
+            if global.permits(peer.ip()) && tunnel.access.permits(peer.ip()) {
+                return (index, peer, data);
+            }
+            debug!(
+                ?peer,
+                tunnel = index,
+                "dropping datagram denied by access list"
+            );
+            ACCESS_DENIED_CONNECTIONS
+                .with_label_values(&["tunnel"])
+                .inc();
+        }
+    }
+
+    /// Run the control loop on the first logical stream of a multiplexed
+    /// connection, forwarding each connection over a stream of its own.
+    async fn multiplexed_tunnel(
+        &self,
+        stream: Delimited<Box<dyn StreamTrait>>,
+        state: &mut TunnelState,
+        protocol: &ProtocolInfo,
+        key_id: Option<&str>,
+    ) -> Result<()> {
+        let mut session = Session::new_server(stream.into_stream(), mux_config());
+        let mut mux = session.control();
+
+        // The session only makes progress while polled, so drive it in the background.
+        tokio::spawn(async move {
+            while let Some(result) = session.next().await {
+                match result {
+                    Ok(_) => warn!("ignoring stream opened by client"),
+                    Err(err) => {
+                        warn!(%err, "multiplexed session failed");
+                        break;
+                    }
+                }
+            }
+        });
+
+        let control: Box<dyn StreamTrait> = Box::new(mux.open_stream().await?);
+        let result = self
+            .control_loop(Delimited::new(control), state, protocol, key_id, Some(&mux))
+            .await;
+        mux.close().await;
+        result
+    }
+
+    /// Returns the current policy of a client, which allows everything without
+    /// a named credential.
+    fn policy(&self, key_id: Option<&str>) -> Policy {
+        key_id
+            .and_then(|key_id| self.settings.get().credentials.policy(key_id).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Returns whether the named credential of a client has been removed.
+    fn is_revoked(&self, key_id: Option<&str>) -> bool {
+        key_id.is_some_and(|key_id| !self.settings.get().credentials.contains(key_id))
+    }
+
+    /// Bind a public TCP listener or UDP socket on the requested port, or on
+    /// any free port in range if 0, or register a hostname for HTTP tunnels.
+    ///
+    /// Random ports are only picked where the client's policy allows them.
+    async fn create_listener(
+        &self,
+        port: u16,
+        kind: TunnelKind<'_>,
+        policy: &Policy,
+    ) -> Result<Listener, &'static str> {
+        if let TunnelKind::Http(requested) = kind {
+            return self.route_host(requested).map(Listener::Http);
+        }
+        let settings = self.settings.get();
+        let port_range = settings.port_range.clone();
+        let try_bind = |port: u16| {
+            let addr = SocketAddr::new(settings.bind_tunnels, port);
+            let listener = if kind == TunnelKind::Udp {
+                bind_udp(addr).map(|socket| Listener::Udp(UdpListener::new(socket)))
+            } else {
+                bind_listener(addr).map(Listener::Tcp)
+            };
+            listener.map_err(|err| match err.kind() {
+                io::ErrorKind::AddrInUse => "port already in use",
+                io::ErrorKind::PermissionDenied => "permission denied",
+                _ => "failed to bind to port",
+            })
+        };
+        if port > 0 {
+            // Client requests a specific port number.
+            if !port_range.contains(&port) {
+                return Err("client port number not in allowed range");
+            }
+            try_bind(port)
+        } else {
+            // Client requests any available port in range.
+            //
+            // Try a bounded number of random ports, since the range may be mostly taken.
+            let ranges = policy.port_ranges(&port_range);
+            if ranges.is_empty() {
+                return Err("no ports in range allowed by policy");
+            }
+            for _ in 0..150 {
+                let range = &ranges[fastrand::usize(..ranges.len())];
+                let port = fastrand::u16(range.clone());
+                if let Ok(listener) = try_bind(port) {
+                    return Ok(listener);
+                }
+            }
+            Err("failed to find an available port")
+        }
+    }
+
+    /// Register a hostname on the HTTP port, or a generated subdomain of the
+    /// base domain if the client requested none.
+    ///
+    /// Requested names without a dot are taken as subdomains of the base domain.
+    fn route_host(&self, requested: &str) -> Result<HostRoute, &'static str> {
+        let addr = self
+            .http_addr
+            .ok_or("server does not route http by hostname")?;
+        // Without a domain of its own, clients could claim any hostname.
+        let settings = self.settings.get();
+        let domain = settings
+            .http_domain
+            .as_deref()
+            .ok_or("server has no domain to route http by hostname")?;
+        let (sender, connections) = mpsc::channel(HTTP_BACKLOG);
+        let host = loop {
+            let host = match requested {
+                "" => http::generate_hostname(domain),
+                requested if !requested.contains('.') => {
+                    format!("{}.{domain}", http::normalize_hostname(requested))
+                }
+                requested => {
+                    let host = http::normalize_hostname(requested);
+                    if !host.ends_with(&format!(".{domain}")) {
+                        return Err("hostname is not within the server's domain");
+                    }
+                    host
+                }
+            };
+            if !http::is_valid_hostname(&host) {
+                return Err("invalid hostname");
+            }
+            match self.hosts.entry(host.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(sender);
+                    break host;
+                }
+                // Collisions of generated names are merely unlucky.
+                Entry::Occupied(_) if requested.is_empty() => continue,
+                Entry::Occupied(_) => return Err("hostname already in use"),
+            }
+        };
+        Ok(HostRoute {
+            requested: requested.to_string(),
+            host,
+            addr,
+            connections: Mutex::new(connections),
+            hosts: Arc::clone(&self.hosts),
+        })
+    }
+}
+
+/// Bind a TCP listener, accepting IPv4 as well on an unspecified IPv6 address.
+///
+/// Dual-stack binding is best effort, since some systems only allow IPv6 there.
+fn bind_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        let _ = socket.set_only_v6(false);
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Bind a UDP socket, accepting IPv4 as well on an unspecified IPv6 address.
+///
+/// Unlike TCP listeners, the address is not reused, since that would let
+/// several sockets share the port.
+fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        let _ = socket.set_only_v6(false);
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Answer a request on the HTTP port with an error page, and close the connection.
+async fn reject_http(mut stream: TcpStream, status: u16, reason: &str, detail: &str) {
+    HTTP_CONNECTIONS
+        .with_label_values(&[&status.to_string()])
+        .inc();
+    let response = http::error_response(status, reason, detail);
+    stream.write_all(response.as_bytes()).await.ok();
+    stream.shutdown().await.ok();
+}
+
+/// Send a reply of the client's local service on a UDP flow to its peer.
+async fn reply_datagram(state: &mut TunnelState, flow: u32, data: &str) {
+    let Some((tunnel, peer)) = state.flows.peer(flow) else {
+        debug!(flow, "dropping datagram of unknown flow");
+        DROPPED_DATAGRAMS.inc();
+        return;
+    };
+    let Some(Some(OpenTunnel {
+        listener: Listener::Udp(udp),
+        ..
+    })) = state.tunnels.get(tunnel)
+    else {
+        return;
+    };
+    match hex::decode(data) {
+        Ok(data) if data.len() <= MAX_DATAGRAM_LENGTH => {
+            if let Err(err) = udp.socket.send_to(&data, peer).await {
+                debug!(%err, ?peer, "failed to send datagram");
+            }
+        }
+        _ => {
+            warn!(flow, "dropping invalid datagram from client");
+            DROPPED_DATAGRAMS.inc();
+        }
+    }
+}
+
+/// Tell a client that its credential was revoked, before closing its tunnel.
+async fn close_revoked(mut stream: Delimited<Box<dyn StreamTrait>>) -> Result<()> {
+    warn!("credential revoked, closing tunnel");
+    REVOKED_SESSIONS.inc();
+    stream
+        .send(ServerMessage::Error("credential revoked".into()))
+        .await
+}
+
+/// Returns the subject common name of a client certificate, or the whole
+/// subject if it has no common name.
+fn certificate_subject(cert: &Certificate) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let subject = cert.subject();
+    let common_name = subject.iter_common_name().next();
+    match common_name.and_then(|name| name.as_str().ok()) {
+        Some(name) => Some(name.to_string()),
+        None => Some(subject.to_string()),
+    }
+}
+
+/// Report a request denied by a client's policy.
+async fn deny<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut Delimited<T>,
+    key_id: Option<&str>,
+    denial: Denial,
+) -> Result<()> {
+    let denial = record_denial(key_id, denial);
+    stream.send(ServerMessage::Error(denial.to_string())).await
+}
+
+/// Log and count a request denied by a client's policy.
+fn record_denial(key_id: Option<&str>, denial: Denial) -> Denial {
+    warn!(%denial, "denied by policy");
+    POLICY_DENIALS
+        .with_label_values(&[key_id.unwrap_or_default(), denial.label()])
+        .inc();
+    denial
+}
+
+/// Check that a request for several tunnels is allowed by the negotiated protocol.
+fn check_tunnels(protocol: &ProtocolInfo, request: &TunnelRequest) -> Result<()> {
+    let TunnelRequest {
+        ports,
+        access,
+        multiplex,
+        resume,
+        udp,
+        http,
+        ..
+    } = request;
+    ensure!(
+        protocol.supports(Capability::MultiTunnel),
+        "multiple tunnels were not negotiated"
+    );
+    ensure!(
+        !multiplex || protocol.supports(Capability::Multiplex),
+        "multiplexing was not negotiated"
+    );
+    ensure!(
+        resume.is_none() || protocol.supports(Capability::Resume),
+        "resuming sessions was not negotiated"
+    );
+    ensure!(!ports.is_empty(), "no tunnels requested");
+    ensure!(
+        ports.len() <= MAX_TUNNELS,
+        "at most {MAX_TUNNELS} tunnels may be requested"
+    );
+    ensure!(
+        access.is_empty() || access.len() == ports.len(),
+        "access lists do not match the requested tunnels"
+    );
+    ensure!(
+        udp.is_empty() || udp.len() == ports.len(),
+        "udp flags do not match the requested tunnels"
+    );
+    if udp.contains(&true) {
+        ensure!(
+            protocol.supports(Capability::Udp),
+            "udp tunnels were not negotiated"
+        );
+        ensure!(!multiplex, "udp tunnels cannot be multiplexed");
+    }
+    ensure!(
+        http.is_empty() || http.len() == ports.len(),
+        "http hostnames do not match the requested tunnels"
+    );
+    if http.iter().any(Option::is_some) {
+        ensure!(
+            protocol.supports(Capability::Http),
+            "http tunnels were not negotiated"
+        );
+        ensure!(
+            !http
+                .iter()
+                .zip(udp)
+                .any(|(http, &udp)| http.is_some() && udp),
+            "a tunnel cannot forward both udp and http"
+        );
+    }
+    check_access(protocol, access)
+}
+
+/// Check that the access lists requested for tunnels are negotiated and not too long.
+fn check_access(protocol: &ProtocolInfo, access: &[AccessList]) -> Result<()> {
+    if access.iter().all(AccessList::is_empty) {
+        return Ok(());
+    }
+    ensure!(
+        protocol.supports(Capability::AccessLists),
+        "access lists were not negotiated"
+    );
+    ensure!(
+        access.iter().all(|list| list.len() <= MAX_ACCESS_RULES),
+        "at most {MAX_ACCESS_RULES} networks may be listed per tunnel"
+    );
+    Ok(())
+}
+
+/// Accept the next connection on any of the control listeners.
+async fn accept_any(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+    let (result, ..) = select_all(accepts).await;
+    result
+}
+
+/// Tunnels requested by a client's hello.
+struct TunnelRequest {
+    /// Requested public ports, or 0 for any port.
+    ports: Vec<u16>,
+
+    /// Access lists for each tunnel, or empty for no restrictions.
+    access: Vec<AccessList>,
+
+    /// Whether proxied streams are multiplexed over the control connection.
+    multiplex: bool,
+
+    /// Whether the client used `HelloTunnels`, so tunnels are referred to by index.
+    multi_tunnel: bool,
+
+    /// Session token of a previous connection that the client wants to resume.
+    resume: Option<Uuid>,
+
+    /// Whether each tunnel forwards UDP, or empty for all TCP.
+    udp: Vec<bool>,
+
+    /// Hostname that each tunnel is routed HTTP requests by, or empty for none.
+    http: Vec<Option<String>>,
+}
+
+impl TunnelRequest {
+    /// A request for one tunnel, as made by `Hello` and `HelloMux`.
+    fn single(port: u16, multiplex: bool) -> Self {
+        TunnelRequest {
+            ports: vec![port],
+            access: Vec::new(),
+            multiplex,
+            multi_tunnel: false,
+            resume: None,
+            udp: Vec::new(),
+            http: Vec::new(),
+        }
+    }
+
+    /// Returns what the tunnel at an index forwards.
+    fn kind(&self, index: usize) -> TunnelKind<'_> {
+        let udp = self.udp.get(index).copied().unwrap_or(false);
+        let http = self.http.get(index).and_then(Option::as_deref);
+        TunnelKind::new(udp, http).unwrap_or(TunnelKind::Tcp)
+    }
+}
+
+/// What a tunnel forwards, and how the server receives it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TunnelKind<'a> {
+    /// TCP connections to a port of its own.
+    Tcp,
+
+    /// UDP datagrams to a port of its own.
+    Udp,
+
+    /// HTTP requests for a hostname on the shared HTTP port, or for a
+    /// generated one if empty.
+    Http(&'a str),
+}
+
+impl<'a> TunnelKind<'a> {
+    /// The kind of tunnel a client asks for with these flags.
+    fn new(udp: bool, http: Option<&'a str>) -> Result<Self> {
+        match (udp, http) {
+            (false, None) => Ok(TunnelKind::Tcp),
+            (true, None) => Ok(TunnelKind::Udp),
+            (false, Some(host)) => Ok(TunnelKind::Http(host)),
+            (true, Some(_)) => bail!("a tunnel cannot forward both udp and http"),
+        }
+    }
+
+    /// Returns whether the tunnel listens on the port the client requests,
+    /// which the client's policy must allow, rather than the shared HTTP port.
+    fn binds_port(self) -> bool {
+        !matches!(self, TunnelKind::Http(_))
+    }
+}
+
+/// Tunnels of a control connection, which the client may add to and close.
+struct TunnelState {
+    /// Open tunnels by index, with `None` for closed ones so indices stay stable.
+    tunnels: Vec<Option<OpenTunnel>>,
+
+    /// Whether the client used `HelloTunnels`, so tunnels are referred to by index.
+    multi_tunnel: bool,
+
+    /// Session token issued to the client in the hello.
+    session: Uuid,
+
+    /// Value and time of the last ping sent to the client, until it is answered.
+    ping: Option<(u64, Instant)>,
+
+    /// When the last message from the client was received.
+    last_seen: Instant,
+
+    /// Completes when another connection resumes the session.
+    stop: oneshot::Receiver<()>,
+
+    /// Peers of UDP tunnels, named by flow IDs in datagrams to and from the client.
+    flows: FlowTable,
+}
+
+/// A control connection running a session, as seen by connections resuming it.
+struct LiveSession {
+    /// Named credential of the client, which a resuming client must share.
+    key_id: Option<String>,
+
+    /// Dropped to stop the connection.
+    _stop: oneshot::Sender<()>,
+
+    /// Completes once the connection exited, after holding its tunnels.
+    exited: oneshot::Receiver<()>,
+}
+
+/// Tunnels held for a disconnected client to resume its session.
+struct Reservation {
+    tunnels: Vec<Option<OpenTunnel>>,
+
+    /// Named credential of the client, which a resuming client must share.
+    key_id: Option<String>,
+
+    /// When the tunnels are released.
+    expires: Instant,
+}
+
+/// Public listener of a tunnel.
+struct OpenTunnel {
+    listener: Listener,
+
+    /// Networks that the client allows to connect, within the server's list.
+    access: AccessList,
+
+    /// The tunnel's share of its credential's tunnel limit.
+    _usage: UsageGuard,
+}
+
+/// Public socket of a tunnel, depending on the protocol it forwards.
+enum Listener {
+    Tcp(TcpListener),
+    Udp(UdpListener),
+    Http(HostRoute),
+}
+
+impl Listener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            Listener::Udp(udp) => udp.socket.local_addr(),
+            Listener::Http(route) => Ok(route.addr),
+        }
+    }
+
+    /// Returns the hostname that HTTP requests are routed by, if any.
+    fn host(&self) -> Option<&str> {
+        match self {
+            Listener::Http(route) => Some(&route.host),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the listener was opened for a tunnel of this kind.
+    fn serves(&self, kind: TunnelKind) -> bool {
+        match (self, kind) {
+            (Listener::Tcp(_), TunnelKind::Tcp) | (Listener::Udp(_), TunnelKind::Udp) => true,
+            (Listener::Http(route), TunnelKind::Http(requested)) => route.requested == requested,
+            _ => false,
+        }
+    }
+
+    /// Accept the next connection of a TCP or HTTP tunnel, along with the
+    /// address it came from. Never completes for UDP tunnels.
+    async fn accept(&self) -> io::Result<(Incoming, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Prefixed::new(BytesMut::new(), stream), addr))
+            }
+            // The sender stays registered for as long as the route exists.
+            Listener::Http(route) => match route.connections.lock().await.recv().await {
+                Some(routed) => Ok(routed),
+                None => future::pending().await,
+            },
+            Listener::Udp(_) => future::pending().await,
+        }
+    }
+}
+
+/// Public connection to a tunnel, replaying any bytes the server already read.
+type Incoming = Prefixed<TcpStream>;
+
+/// Connections routed to tunnels by hostname, with the head of their first
+/// request already read.
+type HostRoutes = DashMap<String, mpsc::Sender<(Incoming, SocketAddr)>>;
+
+/// Hostname registered for a tunnel on the HTTP port, until dropped.
+struct HostRoute {
+    /// Hostname as the client requested it, empty for a generated one.
+    requested: String,
+
+    /// Hostname that requests are routed by.
+    host: String,
+
+    /// Address of the HTTP port.
+    addr: SocketAddr,
+
+    /// Connections for the hostname, waiting to be announced to the client.
+    connections: Mutex<mpsc::Receiver<(Incoming, SocketAddr)>>,
+
+    hosts: Arc<HostRoutes>,
+}
+
+impl Drop for HostRoute {
+    fn drop(&mut self) {
+        self.hosts.remove(&self.host);
+    }
+}
+
+/// Public socket of a UDP tunnel, with a task receiving its datagrams until dropped.
+struct UdpListener {
+    socket: Arc<UdpSocket>,
+
+    /// Datagrams received from peers, waiting to be relayed to the client.
+    datagrams: std::sync::Mutex<mpsc::Receiver<(SocketAddr, Vec<u8>)>>,
+
+    receiver: JoinHandle<()>,
+}
+
+impl UdpListener {
+    fn new(socket: UdpSocket) -> Self {
+        let socket = Arc::new(socket);
+        let (tx, rx) = mpsc::channel(DATAGRAM_BACKLOG);
+        let receiver = tokio::spawn(receive_datagrams(Arc::clone(&socket), tx));
+        UdpListener {
+            socket,
+            datagrams: std::sync::Mutex::new(rx),
+            receiver,
+        }
+    }
+
+    fn poll_recv(&self, cx: &mut task::Context<'_>) -> Poll<Option<(SocketAddr, Vec<u8>)>> {
+        let mut datagrams = self.datagrams.lock().unwrap();
+        datagrams.poll_recv(cx)
+    }
+}
+
+impl Drop for UdpListener {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// Receive the datagrams of a UDP tunnel into one buffer, dropping oversized ones.
+async fn receive_datagrams(socket: Arc<UdpSocket>, datagrams: mpsc::Sender<(SocketAddr, Vec<u8>)>) {
+    // One more byte than allowed, to tell oversized datagrams apart.
+    let mut buf = vec![0; MAX_DATAGRAM_LENGTH + 1];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // Replies to peers that are gone can surface as errors of a later receive.
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionRefused
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                debug!(%err, "failed to receive datagram");
+                continue;
+            }
+            Err(err) => {
+                warn!(%err, "failed to receive datagrams, no longer relaying them");
+                return;
+            }
+        };
+        if len > MAX_DATAGRAM_LENGTH {
+            // Counted rather than logged at a higher level, as any peer can send them.
+            debug!(?peer, "dropping oversized datagram");
+            DROPPED_DATAGRAMS.inc();
+            continue;
+        }
+        // While the client falls behind, further datagrams queue in the socket.
+        if datagrams.send((peer, buf[..len].to_vec())).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Forward a connection over a new logical stream of a multiplexed control
+/// connection in the background.
+fn forward_multiplexed(
+    mut mux: Control,
+    multi_tunnel: bool,
+    tunnel: usize,
+    id: Uuid,
+    stream2: Incoming,
+    held: UsageGuard,
+) {
+    info!(tunnel, %id, "new multiplexed connection");
+    tokio::spawn(
+        async move {
+            let _held = held;
+            let result = async {
+                let mut stream = Delimited::new(mux.open_stream().await?);
+                stream
+                    .send(connection_message(multi_tunnel, tunnel, id))
+                    .await?;
+                let stream = byte_counter::CountingStream::new(stream.into_stream());
+                proxy(stream, stream2).await?;
+                anyhow::Ok(())
+            };
+            if let Err(err) = result.await {
+                warn!(%err, "connection exited with error");
+            }
+        }
+        .instrument(info_span!("proxy", %id)),
+    );
+}
+
+/// Message announcing a new connection, in the shape the client's hello asked for.
+fn connection_message(multi_tunnel: bool, tunnel: usize, id: Uuid) -> ServerMessage {
+    if multi_tunnel {
+        ServerMessage::TunnelConnection(tunnel, id)
+    } else {
+        ServerMessage::Connection(id)
+    }
 }
 
 impl Default for Server {
     fn default() -> Self {
-        Server::new(None)
+        Server::new(1024..=65535, None)
+    }
+}
+
+/// Complete the TLS handshake of a control connection within a time limit.
+///
+/// Returns the stream along with the subject of the client certificate, which
+/// is only present if it was verified against the client CA.
+async fn accept_tls(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    limit: Duration,
+    _guard: HandshakeGuard,
+) -> Option<(Box<dyn StreamTrait>, Option<String>)> {
+    let stream = match timeout(limit, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+            warn!(%err, "failed to accept tls connection");
+            TLS_HANDSHAKE_FAILURES.with_label_values(&["error"]).inc();
+            return None;
+        }
+        Err(_) => {
+            warn!("timed out waiting for tls handshake");
+            TLS_HANDSHAKE_FAILURES.with_label_values(&["timeout"]).inc();
+            return None;
+        }
+    };
+    let certs = stream.get_ref().1.peer_certificates();
+    let subject = certs.and_then(|certs| certificate_subject(certs.first()?));
+    Some((Box::new(stream), subject))
+}
+
+/// Complete the Noise handshake of a control connection within a time limit.
+async fn accept_noise(
+    noise: &NoiseServer,
+    stream: TcpStream,
+    limit: Duration,
+    _guard: HandshakeGuard,
+) -> Option<Box<dyn StreamTrait>> {
+    match timeout(limit, noise.accept(stream)).await {
+        Ok(Ok((stream, client_key))) => {
+            Span::current().record("client_key", field::display(client_key));
+            Some(Box::new(stream))
+        }
+        Ok(Err(err)) => {
+            warn!("failed to accept noise connection: {err:#}");
+            NOISE_HANDSHAKE_FAILURES.with_label_values(&["error"]).inc();
+            None
+        }
+        Err(_) => {
+            warn!("timed out waiting for noise handshake");
+            NOISE_HANDSHAKE_FAILURES
+                .with_label_values(&["timeout"])
+                .inc();
+            None
+        }
+    }
+}
+
+/// Encryption applied to control connections before the bore protocol.
+enum Encryption {
+    Tls(TlsAcceptor),
+    Noise(NoiseServer),
+}
+
+impl Encryption {
+    fn name(&self) -> &'static str {
+        match self {
+            Encryption::Tls(_) => "tls",
+            Encryption::Noise(_) => "noise",
+        }
+    }
+
+    fn handshakes(&self) -> &'static IntGauge {
+        match self {
+            Encryption::Tls(_) => &TLS_HANDSHAKES,
+            Encryption::Noise(_) => &NOISE_HANDSHAKES,
+        }
+    }
+
+    fn failures(&self) -> &'static IntCounterVec {
+        match self {
+            Encryption::Tls(_) => &TLS_HANDSHAKE_FAILURES,
+            Encryption::Noise(_) => &NOISE_HANDSHAKE_FAILURES,
+        }
+    }
+}
+
+/// An encryption handshake in progress, counted until dropped.
+struct HandshakeGuard(Arc<AtomicUsize>, &'static IntGauge);
+
+impl HandshakeGuard {
+    /// Count a new handshake, unless the limit is already reached.
+    fn acquire(count: &Arc<AtomicUsize>, gauge: &'static IntGauge, limit: usize) -> Option<Self> {
+        count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < limit).then_some(n + 1)
+            })
+            .ok()?;
+        gauge.inc();
+        Some(Self(Arc::clone(count), gauge))
+    }
+}
+
+impl Drop for HandshakeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+        self.1.dec();
     }
 }
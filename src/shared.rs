@@ -1,48 +1,361 @@
 //! Shared data structures, utilities, and protocol definitions.
 
+use std::pin::Pin;
+use std::task::{self, Poll};
 use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use bytes::BytesMut;
 use futures_util::{SinkExt, StreamExt};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tokio::io::{self, copy_bidirectional, AsyncRead, AsyncWrite};
+use sha2::{Digest, Sha256};
+use tokio::io::{self, copy_bidirectional, AsyncRead, AsyncWrite, ReadBuf};
 
 use tokio::time::timeout;
 use tokio_util::codec::{AnyDelimiterCodec, Framed, FramedParts};
 use tracing::trace;
 use uuid::Uuid;
 
+use crate::access::AccessList;
+use crate::auth::MessageAuth;
+
 /// TCP port used for control connections with the server.
 pub const CONTROL_PORT: u16 = 7835;
 
-/// Maxmium byte length for a JSON frame in the stream.
-pub const MAX_FRAME_LENGTH: usize = 256;
+/// Maxmium byte length for a JSON frame in the stream, enough for a hex
+/// encoded datagram along with its message and tag.
+pub const MAX_FRAME_LENGTH: usize = 2 * MAX_DATAGRAM_LENGTH + 1024;
+
+/// Maximum number of tunnels a client may request over one control connection.
+pub const MAX_TUNNELS: usize = 32;
 
 /// Timeout for network connections and initial protocol messages.
 pub const NETWORK_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// Default interval between heartbeats sent on control connections.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(2000);
+
+/// Default number of heartbeat intervals without a message from the peer,
+/// after which a control connection is considered dead.
+pub const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/// Default time after which the server discards connections the client did not accept.
+pub const STALE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum length of a forwarded UDP datagram, enough for DNS responses
+/// with EDNS.
+pub const MAX_DATAGRAM_LENGTH: usize = 8192;
+
+/// Maximum number of UDP flows tracked at once for each control connection.
+pub const MAX_UDP_FLOWS: usize = 256;
+
+/// Default time without datagrams in either direction after which a UDP flow is forgotten.
+pub const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default time the server holds the tunnels of a disconnected client for it to resume.
+pub const RESUME_GRACE: Duration = Duration::from_secs(30);
+
+/// Default time allowed for a client to complete the TLS handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default maximum number of TLS handshakes in progress at once.
+pub const MAX_HANDSHAKES: usize = 128;
+
+/// Version of the control protocol spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest control protocol version this build is able to speak.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// An optional protocol feature, negotiated at the start of each connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Capability {
+    /// Proxied streams may be multiplexed over the control connection.
+    Multiplex,
+
+    /// Several tunnels may be requested over one control connection.
+    MultiTunnel,
+
+    /// Client and server both prove knowledge of the secret, and authenticate
+    /// later messages with a derived key.
+    MutualAuth,
+
+    /// Clients may restrict who connects to each tunnel with access lists.
+    AccessLists,
+
+    /// After `HelloTunnels`, clients may add and close tunnels on the control
+    /// connection, unless it is multiplexed.
+    TunnelControl,
+
+    /// The server pings the client on every heartbeat instead, and closes the
+    /// connection once the client stops answering.
+    Liveness,
+
+    /// Clients may resume a lost session with its token, and the server
+    /// holds the tunnels of disconnected clients for a grace period.
+    Resume,
+
+    /// Tunnels may forward UDP datagrams, relayed over the control connection
+    /// unless it is multiplexed.
+    Udp,
+
+    /// Tunnels may be routed HTTP requests by hostname on a port the server
+    /// shares between clients, instead of listening on a port of their own.
+    Http,
+
+    /// Any capability of a newer peer that this build does not know about.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Protocol versions and capabilities supported by one side of a connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolInfo {
+    /// Newest protocol version supported.
+    pub version: u32,
+
+    /// Oldest protocol version supported.
+    pub min_version: u32,
+
+    /// Optional features supported.
+    pub capabilities: Vec<Capability>,
+}
+
+impl ProtocolInfo {
+    /// Protocol information for this build, offering the given capabilities.
+    pub fn new(capabilities: Vec<Capability>) -> Self {
+        ProtocolInfo {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// Returns whether a capability is supported.
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Combine with the peer's protocol information into what both sides support.
+    ///
+    /// ```
+    /// use bore_cli::shared::{Capability, ProtocolInfo};
+    ///
+    /// let ours = ProtocolInfo::new(vec![Capability::Multiplex]);
+    /// let theirs = ProtocolInfo::new(vec![]);
+    /// assert!(!ours.negotiate(&theirs).unwrap().supports(Capability::Multiplex));
+    ///
+    /// let future = ProtocolInfo { version: 9, min_version: 8, capabilities: vec![] };
+    /// assert!(ours.negotiate(&future).is_err());
+    /// ```
+    pub fn negotiate(&self, peer: &ProtocolInfo) -> Result<ProtocolInfo> {
+        let version = self.version.min(peer.version);
+        let min_version = self.min_version.max(peer.min_version);
+        if version < min_version {
+            bail!(
+                "incompatible protocol versions: {}..={} and {}..={}",
+                self.min_version,
+                self.version,
+                peer.min_version,
+                peer.version,
+            );
+        }
+        let capabilities = self
+            .capabilities
+            .iter()
+            .copied()
+            .filter(|c| *c != Capability::Unknown && peer.supports(*c))
+            .collect();
+        Ok(ProtocolInfo {
+            version,
+            min_version,
+            capabilities,
+        })
+    }
+
+    /// As the server, negotiate the protocol with the client's initial message.
+    pub async fn server_negotiate<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut Delimited<T>,
+    ) -> Result<ProtocolInfo> {
+        let negotiated = match stream.recv_timeout().await? {
+            Some(ClientMessage::Version(peer)) => self.negotiate(&peer)?,
+            _ => bail!("client did not announce a protocol version, please upgrade"),
+        };
+        stream
+            .send(ServerMessage::Version(negotiated.clone()))
+            .await?;
+        Ok(negotiated)
+    }
+
+    /// As the client, announce this protocol information and read the server's choice.
+    pub async fn client_negotiate<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut Delimited<T>,
+    ) -> Result<ProtocolInfo> {
+        stream.send(ClientMessage::Version(self.clone())).await?;
+        match stream.recv_timeout().await? {
+            // Re-check what the server picked, in case it is not compatible with us.
+            Some(ServerMessage::Version(negotiated)) => self.negotiate(&negotiated),
+            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+            Some(_) => bail!("server did not negotiate a protocol version"),
+            None => bail!("unexpected EOF, server may not support protocol negotiation"),
+        }
+    }
+}
+
 /// A message from the client on the control connection.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
+    /// Protocol versions and capabilities of the client, sent as the first message.
+    Version(ProtocolInfo),
+
     /// Response to an authentication challenge from the server.
     Authenticate(String),
 
-    /// Initial client message specifying a port to forward.
-    Hello(),
+    /// Response to an authentication challenge with a nonce of the client's,
+    /// asking the server to prove its knowledge of the secret in return.
+    AuthenticateMutual {
+        /// ID of the credential that the secret belongs to, if not the shared secret.
+        key_id: Option<String>,
+
+        /// Random nonce chosen by the client.
+        nonce: Uuid,
+
+        /// Proof of the secret, over both nonces and the key ID.
+        tag: String,
+    },
+
+    /// Initial client message specifying a port to forward, or 0 for any port.
+    Hello(u16),
+
+    /// Like `Hello`, but carries all proxied streams over this control connection.
+    ///
+    /// After the server's hello, both sides run a multiplexed session on the
+    /// stream, and the server opens one logical stream per forwarded connection.
+    HelloMux(u16),
+
+    /// Initial client message asking for several tunnels, each on a port or 0 for any port.
+    ///
+    /// Only sent when the `MultiTunnel` capability was negotiated.
+    HelloTunnels {
+        /// Requested public ports, one for each tunnel.
+        ports: Vec<u16>,
+
+        /// Whether to multiplex proxied streams, as with `HelloMux`.
+        multiplex: bool,
+
+        /// Networks that may connect to each tunnel, within what the server
+        /// allows, or empty for no restrictions.
+        ///
+        /// Only sent when the `AccessLists` capability was negotiated.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        access: Vec<AccessList>,
+
+        /// Session token of a previous connection, whose tunnels to take over
+        /// if the server still holds them.
+        ///
+        /// Only sent when the `Resume` capability was negotiated.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume: Option<Uuid>,
+
+        /// Whether each tunnel forwards UDP datagrams instead of TCP
+        /// connections, or empty for all TCP.
+        ///
+        /// Only sent when the `Udp` capability was negotiated.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        udp: Vec<bool>,
+
+        /// Hostname that each tunnel is routed HTTP requests by, empty to
+        /// have the server generate one, or `None` for a tunnel with a port
+        /// of its own. Empty if no tunnel is routed by hostname.
+        ///
+        /// Only sent when the `Http` capability was negotiated.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        http: Vec<Option<String>>,
+    },
 
     /// Accepts an incoming TCP connection, using this stream as a proxy.
-    Accept(Uuid),
+    ///
+    /// Carries the connection ID and the session token from the server's hello.
+    Accept(Uuid, Uuid),
+
+    /// Asks the server to reply with a `Pong` carrying the same value.
+    Ping(u64),
+
+    /// Reply to a server's `Ping`.
+    Pong(u64),
+
+    /// Asks the server to open another tunnel, answered by `TunnelAdded` or `Refused`.
+    ///
+    /// Only sent when the `TunnelControl` capability was negotiated.
+    AddTunnel {
+        /// Requested public port, or 0 for any port.
+        port: u16,
+
+        /// Networks that may connect to the tunnel, as in `HelloTunnels`.
+        #[serde(default, skip_serializing_if = "AccessList::is_empty")]
+        access: AccessList,
+
+        /// Whether the tunnel forwards UDP datagrams, as in `HelloTunnels`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        udp: bool,
+
+        /// Hostname that the tunnel is routed HTTP requests by, as in `HelloTunnels`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        http: Option<String>,
+    },
+
+    /// Asks the server to close the tunnel at an index, answered by
+    /// `TunnelClosed` or `Refused`.
+    ///
+    /// Only sent when the `TunnelControl` capability was negotiated.
+    CloseTunnel(usize),
+
+    /// Reply of the local service to a datagram of a UDP flow.
+    Datagram {
+        /// Flow that the reply belongs to, as named by the server.
+        flow: u32,
+
+        /// Hex encoded payload.
+        data: String,
+    },
 }
 
 /// A message from the server on the control connection.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
+    /// Negotiated protocol version and capabilities, in response to the client's.
+    Version(ProtocolInfo),
+
     /// Authentication challenge, sent as the first message, if enabled.
     Challenge(Uuid),
 
-    /// Response to a client's initial message, with actual public port.
-    Hello(u16),
+    /// Proof of the secret in response to `AuthenticateMutual`, over both nonces.
+    Proof(String),
+
+    /// Response to a client's initial message, with actual public port and session token.
+    Hello(u16, Uuid),
+
+    /// Response to `HelloTunnels`, with the actual public ports and session token.
+    HelloTunnels {
+        /// Public ports, in the order the tunnels were requested.
+        ports: Vec<u16>,
+
+        /// Session token, as in `Hello`.
+        session: Uuid,
+
+        /// Hostname of each tunnel routed by hostname, whose port is the
+        /// server's HTTP port, or empty if there are none.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        hosts: Vec<Option<String>>,
+
+        /// Time without datagrams after which the server forgets a UDP flow,
+        /// in milliseconds, if UDP tunnels were negotiated.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        udp_idle_timeout_ms: Option<u64>,
+    },
 
     /// No-op used to test if the client is still reachable.
     Heartbeat,
@@ -50,24 +363,110 @@ pub enum ServerMessage {
     /// Asks the client to accept a forwarded TCP connection.
     Connection(Uuid),
 
+    /// Like `Connection`, for the tunnel at an index of a `HelloTunnels` request.
+    TunnelConnection(usize, Uuid),
+
+    /// Heartbeat asking the client to reply with a `Pong` carrying the same value.
+    ///
+    /// Only sent when the `Liveness` capability was negotiated.
+    Ping(u64),
+
+    /// Reply to a client's `Ping`.
+    Pong(u64),
+
+    /// Response to `AddTunnel`, with the index of the new tunnel and its public port.
+    TunnelAdded {
+        /// Index of the tunnel, following those of the hello and earlier additions.
+        tunnel: usize,
+
+        /// Public port of the tunnel.
+        port: u16,
+
+        /// Hostname of the tunnel, if it is routed by hostname.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        host: Option<String>,
+    },
+
+    /// Response to `CloseTunnel`, after the tunnel at this index stopped listening.
+    TunnelClosed(usize),
+
+    /// Datagram received by a UDP tunnel, for the client to relay to its local service.
+    Datagram {
+        /// Index of the tunnel that received the datagram.
+        tunnel: usize,
+
+        /// Flow of the sending peer, which replies are addressed to.
+        flow: u32,
+
+        /// Hex encoded payload.
+        data: String,
+    },
+
+    /// Indicates that a client's request was refused, without closing the connection.
+    Refused(String),
+
     /// Indicates a server error that terminates the connection.
     Error(String),
 }
 
 /// Transport stream with JSON frames delimited by null characters.
-pub struct Delimited<U>(Framed<U, AnyDelimiterCodec>);
+///
+/// Once [`authenticate`](Self::authenticate) is called, every frame is
+/// prefixed with a tag authenticating the message. Before that, frames are
+/// hashed into a [`transcript`](Self::transcript) that handshakes bind to.
+pub struct Delimited<U> {
+    framed: Framed<U, AnyDelimiterCodec>,
+    auth: Option<MessageAuth>,
+    transcript: Sha256,
+}
 
 impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     /// Construct a new delimited stream.
     pub fn new(stream: U) -> Self {
         let codec = AnyDelimiterCodec::new_with_max_length(vec![0], vec![0], MAX_FRAME_LENGTH);
-        Self(Framed::new(stream, codec))
+        Self {
+            framed: Framed::new(stream, codec),
+            auth: None,
+            transcript: Sha256::new(),
+        }
+    }
+
+    /// Returns a digest of the frames sent and received so far, in order,
+    /// while messages were not yet authenticated.
+    pub fn transcript(&self) -> [u8; 32] {
+        self.transcript.clone().finalize().into()
+    }
+
+    fn record(&mut self, frame: &[u8]) {
+        if self.auth.is_none() {
+            self.transcript.update((frame.len() as u64).to_be_bytes());
+            self.transcript.update(frame);
+        }
+    }
+
+    /// Authenticate all following messages in both directions.
+    pub fn authenticate(&mut self, auth: MessageAuth) {
+        self.auth = Some(auth);
+    }
+
+    /// Returns whether messages are authenticated.
+    pub fn is_authenticated(&self) -> bool {
+        self.auth.is_some()
     }
 
     /// Read the next null-delimited JSON instruction from a stream.
     pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
-        if let Some(next_message) = self.0.next().await {
-            let byte_message = next_message.context("frame error, invalid byte length")?;
+        if let Some(next_message) = self.framed.next().await {
+            let mut byte_message = next_message.context("frame error, invalid byte length")?;
+            if let Some(auth) = &mut self.auth {
+                ensure!(
+                    byte_message.len() >= MessageAuth::TAG_LEN,
+                    "message failed authentication"
+                );
+                let tag = byte_message.split_to(MessageAuth::TAG_LEN);
+                auth.open(&byte_message, &tag)?;
+            }
+            self.record(&byte_message);
             trace!("got json message: {:?}", byte_message);
             let serialized_obj =
                 serde_json::from_slice(&byte_message).context("unable to parse message")?;
@@ -90,13 +489,82 @@ impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     /// Send a null-terminated JSON instruction on a stream.
     pub async fn send<T: Serialize>(&mut self, msg: T) -> Result<()> {
         trace!("sending json message");
-        self.0.send(serde_json::to_string(&msg)?).await?;
+        let mut message = serde_json::to_string(&msg)?;
+        self.record(message.as_bytes());
+        if let Some(auth) = &mut self.auth {
+            message.insert_str(0, &auth.seal(message.as_bytes()));
+        }
+        self.framed.send(message).await?;
         Ok(())
     }
 
     /// Consume this object, returning current buffers and the inner transport.
     pub fn into_parts(self) -> FramedParts<U, AnyDelimiterCodec> {
-        self.0.into_parts()
+        self.framed.into_parts()
+    }
+
+    /// Consume this object, returning a raw stream that replays already buffered bytes.
+    pub fn into_stream(self) -> Prefixed<U> {
+        let parts = self.framed.into_parts();
+        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
+        Prefixed {
+            prefix: parts.read_buf,
+            inner: parts.io,
+        }
+    }
+}
+
+/// Raw stream that yields leftover bytes from a framed transport before its own.
+pub struct Prefixed<U> {
+    prefix: BytesMut,
+    inner: U,
+}
+
+impl<U> Prefixed<U> {
+    /// Create a stream that yields bytes already read from `inner` first.
+    pub fn new(prefix: BytesMut, inner: U) -> Self {
+        Prefixed { prefix, inner }
+    }
+}
+
+impl<U: AsyncRead + Unpin> AsyncRead for Prefixed<U> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<U: AsyncWrite + Unpin> AsyncWrite for Prefixed<U> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Configuration for multiplexed sessions over a control connection.
+pub fn mux_config() -> tokio_yamux::Config {
+    tokio_yamux::Config {
+        keepalive_interval: Duration::from_secs(2),
+        ..Default::default()
     }
 }
 
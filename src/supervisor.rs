@@ -0,0 +1,159 @@
+//! Supervised clients, reconnecting with backoff and resuming their sessions.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::auth::Authenticator;
+use crate::client::{Client, Liveness, Transport, Tunnel};
+
+/// Default delay before reconnecting, doubled after each failed attempt.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Default upper bound on the delay before reconnecting.
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// How a supervised client waits between attempts to reach the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reconnect {
+    /// Delay before reconnecting after the connection was lost, doubled after
+    /// each failed attempt.
+    pub delay: Duration,
+
+    /// Upper bound on the delay before reconnecting.
+    pub max_delay: Duration,
+
+    /// Number of consecutive failed attempts after which the client gives
+    /// up, or `None` to retry forever. A connection that is lost within a
+    /// heartbeat interval counts as a failed attempt.
+    pub max_retries: Option<u32>,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            delay: RECONNECT_DELAY,
+            max_delay: MAX_RECONNECT_DELAY,
+            max_retries: None,
+        }
+    }
+}
+
+impl Reconnect {
+    /// Returns the delay before reconnecting after the given number of
+    /// consecutive failed attempts.
+    ///
+    /// The delay is randomly shortened by up to half, so that clients cut off
+    /// together do not reconnect together.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use bore_cli::supervisor::Reconnect;
+    ///
+    /// let reconnect = Reconnect::default();
+    /// let delay = reconnect.delay_after(2);
+    /// assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+    /// assert!(reconnect.delay_after(30) <= reconnect.max_delay);
+    /// ```
+    pub fn delay_after(&self, failures: u32) -> Duration {
+        let factor = 1u32.checked_shl(failures).unwrap_or(u32::MAX);
+        let delay = self.delay.saturating_mul(factor).min(self.max_delay);
+        delay.mul_f64(0.5 + fastrand::f64() / 2.0)
+    }
+}
+
+/// A client that reconnects whenever its connection to the server is lost.
+///
+/// After reconnecting, the client resumes its previous session, keeping its
+/// remote ports if the server still holds them.
+pub struct Supervisor {
+    /// Local addresses to expose, along with the remote ports to request.
+    pub tunnels: Vec<Tunnel>,
+
+    /// Address of the server, which may include a control port.
+    pub to: String,
+
+    /// Optional secret used to authenticate to the server.
+    pub auth: Option<Authenticator>,
+
+    /// Optional encrypted transport.
+    pub transport: Option<Transport>,
+
+    /// Whether proxied streams are multiplexed over the control connection.
+    pub multiplex: bool,
+
+    /// How to detect that the server is no longer reachable.
+    pub liveness: Liveness,
+
+    /// How to wait between attempts to reach the server.
+    pub reconnect: Reconnect,
+}
+
+impl Supervisor {
+    /// Create a supervisor for tunnels to a server, with default settings.
+    pub fn new(tunnels: Vec<Tunnel>, to: &str) -> Self {
+        Supervisor {
+            tunnels,
+            to: to.to_string(),
+            auth: None,
+            transport: None,
+            multiplex: false,
+            liveness: Liveness::default(),
+            reconnect: Reconnect::default(),
+        }
+    }
+
+    /// Run the client, reconnecting until the retries are exhausted.
+    pub async fn run(self) -> Result<()> {
+        let mut failures = 0;
+        let mut session: Option<Uuid> = None;
+        loop {
+            let client = Client::connect(
+                self.tunnels.clone(),
+                &self.to,
+                self.auth.clone(),
+                self.transport.clone(),
+                self.multiplex,
+                session,
+            );
+            let failure = match client.await {
+                Ok(client) => {
+                    session = Some(client.session());
+                    let connected = Instant::now();
+                    let result = client.with_liveness(self.liveness).listen().await;
+                    // A session lost within a heartbeat counts as a failed
+                    // attempt, so a server that accepts and then drops
+                    // connections is not reconnected to without backoff.
+                    if connected.elapsed() >= self.liveness.interval {
+                        failures = 0;
+                        match result {
+                            Ok(()) => info!("client exited"),
+                            Err(err) => warn!("client exited with error: {err:#}"),
+                        }
+                        None
+                    } else {
+                        Some(result.err().unwrap_or_else(|| anyhow!("connection closed")))
+                    }
+                }
+                Err(err) => Some(err),
+            };
+            if let Some(err) = failure {
+                failures += 1;
+                if self
+                    .reconnect
+                    .max_retries
+                    .is_some_and(|max_retries| failures > max_retries)
+                {
+                    return Err(err.context(format!("giving up after {failures} attempts")));
+                }
+                warn!(failures, "failed to connect: {err:#}");
+            }
+            let delay = self.reconnect.delay_after(failures);
+            info!(?delay, "reconnecting");
+            sleep(delay).await;
+        }
+    }
+}
@@ -0,0 +1,445 @@
+//! Loading of certificates and private keys for TLS connections.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use p12_keystore::KeyStore;
+use rustls_pemfile::Item;
+use sha2::{Digest, Sha256};
+use tokio::time;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, ServerName};
+use tokio_rustls::TlsConnector;
+use tracing::{error, info};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// How often the server checks its certificate files for changes.
+pub const CERT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Load all certificates from a PEM file.
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path)
+        .with_context(|| format!("could not open certificate file {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("invalid certificate file {}", path.display()))?;
+    ensure!(
+        !certs.is_empty(),
+        "no PEM certificates found in {}",
+        path.display()
+    );
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Load the first private key from a PEM file.
+///
+/// Keys may be in PKCS#8 (`PRIVATE KEY`), RSA PKCS#1 (`RSA PRIVATE KEY`) or
+/// EC SEC1 (`EC PRIVATE KEY`) format.
+pub fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    let file =
+        File::open(path).with_context(|| format!("could not open key file {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("invalid key file {}", path.display()))?;
+        match item {
+            Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => continue,
+            None => bail!(
+                "no PKCS#8, PKCS#1 or SEC1 private key found in {}",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Load a certificate chain and its private key from a PKCS#12 bundle.
+pub fn load_pkcs12(path: &Path, password: &str) -> Result<(Vec<Certificate>, PrivateKey)> {
+    let data = fs::read(path)
+        .with_context(|| format!("could not read PKCS#12 file {}", path.display()))?;
+    let keystore = KeyStore::from_pkcs12(&data, password)
+        .with_context(|| format!("invalid PKCS#12 file {}, or wrong password", path.display()))?;
+    let (_, chain) = keystore
+        .private_key_chain()
+        .with_context(|| format!("no private key found in {}", path.display()))?;
+    let certs = chain
+        .chain()
+        .iter()
+        .map(|cert| Certificate(cert.as_der().to_vec()))
+        .collect();
+    Ok((certs, PrivateKey(chain.key().to_vec())))
+}
+
+/// Load a certificate chain and private key, from PEM files or a PKCS#12 bundle.
+///
+/// Without a key file, the certificate file is read as a PKCS#12 bundle,
+/// protected by the password or an empty one.
+pub fn load_identity(
+    cert: &Path,
+    key: Option<&Path>,
+    password: Option<&str>,
+) -> Result<(Vec<Certificate>, PrivateKey)> {
+    match key {
+        Some(key) => Ok((load_certs(cert)?, load_private_key(key)?)),
+        None => load_pkcs12(cert, password.unwrap_or_default())
+            .context("without a key file, the certificate must be a PKCS#12 bundle"),
+    }
+}
+
+/// Files that a server certificate and its private key are loaded from.
+#[derive(Clone, Debug)]
+pub struct IdentityFiles {
+    /// PEM certificate chain, or a PKCS#12 bundle without a key file.
+    pub cert: PathBuf,
+
+    /// PEM private key of the certificate.
+    pub key: Option<PathBuf>,
+
+    /// Password of the PKCS#12 bundle.
+    pub password: Option<String>,
+}
+
+impl IdentityFiles {
+    /// Load the certificate and key, ready to be served.
+    pub fn load(&self) -> Result<CertifiedKey> {
+        let (certs, key) =
+            load_identity(&self.cert, self.key.as_deref(), self.password.as_deref())?;
+        let key = sign::any_supported_type(&key)
+            .map_err(|_| anyhow!("unsupported private key type for {}", self.cert.display()))?;
+        Ok(CertifiedKey::new(certs, key))
+    }
+
+    /// Modification times and sizes of the files, which change when they are rewritten.
+    fn versions(&self) -> Vec<Option<(SystemTime, u64)>> {
+        [Some(&self.cert), self.key.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| {
+                let metadata = fs::metadata(path).ok()?;
+                Some((metadata.modified().ok()?, metadata.len()))
+            })
+            .collect()
+    }
+}
+
+/// Certificate resolver whose certificate can be replaced while the server runs.
+///
+/// Handshakes in progress keep the certificate they started with, and
+/// established TLS sessions are not affected by a swap.
+pub struct CertResolver(RwLock<Arc<CertifiedKey>>);
+
+impl CertResolver {
+    /// Create a resolver serving the given certificate.
+    pub fn new(key: CertifiedKey) -> Self {
+        Self(RwLock::new(Arc::new(key)))
+    }
+
+    /// Returns the certificate currently being served.
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Serve a new certificate to all following handshakes.
+    pub fn set(&self, key: CertifiedKey) {
+        *self.0.write().unwrap() = Arc::new(key);
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+/// Reload the certificate into the resolver whenever its files change.
+///
+/// Files that fail to load are logged and the previous certificate stays in
+/// use. Stops once the resolver has been dropped.
+pub async fn watch_identity(files: IdentityFiles, resolver: Weak<CertResolver>, every: Duration) {
+    let mut versions = files.versions();
+    loop {
+        time::sleep(every).await;
+        let Some(resolver) = resolver.upgrade() else {
+            return;
+        };
+        let current = files.versions();
+        if current == versions {
+            continue;
+        }
+        versions = current;
+        match files.load() {
+            Ok(key) => {
+                let sha256 = Pin::of(&key.cert[0].0);
+                resolver.set(key);
+                info!(cert = %files.cert.display(), %sha256, "reloaded certificate");
+            }
+            Err(err) => error!("failed to reload certificate: {err:#}"),
+        }
+    }
+}
+
+/// SHA-256 hash of a certificate or of its public key, used to pin a server.
+///
+/// Pins are written in hex, optionally separated by colons as printed by
+/// `openssl x509 -fingerprint -sha256`.
+///
+/// ```
+/// use bore_cli::tls::Pin;
+///
+/// let pin = Pin::of(b"certificate");
+/// assert_eq!(pin.to_string().parse::<Pin>().unwrap(), pin);
+/// assert!("AB:CD".parse::<Pin>().is_err());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pin([u8; 32]);
+
+impl Pin {
+    /// Hash of DER encoded data.
+    pub fn of(der: &[u8]) -> Self {
+        Self(Sha256::digest(der).into())
+    }
+
+    /// Returns whether the certificate or its subject public key info has this hash.
+    fn matches(&self, cert: &Certificate) -> bool {
+        if *self == Pin::of(&cert.0) {
+            return true;
+        }
+        X509Certificate::from_der(&cert.0)
+            .is_ok_and(|(_, cert)| *self == Pin::of(cert.public_key().raw))
+    }
+}
+
+impl FromStr for Pin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hash = hex::decode(s.replace(':', "")).context("pin is not a hex string")?;
+        let hash = hash
+            .try_into()
+            .map_err(|_| anyhow!("pin must be a 32 byte SHA-256 hash"))?;
+        Ok(Self(hash))
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// Signature algorithms accepted in certificate chains, the same as rustls.
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Trust anchor owning its parts.
+struct Anchor {
+    subject: Vec<u8>,
+    spki: Vec<u8>,
+    name_constraints: Option<Vec<u8>>,
+}
+
+impl Anchor {
+    fn borrow(&self) -> webpki::TrustAnchor<'_> {
+        webpki::TrustAnchor {
+            subject: &self.subject,
+            spki: &self.spki,
+            name_constraints: self.name_constraints.as_deref(),
+        }
+    }
+}
+
+/// Verifier of server certificates, by CA or by pinned hashes.
+///
+/// Unlike the default verifier of rustls, this accepts servers reached by IP
+/// address, if their certificate has a matching IP address SAN.
+pub struct ServerVerifier {
+    anchors: Vec<Anchor>,
+    pins: Vec<Pin>,
+}
+
+impl ServerVerifier {
+    /// Trust the Mozilla root certificates.
+    pub fn webpki_roots() -> Self {
+        let anchors = webpki_roots::TLS_SERVER_ROOTS
+            .iter()
+            .map(|anchor| Anchor {
+                subject: anchor.subject.to_vec(),
+                spki: anchor.spki.to_vec(),
+                name_constraints: anchor.name_constraints.map(<[u8]>::to_vec),
+            })
+            .collect();
+        Self {
+            anchors,
+            pins: Vec::new(),
+        }
+    }
+
+    /// Trust servers with certificates issued by the given CAs.
+    pub fn with_ca_certs(certs: &[Certificate]) -> Result<Self> {
+        let mut anchors = Vec::new();
+        for cert in certs {
+            let anchor = webpki::TrustAnchor::try_from_cert_der(&cert.0)
+                .map_err(|err| anyhow!("invalid CA certificate: {err}"))?;
+            anchors.push(Anchor {
+                subject: anchor.subject.to_vec(),
+                spki: anchor.spki.to_vec(),
+                name_constraints: anchor.name_constraints.map(<[u8]>::to_vec),
+            });
+        }
+        Ok(Self {
+            anchors,
+            pins: Vec::new(),
+        })
+    }
+
+    /// Trust only servers whose certificate or public key matches a pin.
+    ///
+    /// The certificate is not checked against any CA, nor for the server name,
+    /// so self-signed certificates are accepted.
+    pub fn pinned(pins: Vec<Pin>) -> Self {
+        Self {
+            anchors: Vec::new(),
+            pins,
+        }
+    }
+}
+
+/// Map a certificate error like rustls does.
+fn pki_error(err: webpki::Error) -> rustls::Error {
+    rustls::Error::InvalidCertificateData(format!("invalid peer certificate: {err}"))
+}
+
+/// Returns the IP addresses in the subject alternative names of a certificate.
+fn ip_sans(cert: &Certificate) -> Vec<IpAddr> {
+    let Ok((_, cert)) = X509Certificate::from_der(&cert.0) else {
+        return Vec::new();
+    };
+    let Ok(Some(sans)) = cert.subject_alternative_name() else {
+        return Vec::new();
+    };
+    sans.value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::IPAddress(&[a, b, c, d]) => Some(IpAddr::from([a, b, c, d])),
+            GeneralName::IPAddress(octets) => <[u8; 16]>::try_from(*octets).ok().map(IpAddr::from),
+            _ => None,
+        })
+        .collect()
+}
+
+impl ServerCertVerifier for ServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if !self.pins.is_empty() {
+            if self.pins.iter().any(|pin| pin.matches(end_entity)) {
+                return Ok(ServerCertVerified::assertion());
+            }
+            return Err(rustls::Error::InvalidCertificateData(
+                "certificate does not match any pinned hash".into(),
+            ));
+        }
+
+        let cert = webpki::EndEntityCert::try_from(end_entity.0.as_ref())
+            .map_err(|_| rustls::Error::InvalidCertificateEncoding)?;
+        let anchors: Vec<_> = self.anchors.iter().map(Anchor::borrow).collect();
+        let chain: Vec<&[u8]> = intermediates.iter().map(|cert| cert.0.as_ref()).collect();
+        let now = webpki::Time::try_from(now).map_err(|_| rustls::Error::FailedToGetCurrentTime)?;
+        cert.verify_is_valid_tls_server_cert(
+            SIGNATURE_ALGORITHMS,
+            &webpki::TlsServerTrustAnchors(&anchors),
+            &chain,
+            now,
+        )
+        .map_err(pki_error)?;
+
+        match server_name {
+            ServerName::DnsName(name) => {
+                let name = webpki::DnsNameRef::try_from_ascii_str(name.as_ref())
+                    .map_err(|_| rustls::Error::UnsupportedNameType)?;
+                cert.verify_is_valid_for_dns_name(name).map_err(pki_error)?;
+            }
+            ServerName::IpAddress(ip) => {
+                if !ip_sans(end_entity).contains(ip) {
+                    return Err(pki_error(webpki::Error::CertNotValidForName));
+                }
+            }
+            _ => return Err(rustls::Error::UnsupportedNameType),
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// TLS settings of a client, with the name to verify the server against.
+#[derive(Clone)]
+pub struct ClientTls {
+    connector: TlsConnector,
+    server_name: Option<ServerName>,
+}
+
+impl ClientTls {
+    /// Use a connector, verifying servers against the host they are reached at.
+    pub fn new(connector: TlsConnector) -> Self {
+        Self {
+            connector,
+            server_name: None,
+        }
+    }
+
+    /// Send and verify a different DNS name or IP address than the server's host.
+    pub fn with_server_name(mut self, name: &str) -> Result<Self> {
+        let name =
+            ServerName::try_from(name).map_err(|_| anyhow!("invalid tls server name {name:?}"))?;
+        self.server_name = Some(name);
+        Ok(self)
+    }
+
+    /// Returns the connector.
+    pub fn connector(&self) -> &TlsConnector {
+        &self.connector
+    }
+
+    /// Returns the name to verify a server reached at `host` against.
+    pub fn server_name(&self, host: &str) -> Result<ServerName> {
+        match &self.server_name {
+            Some(name) => Ok(name.clone()),
+            None => ServerName::try_from(host)
+                .map_err(|_| anyhow!("{host:?} is not a valid tls server name")),
+        }
+    }
+}
+
+impl From<TlsConnector> for ClientTls {
+    fn from(connector: TlsConnector) -> Self {
+        Self::new(connector)
+    }
+}
@@ -0,0 +1,160 @@
+//! UDP tunnels, relaying datagrams over the control connection.
+//!
+//! The server names each peer address of a UDP tunnel by a flow ID, and the
+//! client relays the datagrams of each flow from its own local socket, so
+//! that the local service can tell peers apart and replies find their way
+//! back to the right one.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Instant};
+use tracing::{debug, warn};
+
+use crate::metrics::{DROPPED_DATAGRAMS, UDP_FLOWS};
+use crate::shared::{MAX_DATAGRAM_LENGTH, MAX_UDP_FLOWS};
+
+/// Maximum number of datagrams received on a UDP tunnel, waiting to be
+/// relayed to the client.
+pub const DATAGRAM_BACKLOG: usize = 64;
+
+/// UDP flows of a control connection, one for each peer address of a tunnel.
+///
+/// ```
+/// use std::time::Duration;
+/// use bore_cli::udp::FlowTable;
+///
+/// let mut flows = FlowTable::default();
+/// let peer = "192.0.2.1:5353".parse().unwrap();
+/// let flow = flows.open(0, peer).unwrap();
+/// assert_eq!(flows.open(0, peer), Some(flow));
+/// assert_eq!(flows.peer(flow), Some((0, peer)));
+///
+/// flows.expire(Duration::ZERO);
+/// assert_eq!(flows.peer(flow), None);
+/// ```
+#[derive(Debug, Default)]
+pub struct FlowTable {
+    ids: HashMap<(usize, SocketAddr), u32>,
+    flows: HashMap<u32, Flow>,
+    next_id: u32,
+}
+
+/// A peer of a UDP tunnel.
+#[derive(Debug)]
+struct Flow {
+    tunnel: usize,
+    peer: SocketAddr,
+    last_active: Instant,
+}
+
+impl FlowTable {
+    /// Returns the flow of a peer of a tunnel, opening one if fewer than
+    /// [`MAX_UDP_FLOWS`] are open.
+    pub fn open(&mut self, tunnel: usize, peer: SocketAddr) -> Option<u32> {
+        if let Some(&id) = self.ids.get(&(tunnel, peer)) {
+            self.peer(id);
+            return Some(id);
+        }
+        if self.flows.len() >= MAX_UDP_FLOWS {
+            return None;
+        }
+        while self.flows.contains_key(&self.next_id) {
+            self.next_id = self.next_id.wrapping_add(1);
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let flow = Flow {
+            tunnel,
+            peer,
+            last_active: Instant::now(),
+        };
+        self.ids.insert((tunnel, peer), id);
+        self.flows.insert(id, flow);
+        UDP_FLOWS.inc();
+        debug!(tunnel, ?peer, flow = id, "opened udp flow");
+        Some(id)
+    }
+
+    /// Returns the tunnel and peer address of a flow, marking it active.
+    pub fn peer(&mut self, id: u32) -> Option<(usize, SocketAddr)> {
+        let flow = self.flows.get_mut(&id)?;
+        flow.last_active = Instant::now();
+        Some((flow.tunnel, flow.peer))
+    }
+
+    /// Forget the flows without a datagram in either direction for longer than `idle`.
+    pub fn expire(&mut self, idle: Duration) {
+        self.retain(|flow| flow.last_active.elapsed() <= idle);
+    }
+
+    /// Forget the flows of a closed tunnel.
+    pub fn close_tunnel(&mut self, tunnel: usize) {
+        self.retain(|flow| flow.tunnel != tunnel);
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Flow) -> bool) {
+        let ids = &mut self.ids;
+        self.flows.retain(|_, flow| {
+            let kept = keep(flow);
+            if !kept {
+                ids.remove(&(flow.tunnel, flow.peer));
+            }
+            kept
+        });
+    }
+}
+
+/// Relay the datagrams of one flow to a local service from a socket of its
+/// own, sending the service's replies back tagged with the flow.
+///
+/// Returns once the flow was idle for `idle`, or either channel is closed.
+pub async fn relay_flow(
+    local: (&str, u16),
+    flow: u32,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    replies: mpsc::Sender<(u32, Vec<u8>)>,
+    idle: Duration,
+) -> io::Result<()> {
+    let addr = lookup_host(local)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "local host not found"))?;
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+
+    let mut buf = vec![0; MAX_DATAGRAM_LENGTH + 1];
+    loop {
+        tokio::select! {
+            datagram = datagrams.recv() => match datagram {
+                Some(datagram) => {
+                    socket.send(&datagram).await?;
+                }
+                None => return Ok(()),
+            },
+            result = socket.recv(&mut buf) => {
+                let len = result?;
+                if len > MAX_DATAGRAM_LENGTH {
+                    warn!(flow, "dropping oversized datagram from local service");
+                    DROPPED_DATAGRAMS.inc();
+                    continue;
+                }
+                if replies.send((flow, buf[..len].to_vec())).await.is_err() {
+                    return Ok(());
+                }
+            }
+            _ = sleep(idle) => {
+                debug!(flow, "closing idle udp flow");
+                return Ok(());
+            }
+        }
+    }
+}
@@ -1,6 +1,13 @@
 use anyhow::Result;
-use bore_cli::{auth::Authenticator, shared::Delimited};
+use bore_cli::{
+    auth::{Authenticator, Credentials},
+    shared::{Capability, ClientMessage, Delimited, ProtocolInfo, ServerMessage},
+};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
 use tokio::io::{self};
+use tokio_util::codec::{AnyDelimiterCodec, Framed};
+use uuid::Uuid;
 
 #[tokio::test]
 async fn auth_handshake() -> Result<()> {
@@ -33,3 +40,199 @@ async fn auth_handshake_fail() {
     );
     assert!(result.is_err());
 }
+
+#[tokio::test]
+async fn auth_handshake_key_id() -> Result<()> {
+    let mut credentials = Credentials::default();
+    credentials.insert("laptop", "laptop secret");
+    let shared = Authenticator::new("shared secret");
+
+    for (auth, expected) in [
+        (
+            Authenticator::with_key_id("laptop", "laptop secret"),
+            Some("laptop"),
+        ),
+        (Authenticator::new("shared secret"), None),
+    ] {
+        let (client, server) = io::duplex(8);
+        let mut client = Delimited::new(client);
+        let mut server = Delimited::new(server);
+
+        let (_, key_id) = tokio::try_join!(
+            auth.client_handshake(&mut client),
+            credentials.server_handshake(Some(&shared), true, &mut server),
+        )?;
+        assert_eq!(key_id.as_deref(), expected);
+    }
+
+    for auth in [
+        Authenticator::with_key_id("laptop", "shared secret"),
+        Authenticator::with_key_id("phone", "laptop secret"),
+    ] {
+        let (client, server) = io::duplex(8);
+        let mut client = Delimited::new(client);
+        let mut server = Delimited::new(server);
+
+        let result = tokio::try_join!(
+            auth.client_handshake(&mut client),
+            credentials.server_handshake(Some(&shared), true, &mut server),
+        );
+        assert!(result.is_err());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn auth_handshake_legacy() -> Result<()> {
+    let credentials = Credentials::default();
+    let shared = Authenticator::new("shared secret");
+
+    // An older client only answers the challenge.
+    for legacy in [true, false] {
+        let (client, server) = io::duplex(1024);
+        let mut client = Delimited::new(client);
+        let mut server = Delimited::new(server);
+        let (sent, result) = tokio::join!(
+            shared.legacy_client_handshake(&mut client),
+            credentials.server_handshake(Some(&shared), legacy, &mut server),
+        );
+        sent?;
+        match result {
+            Ok(key_id) => assert!(legacy && key_id.is_none()),
+            Err(err) => {
+                assert!(!legacy);
+                assert!(err.to_string().contains("mutual"), "{err}");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn auth_handshake_legacy_key_id() -> Result<()> {
+    let auth = Authenticator::with_key_id("laptop", "laptop secret");
+
+    // Named credentials cannot be used with an older server.
+    let (client, _server) = io::duplex(1024);
+    let mut client = Delimited::new(client);
+    let err = auth.legacy_client_handshake(&mut client).await.unwrap_err();
+    assert!(err.to_string().contains("named credentials"), "{err}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn auth_handshake_negotiation() -> Result<()> {
+    let auth = Authenticator::new("some secret string");
+
+    let (client, to_client) = io::duplex(1024);
+    let (to_server, server) = io::duplex(1024);
+    let mut client = Delimited::new(client);
+    let mut server = Delimited::new(server);
+    let codec = || AnyDelimiterCodec::new(vec![0], vec![0]);
+    let mut to_client = Framed::new(to_client, codec());
+    let mut to_server = Framed::new(to_server, codec());
+
+    // A man-in-the-middle strips the capabilities the client offers.
+    let relay = async move {
+        let text = |frame: Bytes| String::from_utf8(frame.to_vec()).unwrap();
+        to_client.next().await.unwrap()?;
+        let stripped = ClientMessage::Version(ProtocolInfo::new(vec![]));
+        to_server.send(serde_json::to_string(&stripped)?).await?;
+        for _ in 0..2 {
+            let reply = to_server.next().await.unwrap()?;
+            to_client.send(text(reply)).await?;
+        }
+        let answer = to_client.next().await.unwrap()?;
+        to_server.send(text(answer)).await?;
+        anyhow::Ok(())
+    };
+    let client = async {
+        ProtocolInfo::new(vec![Capability::Multiplex])
+            .client_negotiate(&mut client)
+            .await?;
+        auth.client_handshake(&mut client).await
+    };
+    let server = async {
+        ProtocolInfo::new(vec![Capability::Multiplex])
+            .server_negotiate(&mut server)
+            .await?;
+        auth.server_handshake(&mut server).await
+    };
+    let (relayed, _, result) = tokio::join!(relay, client, server);
+    relayed?;
+    let err = result.unwrap_err();
+    assert!(err.to_string().contains("invalid secret"), "{err}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn auth_handshake_server_proof() -> Result<()> {
+    let auth = Authenticator::new("some secret string");
+
+    // A server that does not know the secret cannot pose as the real one.
+    let (client, server) = io::duplex(1024);
+    let mut client = Delimited::new(client);
+    let mut server = Delimited::new(server);
+    let fake_server = async {
+        server
+            .send(ServerMessage::Challenge(Uuid::new_v4()))
+            .await?;
+        server.recv::<ClientMessage>().await?;
+        server
+            .send(ServerMessage::Proof(hex::encode([0; 32])))
+            .await
+    };
+    let (result, sent) = tokio::join!(auth.client_handshake(&mut client), fake_server);
+    sent?;
+    let err = result.unwrap_err();
+    assert!(err.to_string().contains("prove"), "{err}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn auth_messages() -> Result<()> {
+    let auth = Authenticator::new("some secret string");
+
+    let (client, to_client) = io::duplex(1024);
+    let (to_server, server) = io::duplex(1024);
+    let mut client = Delimited::new(client);
+    let mut server = Delimited::new(server);
+    let codec = || AnyDelimiterCodec::new(vec![0], vec![0]);
+    let mut to_client = Framed::new(to_client, codec());
+    let mut to_server = Framed::new(to_server, codec());
+
+    // A man-in-the-middle relays the handshake, then replays and forges messages.
+    let relay = async {
+        let text = |frame: Bytes| String::from_utf8(frame.to_vec()).unwrap();
+        let challenge = to_server.next().await.unwrap()?;
+        to_client.send(text(challenge)).await?;
+        let answer = to_client.next().await.unwrap()?;
+        to_server.send(text(answer)).await?;
+        let proof = to_server.next().await.unwrap()?;
+        to_client.send(text(proof)).await?;
+
+        let hello = text(to_client.next().await.unwrap()?);
+        to_server.send(&hello).await?;
+        to_server.send(&hello).await?;
+        to_server.send(r#"{"Hello":81}"#).await?;
+        anyhow::Ok(())
+    };
+    let client = async {
+        auth.client_handshake(&mut client).await?;
+        assert!(client.is_authenticated());
+        client.send(ClientMessage::Hello(80)).await
+    };
+    let server = async {
+        auth.server_handshake(&mut server).await?;
+        let hello = server.recv::<ClientMessage>().await?;
+        assert!(matches!(hello, Some(ClientMessage::Hello(80))));
+
+        let replayed = server.recv::<ClientMessage>().await;
+        assert!(replayed.is_err(), "replayed message was accepted");
+        let forged = server.recv::<ClientMessage>().await;
+        assert!(forged.is_err(), "forged message was accepted");
+        anyhow::Ok(())
+    };
+    tokio::try_join!(relay, client, server)?;
+    Ok(())
+}
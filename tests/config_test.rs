@@ -0,0 +1,237 @@
+use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_cli::access::AccessList;
+use bore_cli::client::Tunnel;
+use bore_cli::config::{ClientConfigFile, CredentialsFile, ServerConfigFile};
+
+#[test]
+fn client_config() -> Result<()> {
+    let config: ClientConfigFile = r#"
+        to = "bore.example.com"
+        multiplex = true
+        heartbeat_interval_ms = 500
+
+        [secret]
+        value = "my secret"
+
+        [reconnect]
+        initial_delay_ms = 200
+        max_retries = 5
+
+        [tls]
+        enabled = true
+
+        [tunnels.api]
+        local_port = 8080
+        remote_port = 9000
+
+        [tunnels.blog]
+        local_port = 4000
+        hostname = "blog"
+
+        [tunnels.db]
+        local_host = "db.lan"
+        local_port = 5432
+        allow = ["10.0.0.0/8"]
+        deny = ["10.0.0.13"]
+    "#
+    .parse()?;
+
+    assert_eq!(config.to.as_deref(), Some("bore.example.com"));
+    assert_eq!(config.secret.as_ref().unwrap().read()?, "my secret");
+    assert!(config.tls.enabled);
+    assert_eq!(config.liveness().interval, Duration::from_millis(500));
+    let reconnect = config.reconnect.settings();
+    assert_eq!(reconnect.delay, Duration::from_millis(200));
+    assert_eq!(reconnect.max_retries, Some(5));
+    let mut db: Tunnel = "db.lan:5432".parse()?;
+    db.access = AccessList::parse(&["10.0.0.0/8".into()], &["10.0.0.13".into()])?;
+    let blog = Tunnel {
+        http: Some("blog".into()),
+        ..Tunnel::from_str("localhost:4000")?
+    };
+    assert_eq!(
+        config.tunnels()?,
+        vec!["localhost:8080:9000".parse()?, blog, db]
+    );
+    Ok(())
+}
+
+#[test]
+fn client_config_errors() {
+    let err = "tunnel = 1".parse::<ClientConfigFile>().unwrap_err();
+    assert!(err.to_string().contains("line 1"), "{err}");
+
+    let err = "[secret]\nvalue = \"a\"\nenv = \"B\""
+        .parse::<ClientConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("secret:"), "{err}");
+
+    let err = "[tunnels.web]\nlocal_host = \"\"\nlocal_port = 80"
+        .parse::<ClientConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().contains("tunnels.web.local_host"), "{err}");
+
+    let err = "[tls]\npin_sha256 = [\"abcd\"]"
+        .parse::<ClientConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("tls.pin_sha256"), "{err}");
+
+    let err = "[tls]\nenabled = true\n[noise]\nserver_key = \"00\""
+        .parse::<ClientConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("noise.server_key"), "{err}");
+
+    let err = "max_missed_heartbeats = 0"
+        .parse::<ClientConfigFile>()
+        .unwrap_err();
+    assert!(
+        err.to_string().starts_with("max_missed_heartbeats:"),
+        "{err}"
+    );
+
+    let err = "multiplex = true\n[tunnels.dns]\nlocal_port = 53\nudp = true"
+        .parse::<ClientConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("tunnels.dns.udp:"), "{err}");
+
+    let err = "[tunnels.web]\nlocal_port = 80\nhostname = \"my_app\""
+        .parse::<ClientConfigFile>()
+        .unwrap_err();
+    assert!(
+        err.to_string().starts_with("tunnels.web.hostname:"),
+        "{err}"
+    );
+
+    let err = "[reconnect]\ninitial_delay_ms = 5000\nmax_delay_ms = 1000"
+        .parse::<ClientConfigFile>()
+        .unwrap_err();
+    assert!(
+        err.to_string().starts_with("reconnect.max_delay_ms:"),
+        "{err}"
+    );
+
+    let err = "[tunnels.web]\nlocal_port = 80\nallow = [\"10.0.0.0/33\"]"
+        .parse::<ClientConfigFile>()
+        .unwrap_err();
+    assert!(
+        format!("{err:#}").starts_with("tunnels.web: allow:"),
+        "{err:#}"
+    );
+}
+
+#[test]
+fn client_config_relative_paths() -> Result<()> {
+    let dir = env::temp_dir().join(format!("bore-config-test-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("secret.txt"), "file secret\n")?;
+    fs::write(
+        dir.join("bore.toml"),
+        "[secret]\nfile = \"secret.txt\"\n\n[tls]\ncafile = \"ca.crt\"\n",
+    )?;
+
+    let config = ClientConfigFile::load(&dir.join("bore.toml"))?;
+    assert_eq!(config.secret.unwrap().read()?, "file secret");
+    assert_eq!(config.tls.cafile, Some(dir.join("ca.crt")));
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn server_config() -> Result<()> {
+    let config: ServerConfigFile = r#"
+        control_port = 7000
+        metrics_addr = "0.0.0.0:9100"
+        heartbeat_interval_ms = 500
+        min_port = 20000
+
+        [secret]
+        env = "BORE_SERVER_SECRET"
+    "#
+    .parse()?;
+
+    assert_eq!(config.control_port, Some(7000));
+    assert_eq!(config.metrics_addr, Some("0.0.0.0:9100".parse()?));
+    assert_eq!(config.port_range(), Some(20000..=65535));
+
+    // Without a key, the certificate is a PKCS#12 bundle.
+    "[tls]\nenabled = true\ncert = \"identity.p12\"".parse::<ServerConfigFile>()?;
+    let err = "[tls]\nenabled = true\nkey = \"server.key\""
+        .parse::<ServerConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("tls.cert:"), "{err}");
+
+    let err = "[noise]\nclients = [\"00\"]"
+        .parse::<ServerConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("noise.clients"), "{err}");
+    let err = "[tls]\nenabled = true\ncert = \"server.crt\"\n[noise]\nkey = \"server.key\""
+        .parse::<ServerConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("noise.key:"), "{err}");
+
+    let config: ServerConfigFile = "[bans]\nmax_failures = 3\nban_secs = 60".parse()?;
+    assert_eq!(config.bans.settings().max_failures, 3);
+    assert_eq!(config.bans.settings().ban_duration, Duration::from_secs(60));
+    let err = "[bans]\ndelay_ms = 5000\nmax_delay_ms = 1000"
+        .parse::<ServerConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("bans.max_delay_ms:"), "{err}");
+    let config: ServerConfigFile = "[bans]\nipv6_prefix = 56".parse()?;
+    assert_eq!(config.bans.settings().ipv6_prefix, 56);
+    let err = "[bans]\nipv6_prefix = 129"
+        .parse::<ServerConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("bans.ipv6_prefix:"), "{err}");
+
+    let config: ServerConfigFile = "[access.control]\ndeny = [\"192.0.2.0/24\"]".parse()?;
+    let control = config.access.control.list()?;
+    assert!(!control.permits("192.0.2.1".parse()?));
+    assert!(config.access.tunnels.list()?.is_empty());
+    let err = "[access.tunnels]\nallow = [\"example.com\"]"
+        .parse::<ServerConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("access.tunnels"), "{err}");
+
+    let config: ServerConfigFile = "http_port = 80\nhttp_domain = \"bore.example.com\"".parse()?;
+    assert_eq!(config.http_port, Some(80));
+    let err = "http_domain = \"*.example.com\""
+        .parse::<ServerConfigFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("http_domain:"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn credentials_file() -> Result<()> {
+    let dir = env::temp_dir().join(format!("bore-credentials-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("ci.key"), "ci secret\n")?;
+    fs::write(
+        dir.join("credentials.toml"),
+        "[keys.laptop]\nvalue = \"laptop secret\"\n\n[keys.ci]\nfile = \"ci.key\"\n",
+    )?;
+
+    let credentials = CredentialsFile::load(&dir.join("credentials.toml"))?.credentials()?;
+    assert!(credentials.contains("laptop"));
+    assert!(credentials.contains("ci"));
+    assert!(!credentials.contains("phone"));
+
+    let err = "[keys.laptop]\nvalue = \"a\"\nenv = \"B\""
+        .parse::<CredentialsFile>()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("keys.laptop:"), "{err}");
+
+    let err = "[keys.laptop]\nvalue = \"a\"\nallowed_ports = [\"9000-8000\"]"
+        .parse::<CredentialsFile>()
+        .unwrap_err();
+    assert!(format!("{err:#}").starts_with("keys.laptop.allowed_ports: empty port range"));
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -1,14 +1,28 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
-use bore_cli::{client::Client, server::Server, shared::CONTROL_PORT};
+use bore_cli::access::AccessList;
+use bore_cli::auth::{Authenticator, Credentials};
+use bore_cli::ban::BanSettings;
+use bore_cli::client::{Client, Liveness, Tunnel};
+use bore_cli::metrics::{
+    start_admin_server, start_metric_server, ACCESS_DENIED_CONNECTIONS, HEARTBEAT_TIMEOUTS,
+};
+use bore_cli::noise::{Keypair, NoiseClient, NoiseServer};
+use bore_cli::policy::Policy;
+use bore_cli::server::{Server, ServerSettings};
+use bore_cli::shared::{
+    Capability, ClientMessage, Delimited, ProtocolInfo, ServerMessage, CONTROL_PORT,
+};
+use bore_cli::supervisor::{Reconnect, Supervisor};
 use lazy_static::lazy_static;
 use rstest::*;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::sync::Mutex;
 use tokio::time;
+use uuid::Uuid;
 
 lazy_static! {
     /// Guard to make sure that tests are run serially, not concurrently.
@@ -17,27 +31,48 @@ lazy_static! {
 
 /// Spawn the server, giving some time for the control port TcpListener to start.
 async fn spawn_server(secret: Option<&str>) {
-    tokio::spawn(Server::new(secret).listen());
+    tokio::spawn(Server::new(1024..=65535, secret).listen());
     time::sleep(Duration::from_millis(50)).await;
 }
 
 /// Spawns a client with randomly assigned ports, returning the listener and remote address.
-async fn spawn_client(secret: Option<&str>) -> Result<(TcpListener, SocketAddr)> {
+async fn spawn_client(secret: Option<&str>, multiplex: bool) -> Result<(TcpListener, SocketAddr)> {
     let listener = TcpListener::bind("localhost:0").await?;
     let local_port = listener.local_addr()?.port();
-    let client = Client::new("localhost", local_port, "localhost", secret).await?;
+    let client = Client::new_with_tls(
+        "localhost",
+        local_port,
+        "localhost",
+        0,
+        secret,
+        None,
+        multiplex,
+    )
+    .await?;
     let remote_addr = ([127, 0, 0, 1], client.remote_port()).into();
     tokio::spawn(client.listen());
     Ok((listener, remote_addr))
 }
 
+/// Opens a raw control connection to the server and negotiates the protocol.
+async fn connect_control() -> Result<Delimited<TcpStream>> {
+    let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    ProtocolInfo::new(vec![])
+        .client_negotiate(&mut stream)
+        .await?;
+    Ok(stream)
+}
+
 #[rstest]
 #[tokio::test]
-async fn basic_proxy(#[values(None, Some(""), Some("abc"))] secret: Option<&str>) -> Result<()> {
+async fn basic_proxy(
+    #[values(None, Some(""), Some("abc"))] secret: Option<&str>,
+    #[values(false, true)] multiplex: bool,
+) -> Result<()> {
     let _guard = SERIAL_GUARD.lock().await;
 
     spawn_server(secret).await;
-    let (listener, addr) = spawn_client(secret).await?;
+    let (listener, addr) = spawn_client(secret, multiplex).await?;
 
     tokio::spawn(async move {
         let (mut stream, _) = listener.accept().await?;
@@ -77,14 +112,372 @@ async fn mismatched_secret(
     let _guard = SERIAL_GUARD.lock().await;
 
     spawn_server(server_secret).await;
-    assert!(spawn_client(client_secret).await.is_err());
+    assert!(spawn_client(client_secret, false).await.is_err());
+}
+
+#[tokio::test]
+async fn requested_port() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    tokio::spawn(Server::new(40000..=40010, None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let client = Client::new("localhost", 5000, "localhost", 40005, None).await?;
+    assert_eq!(client.remote_port(), 40005);
+
+    // The same port cannot be handed out twice.
+    assert!(Client::new("localhost", 5000, "localhost", 40005, None)
+        .await
+        .is_err());
+
+    // Ports outside the configured range are rejected.
+    assert!(Client::new("localhost", 5000, "localhost", 50000, None)
+        .await
+        .is_err());
+
+    // Any available port is picked from the configured range.
+    let client = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    assert!((40000..=40010).contains(&client.remote_port()));
+
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn multiple_tunnels(#[values(false, true)] multiplex: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listeners = [
+        TcpListener::bind("localhost:0").await?,
+        TcpListener::bind("localhost:0").await?,
+    ];
+    let mut tunnels = Vec::new();
+    for listener in &listeners {
+        tunnels.push(format!("localhost:{}", listener.local_addr()?.port()).parse()?);
+    }
+    let client = Client::with_tunnels(tunnels, "localhost", None, None, multiplex).await?;
+    let remote_ports = client.remote_ports().to_vec();
+    assert_eq!(remote_ports.len(), 2);
+    tokio::spawn(client.listen());
+
+    for (listener, message) in listeners.into_iter().zip([b"first!", b"second"]) {
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            stream.write_all(message).await?;
+            anyhow::Ok(())
+        });
+    }
+
+    for (port, message) in remote_ports.into_iter().zip([b"first!", b"second"]) {
+        let mut stream = TcpStream::connect(("localhost", port)).await?;
+        let mut buf = [0u8; 6];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, message);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn accept_other_session() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let mut control = connect_control().await?;
+    control.send(ClientMessage::Hello(0)).await?;
+    let (port, session) = match control.recv().await? {
+        Some(ServerMessage::Hello(port, session)) => (port, session),
+        msg => panic!("unexpected message: {msg:?}"),
+    };
+
+    let mut visitor = TcpStream::connect(("localhost", port)).await?;
+    let id = loop {
+        match control.recv().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(id)) => break id,
+            msg => panic!("unexpected message: {msg:?}"),
+        }
+    };
+
+    // Another session cannot take the connection.
+    let mut intruder = connect_control().await?;
+    intruder
+        .send(ClientMessage::Accept(id, Uuid::new_v4()))
+        .await?;
+    assert!(matches!(
+        intruder.recv().await?,
+        Some(ServerMessage::Error(_))
+    ));
+
+    // The owning session still can.
+    let mut owner = connect_control().await?;
+    owner.send(ClientMessage::Accept(id, session)).await?;
+    owner.into_parts().io.write_all(b"hello").await?;
+
+    let mut buf = [0u8; 5];
+    visitor.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reload_settings() -> Result<()> {
+    // A control port other than the default, so no serial guard is needed.
+    // Multiplexing, which keeps the tunnel working, requires an encrypted transport.
+    let server_key = Keypair::generate();
+    let settings_with = |secret| ServerSettings {
+        noise: Some(NoiseServer::new(server_key.clone(), vec![])),
+        ..ServerSettings::new(1024..=65535, Some(secret))
+    };
+    let server = Server::with_settings(
+        vec![([127, 0, 0, 1], 7850).into()],
+        settings_with("old secret"),
+    );
+    let settings = server.settings();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let tunnel: Tunnel = format!("localhost:{}", listener.local_addr()?.port()).parse()?;
+    let connect = |secret, multiplex| {
+        let noise = NoiseClient::new(Keypair::generate(), server_key.public());
+        let auth = Some(Authenticator::new(secret));
+        let tunnels = vec![tunnel.clone()];
+        Client::with_tunnels(
+            tunnels,
+            "127.0.0.1:7850",
+            auth,
+            Some(noise.into()),
+            multiplex,
+        )
+    };
+    let client = connect("old secret", true).await?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], client.remote_port()));
+    tokio::spawn(client.listen());
+    settings.set(settings_with("new secret"));
+
+    // New clients must use the new secret.
+    assert!(connect("old secret", false).await.is_err());
+    connect("new secret", false).await?;
+
+    // The existing tunnel keeps working.
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(b"still here").await?;
+        anyhow::Ok(())
+    });
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut buf = [0u8; 10];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"still here");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoke_credentials() -> Result<()> {
+    // A control port other than the default, so no serial guard is needed.
+    // Multiplexing requires an encrypted transport.
+    let server_key = Keypair::generate();
+    let settings_with = |key_id: &str| {
+        let mut credentials = Credentials::default();
+        credentials.insert(key_id, "key secret");
+        ServerSettings {
+            credentials,
+            noise: Some(NoiseServer::new(server_key.clone(), vec![])),
+            heartbeat_interval: Duration::from_millis(100),
+            ..ServerSettings::new(1024..=65535, None)
+        }
+    };
+    let server =
+        Server::with_settings(vec![([127, 0, 0, 1], 7853).into()], settings_with("laptop"));
+    let settings = server.settings();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let connect = |multiplex, auth| {
+        let tunnels = vec!["localhost:5000".parse().unwrap()];
+        let noise = NoiseClient::new(Keypair::generate(), server_key.public());
+        Client::with_tunnels(
+            tunnels,
+            "127.0.0.1:7853",
+            auth,
+            Some(noise.into()),
+            multiplex,
+        )
+    };
+    let laptop = Authenticator::with_key_id("laptop", "key secret");
+    assert!(connect(false, None).await.is_err());
+    assert!(connect(false, Some(Authenticator::new("key secret")))
+        .await
+        .is_err());
+    assert!(connect(
+        false,
+        Some(Authenticator::with_key_id("phone", "key secret"))
+    )
+    .await
+    .is_err());
+
+    let multiplexed = connect(true, Some(laptop.clone())).await?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], multiplexed.remote_port()));
+    let clients = [
+        tokio::spawn(connect(false, Some(laptop.clone())).await?.listen()),
+        tokio::spawn(multiplexed.listen()),
+    ];
+
+    // Visitors arrive more often than the heartbeat interval.
+    let visitors = tokio::spawn(async move {
+        loop {
+            TcpStream::connect(addr).await.ok();
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    });
+
+    // Revoking the credential closes its tunnels and rejects new clients.
+    settings.set(settings_with("phone"));
+    for client in clients {
+        time::timeout(Duration::from_secs(5), client).await??.ok();
+    }
+    visitors.abort();
+    assert!(connect(false, Some(laptop)).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiplexed_control() -> Result<()> {
+    // A control port other than the default, so no serial guard is needed.
+    // Multiplexing requires an encrypted transport.
+    let server_key = Keypair::generate();
+    let settings = ServerSettings {
+        noise: Some(NoiseServer::new(server_key.clone(), vec![])),
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    let server = Server::with_settings(vec![([127, 0, 0, 1], 7855).into()], settings);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local = format!("localhost:{}", listener.local_addr()?.port());
+    let noise = NoiseClient::new(Keypair::generate(), server_key.public());
+    let client = Client::with_tunnels(
+        vec![local.parse()?],
+        "127.0.0.1:7855",
+        None,
+        Some(noise.into()),
+        true,
+    )
+    .await?;
+    let control = client.control();
+    tokio::spawn(client.listen());
+
+    // Requests are answered on the control stream of the multiplexed connection.
+    control.ping().await?;
+    let (tunnel, port) = control.add_tunnel(local.parse()?).await?;
+    assert_eq!(tunnel, 1);
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut accepted, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    accepted.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    control.close_tunnel(tunnel).await?;
+    assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn credential_policy(#[values(false, true)] multiplex: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut credentials = Credentials::default();
+    let policy = Policy {
+        port_ranges: vec![40020..=40025],
+        max_tunnels: Some(1),
+        max_connections: Some(1),
+        ..Policy::default()
+    };
+    credentials.insert_with_policy("limited", "secret", policy);
+    let policy = Policy {
+        require_tls: true,
+        ..Policy::default()
+    };
+    credentials.insert_with_policy("secure", "secret", policy);
+    let settings = ServerSettings {
+        credentials,
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    let control_addr = ([0, 0, 0, 0], CONTROL_PORT).into();
+    tokio::spawn(Server::with_settings(vec![control_addr], settings).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let connect = |key_id, port| {
+        let tunnels = vec![format!("localhost:{local_port}:{port}").parse().unwrap()];
+        let auth = Authenticator::with_key_id(key_id, "secret");
+        Client::with_tunnels(tunnels, "localhost", Some(auth), None, multiplex)
+    };
+
+    let err = connect("secure", 0).await.err().unwrap();
+    assert!(err.to_string().contains("tls"), "{err}");
+    let err = connect("limited", 40030).await.err().unwrap();
+    assert!(err.to_string().contains("port not allowed"), "{err}");
+
+    let client = connect("limited", 0).await?;
+    let remote_port = client.remote_port();
+    assert!((40020..=40025).contains(&remote_port));
+    tokio::spawn(client.listen());
+    let err = connect("limited", 0).await.err().unwrap();
+    assert!(err.to_string().contains("tunnel limit"), "{err}");
+
+    // Only one proxied connection may be open at once.
+    let mut first = TcpStream::connect(("localhost", remote_port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    first.write_all(b"first").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+
+    let mut second = TcpStream::connect(("localhost", remote_port)).await?;
+    let read = time::timeout(Duration::from_secs(5), second.read(&mut buf)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn custom_bind_addresses() -> Result<()> {
+    // A control port other than the default, so no serial guard is needed.
+    let control_addr = SocketAddr::from(([127, 0, 0, 1], 7836));
+    let settings = ServerSettings {
+        bind_tunnels: [127, 0, 0, 1].into(),
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    tokio::spawn(Server::with_settings(vec![control_addr], settings).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = Client::new("localhost", local_port, "127.0.0.1:7836", 0, None).await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut conn = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+    let (mut stream, _) = listener.accept().await?;
+    conn.write_all(b"bound").await?;
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"bound");
+    Ok(())
 }
 
 #[tokio::test]
 async fn invalid_address() -> Result<()> {
     // We don't need the serial guard for this test because it doesn't create a server.
     async fn check_address(to: &str, use_secret: bool) -> Result<()> {
-        match Client::new("localhost", 5000, to, use_secret.then(|| "a secret")).await {
+        match Client::new("localhost", 5000, to, 0, use_secret.then_some("a secret")).await {
             Ok(_) => Err(anyhow!("expected error for {to}, use_secret={use_secret}")),
             Err(_) => Ok(()),
         }
@@ -96,6 +489,8 @@ async fn invalid_address() -> Result<()> {
         check_address("nonexistent.domain.for.demonstration", true),
         check_address("malformed !$uri$%", false),
         check_address("malformed !$uri$%", true),
+        check_address("localhost:notaport", false),
+        check_address("[::1", false),
     )?;
     Ok(())
 }
@@ -117,3 +512,670 @@ async fn very_long_frame() -> Result<()> {
     }
     panic!("did not exit after a 1 MB frame");
 }
+
+#[tokio::test]
+async fn plaintext_multiplex_refused() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    let protocol = ProtocolInfo::new(vec![Capability::Multiplex, Capability::MultiTunnel])
+        .client_negotiate(&mut stream)
+        .await?;
+    assert!(!protocol.supports(Capability::Multiplex));
+
+    // Streams multiplexed over plaintext could be forged by a man-in-the-middle.
+    stream.send(ClientMessage::HelloMux(0)).await?;
+    match stream.recv_timeout().await? {
+        Some(ServerMessage::Error(message)) => {
+            assert!(message.contains("multiplexing"), "{message}")
+        }
+        other => panic!("unexpected reply: {other:?}"),
+    }
+
+    // A yamux SYN injected afterwards never reaches a session.
+    let mut stream = stream.into_stream();
+    let syn = [0u8, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0];
+    stream.write_all(&syn).await.ok();
+    let mut buf = [0u8; 64];
+    let read = time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await?;
+    assert!(
+        matches!(read, Ok(0) | Err(_)),
+        "server answered a forged stream"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn ban_failed_authentication() -> Result<()> {
+    // A control port other than the default, so no serial guard is needed.
+    let settings = ServerSettings {
+        bans: BanSettings {
+            max_failures: 3,
+            delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(50),
+            ban_duration: Duration::from_secs(60),
+            ..BanSettings::default()
+        },
+        ..ServerSettings::new(1024..=65535, Some("secret"))
+    };
+    let server = Server::with_settings(vec![([127, 0, 0, 1], 7841).into()], settings);
+    let bans = server.bans();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let connect = |secret| Client::new("localhost", 5000, "127.0.0.1:7841", 0, Some(secret));
+    connect("secret").await?;
+
+    // Failures are answered later each time, until the address is banned.
+    let start = time::Instant::now();
+    for _ in 0..3 {
+        let err = connect("wrong").await.err().unwrap();
+        assert!(err.to_string().contains("invalid secret"), "{err}");
+    }
+    assert!(start.elapsed() >= Duration::from_millis(20 + 40 + 50));
+    let banned = bans.bans();
+    assert_eq!(banned.len(), 1);
+    assert_eq!(banned[0].ip, "127.0.0.1".parse::<IpAddr>()?);
+
+    // While banned, even the right secret is refused.
+    assert!(connect("secret").await.is_err());
+
+    assert!(bans.unban(banned[0].ip));
+    assert!(bans.bans().is_empty());
+    connect("secret").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn ban_parallel_guesses() -> Result<()> {
+    let settings = ServerSettings {
+        bans: BanSettings {
+            max_failures: 3,
+            delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(50),
+            ban_duration: Duration::from_secs(60),
+            max_pending: 5,
+            ..BanSettings::default()
+        },
+        ..ServerSettings::new(1024..=65535, Some("secret"))
+    };
+    let server = Server::with_settings(vec![([127, 0, 0, 1], 7854).into()], settings);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let open = || async {
+        let mut stream = Delimited::new(TcpStream::connect("127.0.0.1:7854").await?);
+        ProtocolInfo::new(vec![Capability::MutualAuth])
+            .client_negotiate(&mut stream)
+            .await?;
+        anyhow::Ok(stream)
+    };
+
+    // All connections are challenged before any guess is answered.
+    let mut streams = Vec::new();
+    for _ in 0..5 {
+        streams.push(open().await?);
+    }
+    let err = Authenticator::new("secret")
+        .client_handshake(&mut open().await?)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("too many"), "{err}");
+
+    let wrong = Authenticator::new("wrong");
+    for stream in &mut streams[..3] {
+        let err = wrong.client_handshake(stream).await.unwrap_err();
+        assert!(err.to_string().contains("invalid secret"), "{err}");
+    }
+
+    // Guesses still in flight when the address is banned are refused, even right ones.
+    let right = Authenticator::new("secret");
+    for stream in &mut streams[3..] {
+        let err = right.client_handshake(stream).await.unwrap_err();
+        assert!(err.to_string().contains("banned"), "{err}");
+    }
+    Ok(())
+}
+
+#[test]
+fn ban_ipv6_network() -> Result<()> {
+    let bans = Server::new(1024..=65535, None).bans();
+    let settings = BanSettings {
+        max_failures: 2,
+        ..Default::default()
+    };
+
+    // Addresses of the same /64 network share their failures and ban.
+    bans.record_failure("2001:db8::1".parse()?, &settings);
+    bans.record_failure("2001:db8::2:3".parse()?, &settings);
+    assert!(bans.is_banned("2001:db8::ffff".parse()?, &settings));
+    assert!(!bans.is_banned("2001:db8:0:1::1".parse()?, &settings));
+    let banned = bans.bans();
+    assert_eq!(banned.len(), 1);
+    assert_eq!(banned[0].ip, "2001:db8::".parse::<IpAddr>()?);
+    assert_eq!(banned[0].prefix_len, 64);
+
+    // Lifting the ban of any address in the network lifts it for all.
+    assert!(bans.unban("2001:db8::42".parse()?));
+    assert!(!bans.is_banned("2001:db8::1".parse()?, &settings));
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_server() -> Result<()> {
+    let bans = Server::new(1024..=65535, None).bans();
+    let settings = BanSettings {
+        max_failures: 1,
+        ..Default::default()
+    };
+    let ip: IpAddr = "203.0.113.7".parse()?;
+    bans.record_failure(ip, &settings);
+    assert!(bans.is_banned(ip, &settings));
+
+    // Ports other than the defaults, which a running server may hold.
+    tokio::spawn(start_metric_server(([127, 0, 0, 1], 7851).into()));
+    tokio::spawn(start_admin_server(
+        ([127, 0, 0, 1], 7852).into(),
+        bans.clone(),
+    ));
+    time::sleep(Duration::from_millis(50)).await;
+
+    let delete = |port| async move {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let request =
+            format!("DELETE /bans/{ip} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        anyhow::Ok(response)
+    };
+
+    // Bans cannot be lifted through the metrics server.
+    let response = delete(7851).await?;
+    assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+    assert!(bans.is_banned(ip, &settings));
+
+    let response = delete(7852).await?;
+    assert!(response.starts_with("HTTP/1.1 204"), "{response}");
+    assert!(!bans.is_banned(ip, &settings));
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_lists() -> Result<()> {
+    // Control ports other than the default, so no serial guard is needed.
+    let settings = ServerSettings {
+        tunnel_access: AccessList::parse(&["127.0.0.0/8".into()], &[])?,
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    tokio::spawn(Server::with_settings(vec![([127, 0, 0, 1], 7842).into()], settings).listen());
+    let settings = ServerSettings {
+        control_access: AccessList::parse(&[], &["127.0.0.1".into()])?,
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    tokio::spawn(Server::with_settings(vec![([127, 0, 0, 1], 7843).into()], settings).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let denied = || {
+        ACCESS_DENIED_CONNECTIONS
+            .with_label_values(&["control"])
+            .get()
+    };
+    let before = denied();
+    assert!(Client::new("localhost", 5000, "127.0.0.1:7843", 0, None)
+        .await
+        .is_err());
+    assert!(denied() > before);
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let tunnel = |access| Tunnel {
+        local_host: "localhost".into(),
+        local_port: listener.local_addr().unwrap().port(),
+        port: 0,
+        access,
+        udp: false,
+        http: None,
+    };
+    let tunnels = vec![
+        tunnel(AccessList::parse(&[], &["127.0.0.1".into()])?),
+        tunnel(AccessList::parse(&["127.0.0.1/32".into()], &[])?),
+    ];
+    let client = Client::with_tunnels(tunnels, "127.0.0.1:7842", None, None, false).await?;
+    let ports = client.remote_ports().to_vec();
+    tokio::spawn(client.listen());
+
+    // The first tunnel denies this address, so the connection is dropped.
+    let denied = || {
+        ACCESS_DENIED_CONNECTIONS
+            .with_label_values(&["tunnel"])
+            .get()
+    };
+    let before = denied();
+    let mut stream = TcpStream::connect(("127.0.0.1", ports[0])).await?;
+    let mut buf = [0; 1];
+    let read = time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+    assert!(denied() > before);
+
+    // The second tunnel allows it, within what the server allows.
+    let mut stream = TcpStream::connect(("127.0.0.1", ports[1])).await?;
+    let (mut local, _) = time::timeout(Duration::from_secs(1), listener.accept()).await??;
+    stream.write_all(b"hi").await?;
+    let mut buf = [0; 2];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hi");
+    Ok(())
+}
+
+#[tokio::test]
+async fn tunnel_control() -> Result<()> {
+    // A control port other than the default, so no serial guard is needed.
+    let server = Server::with_settings(
+        vec![([127, 0, 0, 1], 7844).into()],
+        ServerSettings::new(1024..=65535, None),
+    );
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = Client::new("localhost", local_port, "127.0.0.1:7844", 0, None).await?;
+    let first_port = client.remote_port();
+    let control = client.control();
+    tokio::spawn(client.listen());
+
+    assert!(control.ping().await? < Duration::from_secs(1));
+
+    let tunnel = format!("localhost:{local_port}").parse()?;
+    let (index, port) = control.add_tunnel(tunnel).await?;
+    assert_eq!(index, 1);
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let (mut local, _) = time::timeout(Duration::from_secs(1), listener.accept()).await??;
+    stream.write_all(b"added").await?;
+    let mut buf = [0; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"added");
+
+    // The closed tunnel stops listening, while the added one keeps working.
+    control.close_tunnel(0).await?;
+    assert!(TcpStream::connect(("127.0.0.1", first_port)).await.is_err());
+    let err = control.close_tunnel(0).await.unwrap_err();
+    assert!(err.to_string().contains("not open"), "{err}");
+    TcpStream::connect(("127.0.0.1", port)).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_liveness() -> Result<()> {
+    // A server that answers the hello, then goes silent without closing.
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let to = server.local_addr()?.to_string();
+    tokio::spawn(async move {
+        let (stream, _) = server.accept().await?;
+        let mut stream = Delimited::new(stream);
+        ProtocolInfo::new(vec![])
+            .server_negotiate(&mut stream)
+            .await?;
+        let _hello: Option<ClientMessage> = stream.recv().await?;
+        stream.send(ServerMessage::Hello(1, Uuid::new_v4())).await?;
+        time::sleep(Duration::from_secs(10)).await;
+        anyhow::Ok(())
+    });
+
+    let liveness = Liveness {
+        interval: Duration::from_millis(50),
+        max_missed: 2,
+    };
+    let client = Client::new("localhost", 5000, &to, 0, None).await?;
+    let result = time::timeout(
+        Duration::from_secs(1),
+        client.with_liveness(liveness).listen(),
+    )
+    .await?;
+    let err = result.unwrap_err();
+    assert!(err.to_string().contains("assuming it is gone"), "{err}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_liveness() -> Result<()> {
+    // A control port other than the default, so no serial guard is needed.
+    let settings = ServerSettings {
+        heartbeat_interval: Duration::from_millis(50),
+        max_missed_heartbeats: 2,
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    tokio::spawn(Server::with_settings(vec![([127, 0, 0, 1], 7845).into()], settings).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Clients answer the server's pings and measure their own round trips.
+    let client = Client::new("localhost", 5000, "127.0.0.1:7845", 0, None).await?;
+    let control = client.control();
+    let client = client.with_liveness(Liveness {
+        interval: Duration::from_millis(50),
+        max_missed: 2,
+    });
+    tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(300)).await;
+    assert!(control.rtt().is_some());
+    control.ping().await?;
+
+    // A client that stops answering is disconnected.
+    let before = HEARTBEAT_TIMEOUTS.get();
+    let mut stream = Delimited::new(TcpStream::connect("127.0.0.1:7845").await?);
+    ProtocolInfo::new(vec![Capability::Liveness])
+        .client_negotiate(&mut stream)
+        .await?;
+    stream.send(ClientMessage::Hello(0)).await?;
+    let closed = time::timeout(Duration::from_secs(1), async {
+        while let Some(message) = stream.recv::<ServerMessage>().await? {
+            assert!(!matches!(message, ServerMessage::Heartbeat));
+        }
+        anyhow::Ok(())
+    });
+    closed.await??;
+    assert!(HEARTBEAT_TIMEOUTS.get() > before);
+    Ok(())
+}
+
+#[tokio::test]
+async fn resume_session() -> Result<()> {
+    // A control port other than the default, so no serial guard is needed.
+    let server = Server::with_settings(
+        vec![([127, 0, 0, 1], 7846).into()],
+        ServerSettings::new(1024..=65535, None),
+    );
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let tunnels: Vec<Tunnel> =
+        vec![format!("localhost:{}", listener.local_addr()?.port()).parse()?];
+    let to = "127.0.0.1:7846";
+    let client = Client::with_tunnels(tunnels.clone(), to, None, None, false).await?;
+    let (port, session) = (client.remote_port(), client.session());
+    tokio::spawn(client.listen()).abort();
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The server holds the port of the lost connection for the session.
+    let client = Client::resume(tunnels.clone(), to, None, None, false, session).await?;
+    assert_eq!((client.remote_port(), client.session()), (port, session));
+    let first = tokio::spawn(client.listen());
+
+    // Resuming a session that is still connected takes it over.
+    let client = Client::resume(tunnels.clone(), to, None, None, false, session).await?;
+    assert_eq!(client.remote_port(), port);
+    time::timeout(Duration::from_secs(1), first).await???;
+    tokio::spawn(client.listen());
+    TcpStream::connect(("127.0.0.1", port)).await?;
+    time::timeout(Duration::from_secs(1), listener.accept()).await??;
+
+    // Unknown sessions get new ones.
+    let client = Client::resume(tunnels, to, None, None, false, Uuid::new_v4()).await?;
+    assert_ne!(client.session(), session);
+    assert_ne!(client.remote_port(), port);
+    Ok(())
+}
+
+#[tokio::test]
+async fn resume_without_grace() -> Result<()> {
+    // A control port other than the default, so no serial guard is needed.
+    let settings = ServerSettings {
+        resume_grace: Duration::ZERO,
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    let server = Server::with_settings(vec![([127, 0, 0, 1], 7856).into()], settings);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut stream = Delimited::new(TcpStream::connect("127.0.0.1:7856").await?);
+    let protocol = ProtocolInfo::new(vec![Capability::Resume])
+        .client_negotiate(&mut stream)
+        .await?;
+    assert!(!protocol.supports(Capability::Resume));
+
+    // Without held tunnels, resuming opens a new session right away.
+    let tunnels: Vec<Tunnel> = vec!["localhost:5000".parse()?];
+    let to = "127.0.0.1:7856";
+    let client = Client::with_tunnels(tunnels.clone(), to, None, None, false).await?;
+    let session = client.session();
+    tokio::spawn(client.listen());
+    let resume = Client::resume(tunnels, to, None, None, false, session);
+    let client = time::timeout(Duration::from_millis(500), resume).await??;
+    assert_ne!(client.session(), session);
+    Ok(())
+}
+
+#[tokio::test]
+async fn supervisor_retries() -> Result<()> {
+    // An address that refuses connections.
+    let to = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let supervisor = Supervisor {
+        reconnect: Reconnect {
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            max_retries: Some(2),
+        },
+        ..Supervisor::new(vec!["localhost:5000".parse()?], &to.to_string())
+    };
+    let err = time::timeout(Duration::from_secs(1), supervisor.run())
+        .await?
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("giving up after 3 attempts"),
+        "{err}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn supervisor_backoff() -> Result<()> {
+    // A server that accepts each connection, then drops it right away.
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let to = server.local_addr()?.to_string();
+    let accepted = tokio::spawn(async move {
+        let mut accepted = 0;
+        while let Ok((stream, _)) = server.accept().await {
+            accepted += 1;
+            let mut stream = Delimited::new(stream);
+            ProtocolInfo::new(vec![])
+                .server_negotiate(&mut stream)
+                .await?;
+            let _hello: Option<ClientMessage> = stream.recv().await?;
+            stream.send(ServerMessage::Hello(1, Uuid::new_v4())).await?;
+            if accepted == 3 {
+                break;
+            }
+        }
+        anyhow::Ok(accepted)
+    });
+
+    let supervisor = Supervisor {
+        liveness: Liveness {
+            interval: Duration::from_secs(1),
+            max_missed: 2,
+        },
+        reconnect: Reconnect {
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            max_retries: Some(2),
+        },
+        ..Supervisor::new(vec!["localhost:5000".parse()?], &to)
+    };
+    let err = time::timeout(Duration::from_secs(1), supervisor.run())
+        .await?
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("giving up after 3 attempts"),
+        "{err}"
+    );
+    assert_eq!(accepted.await??, 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn udp_tunnel() -> Result<()> {
+    // A control port other than the default, so no serial guard is needed.
+    let server = Server::with_settings(
+        vec![([127, 0, 0, 1], 7847).into()],
+        ServerSettings::new(1024..=65535, None),
+    );
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A local service that echoes datagrams back to their sender.
+    let local = UdpSocket::bind("127.0.0.1:0").await?;
+    let tunnel = Tunnel {
+        udp: true,
+        ..format!("127.0.0.1:{}", local.local_addr()?.port()).parse()?
+    };
+    tokio::spawn(async move {
+        let mut buf = [0; 8192];
+        while let Ok((len, peer)) = local.recv_from(&mut buf).await {
+            local.send_to(&buf[..len], peer).await.ok();
+        }
+    });
+
+    let to = "127.0.0.1:7847";
+    let err = Client::with_tunnels(vec![tunnel.clone()], to, None, None, true)
+        .await
+        .err()
+        .ok_or_else(|| anyhow!("udp tunnel was multiplexed"))?;
+    assert!(err.to_string().contains("cannot be multiplexed"), "{err}");
+
+    let client = Client::with_tunnels(vec![tunnel], to, None, None, false).await?;
+    let remote = SocketAddr::from(([127, 0, 0, 1], client.remote_port()));
+    tokio::spawn(client.listen());
+
+    // Replies go back to the peer that sent each datagram.
+    let (first, second) = (
+        UdpSocket::bind("127.0.0.1:0").await?,
+        UdpSocket::bind("127.0.0.1:0").await?,
+    );
+    for _ in 0..2 {
+        first.send_to(b"first", remote).await?;
+        second.send_to(b"second", remote).await?;
+        let mut buf = [0; 64];
+        let len = time::timeout(Duration::from_secs(1), first.recv(&mut buf)).await??;
+        assert_eq!(&buf[..len], b"first");
+        let len = time::timeout(Duration::from_secs(1), second.recv(&mut buf)).await??;
+        assert_eq!(&buf[..len], b"second");
+    }
+
+    // Datagrams as long as DNS responses with EDNS are forwarded too.
+    let long = [42; 4096];
+    first.send_to(&long, remote).await?;
+    let mut buf = [0; 8192];
+    let len = time::timeout(Duration::from_secs(1), first.recv(&mut buf)).await??;
+    assert_eq!(&buf[..len], &long[..]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_routing() -> Result<()> {
+    let settings = ServerSettings {
+        http_domain: Some("bore.test".into()),
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    let server = Server::with_settings(vec![([127, 0, 0, 1], 7848).into()], settings)
+        .with_http(([127, 0, 0, 1], 7849).into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Local web services that answer every request with their name.
+    let mut tunnels = Vec::new();
+    for (name, hostname) in [("app", "app"), ("generated", "")] {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        tunnels.push(Tunnel {
+            http: Some(hostname.into()),
+            ..format!("127.0.0.1:{}", listener.local_addr()?.port()).parse()?
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                // The head of the request is replayed to the service, which
+                // is asked to close the connection after answering.
+                let len = stream.read(&mut buf).await.unwrap_or(0);
+                let head = String::from_utf8_lossy(&buf[..len]);
+                if !head.starts_with("GET / HTTP/1.1")
+                    || !head.contains("\r\nConnection: close\r\n")
+                {
+                    continue;
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{name}",
+                    name.len()
+                );
+                stream.write_all(response.as_bytes()).await.ok();
+            }
+        });
+    }
+
+    let to = "127.0.0.1:7848";
+    let client = Client::with_tunnels(tunnels.clone(), to, None, None, false).await?;
+    assert_eq!(client.remote_ports(), [7849, 7849]);
+    let hosts = client.remote_hosts().to_vec();
+    assert_eq!(hosts[0].as_deref(), Some("app.bore.test"));
+    let generated = hosts[1]
+        .clone()
+        .ok_or_else(|| anyhow!("no generated hostname"))?;
+    assert!(generated.ends_with(".bore.test"), "{generated}");
+    tokio::spawn(client.listen());
+
+    let err = Client::with_tunnels(tunnels[..1].to_vec(), to, None, None, false)
+        .await
+        .err()
+        .ok_or_else(|| anyhow!("hostname was registered twice"))?;
+    assert!(err.to_string().contains("already in use"), "{err}");
+
+    let get = |host: String| async move {
+        let mut stream = TcpStream::connect(("127.0.0.1", 7849)).await?;
+        let request =
+            format!("GET / HTTP/1.1\r\nHost: {host}:7849\r\nConnection: keep-alive\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        time::timeout(Duration::from_secs(1), stream.read_to_string(&mut response)).await??;
+        anyhow::Ok(response)
+    };
+    assert!(get("App.bore.test".into()).await?.ends_with("\r\n\r\napp"));
+    assert!(get(generated).await?.ends_with("\r\n\r\ngenerated"));
+    let response = get("nope.bore.test".into()).await?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"), "{response}");
+    assert!(response.contains("nope.bore.test"), "{response}");
+
+    // Hostnames outside the server's domain cannot be claimed.
+    let outside = Tunnel {
+        http: Some("www.example.com".into()),
+        ..tunnels[0].clone()
+    };
+    let err = Client::with_tunnels(vec![outside], to, None, None, false)
+        .await
+        .err()
+        .ok_or_else(|| anyhow!("hostname outside the domain was registered"))?;
+    assert!(err.to_string().contains("not within"), "{err}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_routing_without_domain() -> Result<()> {
+    let server = Server::with_settings(
+        vec![([127, 0, 0, 1], 7857).into()],
+        ServerSettings::new(1024..=65535, None),
+    )
+    .with_http(([127, 0, 0, 1], 7858).into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Without a domain, no hostname can be claimed.
+    let tunnel = Tunnel {
+        http: Some("www.example.com".into()),
+        .."localhost:5000".parse()?
+    };
+    let err = Client::with_tunnels(vec![tunnel], "127.0.0.1:7857", None, None, false)
+        .await
+        .err()
+        .ok_or_else(|| anyhow!("hostname was registered without a domain"))?;
+    assert!(err.to_string().contains("no domain"), "{err}");
+    Ok(())
+}
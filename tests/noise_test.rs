@@ -0,0 +1,161 @@
+use std::env;
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_cli::client::{Client, Tunnel};
+use bore_cli::metrics::NOISE_HANDSHAKE_FAILURES;
+use bore_cli::noise::{Keypair, NoiseClient, NoiseServer};
+use bore_cli::server::{Server, ServerSettings};
+use rstest::rstest;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+/// Control port of the Noise server, distinct from the one in other tests.
+const NOISE_CONTROL_PORT: u16 = 7839;
+
+#[tokio::test]
+async fn noise_stream() -> Result<()> {
+    let (server_key, client_key) = (Keypair::generate(), Keypair::generate());
+    let server = NoiseServer::new(server_key.clone(), vec![client_key.public()]);
+    let client = NoiseClient::new(client_key.clone(), server_key.public());
+
+    let (a, b) = io::duplex(1024);
+    let accept = tokio::spawn(async move { server.accept(b).await });
+    let mut client_stream = client.connect(a).await?;
+    let (mut server_stream, key) = accept.await??;
+    assert_eq!(key, client_key.public());
+
+    // Larger than a single Noise message, in both directions.
+    let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let echo = tokio::spawn(async move {
+        let mut buf = vec![0; expected.len()];
+        server_stream.read_exact(&mut buf).await?;
+        assert_eq!(buf, expected);
+        server_stream.write_all(&buf).await?;
+        server_stream.shutdown().await
+    });
+    client_stream.write_all(&data).await?;
+    client_stream.flush().await?;
+    let mut buf = Vec::new();
+    client_stream.read_to_end(&mut buf).await?;
+    assert_eq!(buf, data);
+    echo.await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn noise_authentication() -> Result<()> {
+    let (server_key, client_key) = (Keypair::generate(), Keypair::generate());
+    let handshake = |server: NoiseServer, client: NoiseClient| async move {
+        let (a, b) = io::duplex(1024);
+        let accept = tokio::spawn(async move { server.accept(b).await.map(|_| ()) });
+        let connect = client.connect(a).await.map(|_| ());
+        (connect, accept.await.unwrap())
+    };
+
+    // The client rejects a server without the pinned key.
+    let server = NoiseServer::new(Keypair::generate(), vec![]);
+    let client = NoiseClient::new(client_key.clone(), server_key.public());
+    let (connect, _) = handshake(server, client).await;
+    let err = connect.unwrap_err();
+    assert!(err.to_string().contains("expected noise key"), "{err:#}");
+
+    // The server rejects a client not on its allow-list.
+    let server = NoiseServer::new(server_key.clone(), vec![client_key.public()]);
+    let client = NoiseClient::new(Keypair::generate(), server_key.public());
+    let (_, accept) = handshake(server, client).await;
+    let err = accept.unwrap_err();
+    assert!(err.to_string().contains("is not allowed"), "{err:#}");
+
+    // Without an allow-list, any client is accepted.
+    let server = NoiseServer::new(server_key.clone(), vec![]);
+    let client = NoiseClient::new(Keypair::generate(), server_key.public());
+    let (connect, accept) = handshake(server, client).await;
+    connect?;
+    accept?;
+    Ok(())
+}
+
+#[test]
+fn save_keypair() -> Result<()> {
+    let dir = env::temp_dir().join(format!("bore-noise-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("bore.key");
+    let key = Keypair::generate();
+    key.save(&path)?;
+
+    assert_eq!(Keypair::load(&path)?.public(), key.public());
+    let public = std::fs::read_to_string(dir.join("bore.key.pub"))?;
+    assert_eq!(
+        public.trim().parse::<bore_cli::noise::PublicKey>()?,
+        key.public()
+    );
+    assert!(
+        key.save(&path).is_err(),
+        "existing keys are not overwritten"
+    );
+
+    std::fs::write(&path, "not a key")?;
+    assert!(Keypair::load(&path).is_err());
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn noise_proxy(#[values(false, true)] multiplex: bool) -> Result<()> {
+    let (server_key, client_key) = (Keypair::generate(), Keypair::generate());
+    let port = NOISE_CONTROL_PORT + u16::from(multiplex);
+    let settings = ServerSettings {
+        noise: Some(NoiseServer::new(
+            server_key.clone(),
+            vec![client_key.public()],
+        )),
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    tokio::spawn(Server::with_settings(vec![([127, 0, 0, 1], port).into()], settings).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let tunnel = Tunnel {
+        local_host: "localhost".into(),
+        local_port: listener.local_addr()?.port(),
+        port: 0,
+        access: Default::default(),
+        udp: false,
+        http: None,
+    };
+    let to = format!("localhost:{port}");
+    let noise = NoiseClient::new(client_key, server_key.public());
+    let client = Client::with_tunnels(
+        vec![tunnel.clone()],
+        &to,
+        None,
+        Some(noise.into()),
+        multiplex,
+    )
+    .await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut remote = TcpStream::connect(("localhost", remote_port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    remote.write_all(b"hello").await?;
+    let mut buf = [0; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    local.write_all(b"world").await?;
+    remote.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"world");
+
+    // A client with a key that is not allowed cannot open tunnels.
+    let before = NOISE_HANDSHAKE_FAILURES.with_label_values(&["error"]).get();
+    let stranger = NoiseClient::new(Keypair::generate(), server_key.public());
+    let result = Client::with_tunnels(vec![tunnel], &to, None, Some(stranger.into()), false).await;
+    assert!(result.is_err());
+    time::sleep(Duration::from_millis(50)).await;
+    assert!(NOISE_HANDSHAKE_FAILURES.with_label_values(&["error"]).get() > before);
+    Ok(())
+}
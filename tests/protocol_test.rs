@@ -0,0 +1,60 @@
+use anyhow::Result;
+use bore_cli::shared::{Capability, Delimited, ProtocolInfo};
+use tokio::io::{self};
+
+#[tokio::test]
+async fn protocol_negotiation() -> Result<()> {
+    let client = ProtocolInfo::new(vec![Capability::Multiplex]);
+    let server = ProtocolInfo::new(vec![]);
+
+    let (client_stream, server_stream) = io::duplex(8); // Ensure correctness with limited capacity.
+    let mut client_stream = Delimited::new(client_stream);
+    let mut server_stream = Delimited::new(server_stream);
+
+    let (from_client, from_server) = tokio::try_join!(
+        client.client_negotiate(&mut client_stream),
+        server.server_negotiate(&mut server_stream),
+    )?;
+    assert_eq!(from_client, from_server);
+    assert!(!from_client.supports(Capability::Multiplex));
+
+    Ok(())
+}
+
+#[test]
+fn unknown_capability() -> Result<()> {
+    let peer: ProtocolInfo = serde_json::from_str(
+        r#"{"version":1,"min_version":1,"capabilities":["Multiplex","Teleport"]}"#,
+    )?;
+    assert_eq!(
+        peer.capabilities,
+        vec![Capability::Multiplex, Capability::Unknown]
+    );
+
+    let ours = ProtocolInfo::new(vec![Capability::Multiplex]);
+    assert_eq!(
+        ours.negotiate(&peer)?.capabilities,
+        vec![Capability::Multiplex]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn protocol_negotiation_incompatible() {
+    let client = ProtocolInfo {
+        version: 100,
+        min_version: 100,
+        capabilities: vec![],
+    };
+    let server = ProtocolInfo::new(vec![Capability::Multiplex]);
+
+    let (client_stream, server_stream) = io::duplex(8); // Ensure correctness with limited capacity.
+    let mut client_stream = Delimited::new(client_stream);
+    let mut server_stream = Delimited::new(server_stream);
+
+    let result = tokio::try_join!(
+        client.client_negotiate(&mut client_stream),
+        server.server_negotiate(&mut server_stream),
+    );
+    assert!(result.is_err());
+}
@@ -0,0 +1,349 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_cli::ca::CertificateAuthority;
+use bore_cli::client::Client;
+use bore_cli::metrics::{CERTIFICATE_CONNECTIONS, TLS_HANDSHAKE_FAILURES};
+use bore_cli::server::{Server, ServerSettings};
+use bore_cli::tls::{
+    load_certs, load_identity, load_pkcs12, load_private_key, watch_identity, CertResolver,
+    ClientTls, IdentityFiles, Pin, ServerVerifier,
+};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, SanType};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_rustls::rustls::{self, server::AllowAnyAuthenticatedClient, sign, PrivateKey};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Control port of the TLS server, distinct from the one in other tests.
+const TLS_CONTROL_PORT: u16 = 7837;
+
+/// Generate a certificate signed by the CA, with a common name and DNS names.
+///
+/// At least one DNS name is needed, since webpki rejects an empty SAN extension.
+fn issue(
+    ca: &Certificate,
+    name: &str,
+    dns_names: &[&str],
+) -> Result<(rustls::Certificate, PrivateKey)> {
+    let mut params =
+        CertificateParams::new(dns_names.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+    params.distinguished_name.push(DnType::CommonName, name);
+    let cert = Certificate::from_params(params)?;
+    Ok((
+        rustls::Certificate(cert.serialize_der_with_signer(ca)?),
+        PrivateKey(cert.serialize_private_key_der()),
+    ))
+}
+
+fn generate_ca() -> Result<Certificate> {
+    let mut params = CertificateParams::default();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "bore test CA");
+    Ok(Certificate::from_params(params)?)
+}
+
+#[tokio::test]
+async fn mutual_tls() -> Result<()> {
+    let ca = generate_ca()?;
+    let ca_cert = rustls::Certificate(ca.serialize_der()?);
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&ca_cert)?;
+
+    let (server_cert, server_key) = issue(&ca, "bore server", &["localhost"])?;
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots.clone()))
+        .with_single_cert(vec![server_cert], server_key)?;
+    let settings = ServerSettings {
+        tls: Some(TlsAcceptor::from(Arc::new(server_config))),
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    let control_addr = ([127, 0, 0, 1], TLS_CONTROL_PORT).into();
+    tokio::spawn(Server::with_settings(vec![control_addr], settings).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let client_tls = |cert: Option<(rustls::Certificate, PrivateKey)>| -> Result<TlsConnector> {
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots.clone());
+        let config = match cert {
+            Some((cert, key)) => config.with_single_cert(vec![cert], key)?,
+            None => config.with_no_client_auth(),
+        };
+        Ok(TlsConnector::from(Arc::new(config)))
+    };
+    let to = format!("localhost:{TLS_CONTROL_PORT}");
+    let connect = |tls| Client::new_with_tls("localhost", 5000, &to, 0, None, Some(tls), false);
+
+    // Clients without a certificate signed by the CA are rejected.
+    assert!(connect(client_tls(None)?).await.is_err());
+    let other_ca = generate_ca()?;
+    let stranger = issue(&other_ca, "stranger", &["stranger"])?;
+    assert!(connect(client_tls(Some(stranger))?).await.is_err());
+
+    let laptop = issue(&ca, "laptop", &["laptop"])?;
+    connect(client_tls(Some(laptop))?).await?;
+    let connections = CERTIFICATE_CONNECTIONS.with_label_values(&["laptop"]).get();
+    assert_eq!(connections, 1);
+    Ok(())
+}
+
+/// Path to a certificate or key generated with openssl for these tests.
+fn fixture(name: &str) -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/certs")
+        .join(name)
+}
+
+#[test]
+fn load_key_formats() -> Result<()> {
+    for name in ["rsa_pkcs1.key", "ec_sec1.key", "ed25519_pkcs8.key"] {
+        let key = load_private_key(&fixture(name))?;
+        sign::any_supported_type(&key).map_err(|_| anyhow::anyhow!("unusable key {name}"))?;
+    }
+
+    // Certificates pair with keys in any format.
+    let (certs, key) = load_identity(&fixture("ec.crt"), Some(&fixture("ec_sec1.key")), None)?;
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(())
+}
+
+#[test]
+fn load_pkcs12_bundles() -> Result<()> {
+    for name in ["identity.p12", "identity_legacy.p12"] {
+        let (certs, key) = load_pkcs12(&fixture(name), "1234")?;
+        assert_eq!(certs, load_certs(&fixture("ec.crt"))?);
+        sign::any_supported_type(&key).map_err(|_| anyhow::anyhow!("unusable key {name}"))?;
+    }
+
+    let err = load_pkcs12(&fixture("identity.p12"), "wrong").unwrap_err();
+    assert!(err.to_string().contains("wrong password"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn load_errors() {
+    let err = load_private_key(&fixture("ec.crt")).unwrap_err();
+    assert!(err.to_string().contains("no PKCS#8"), "{err}");
+    assert!(load_private_key(&fixture("missing.key")).is_err());
+    assert!(load_certs(&fixture("ec_sec1.key")).is_err());
+
+    // A PEM certificate without a key file is read as a PKCS#12 bundle.
+    let err = load_identity(&fixture("ec.crt"), None, None).unwrap_err();
+    assert!(err.to_string().contains("PKCS#12"), "{err}");
+}
+
+#[tokio::test]
+async fn reload_certificate() -> Result<()> {
+    let dir = env::temp_dir().join(format!("bore-reload-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let ca = generate_ca()?;
+    let write_identity = |name: &str| -> Result<Vec<u8>> {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let cert = Certificate::from_params(params)?;
+        fs::write(dir.join("server.crt"), cert.serialize_pem_with_signer(&ca)?)?;
+        fs::write(dir.join("server.key"), cert.serialize_private_key_pem())?;
+        Ok(load_certs(&dir.join("server.crt"))?.remove(0).0)
+    };
+
+    let first = write_identity("first")?;
+    let files = IdentityFiles {
+        cert: dir.join("server.crt"),
+        key: Some(dir.join("server.key")),
+        password: None,
+    };
+    let resolver = Arc::new(CertResolver::new(files.load()?));
+    let every = Duration::from_millis(20);
+    tokio::spawn(watch_identity(files, Arc::downgrade(&resolver), every));
+    assert_eq!(resolver.current().cert[0].0, first);
+
+    // Invalid files are ignored, keeping the previous certificate.
+    fs::write(dir.join("server.key"), "garbage")?;
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(resolver.current().cert[0].0, first);
+
+    let second = write_identity("second")?;
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(resolver.current().cert[0].0, second);
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn certificate_authority() -> Result<()> {
+    let dir = env::temp_dir().join(format!("bore-ca-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let path = |name: &str| dir.join(name);
+    CertificateAuthority::generate("bore CA", 30)?.save(&path("ca.crt"), &path("ca.key"))?;
+    assert!(CertificateAuthority::generate("other", 30)?
+        .save(&path("ca.crt"), &path("ca.key"))
+        .is_err());
+
+    // Certificates issued after reloading the authority chain up to the saved one.
+    let ca = CertificateAuthority::load(&path("ca.crt"), &path("ca.key"))?;
+    let sans = ["localhost".to_string(), "127.0.0.1".to_string()];
+    ca.issue("localhost", &sans, false, 30)?
+        .save(&path("server.crt"), &path("server.key"))?;
+    ca.issue("laptop", &["laptop".to_string()], true, 30)?
+        .save(&path("laptop.crt"), &path("laptop.key"))?;
+    assert!(ca.issue("nobody", &[], false, 30).is_err());
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&load_certs(&path("ca.crt"))?[0])?;
+    let (certs, key) = load_identity(&path("server.crt"), Some(&path("server.key")), None)?;
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots.clone()))
+        .with_single_cert(certs, key)?;
+    let (certs, key) = load_identity(&path("laptop.crt"), Some(&path("laptop.key")), None)?;
+    let client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_single_cert(certs, key)?;
+
+    let (client, server) = tokio::io::duplex(4096);
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let server = tokio::spawn(async move { acceptor.accept(server).await });
+    let connector = TlsConnector::from(Arc::new(client_config));
+    let _client = connector.connect("localhost".try_into()?, client).await?;
+    let (_, session) = server.await??.into_inner();
+    assert!(session.peer_certificates().is_some());
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+fn verifying_client(verifier: ServerVerifier) -> ClientTls {
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    ClientTls::new(TlsConnector::from(Arc::new(config)))
+}
+
+/// Run a TLS handshake over an in-memory stream, verifying the server as `name`.
+async fn handshake(
+    server: rustls::ServerConfig,
+    verifier: ServerVerifier,
+    name: &str,
+) -> Result<()> {
+    let tls = verifying_client(verifier);
+    let (client, server_stream) = tokio::io::duplex(4096);
+    let acceptor = TlsAcceptor::from(Arc::new(server));
+    let accept = tokio::spawn(async move { acceptor.accept(server_stream).await });
+    let result = tls
+        .connector()
+        .connect(tls.server_name(name)?, client)
+        .await;
+    let _server = accept.await?;
+    result?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_verification() -> Result<()> {
+    let ca = generate_ca()?;
+    let mut params = CertificateParams::new(vec!["bore.test".to_string()]);
+    params
+        .subject_alt_names
+        .push(SanType::IpAddress([127, 0, 0, 1].into()));
+    let cert = Certificate::from_params(params)?;
+    let server_cert = rustls::Certificate(cert.serialize_der_with_signer(&ca)?);
+    let server = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![server_cert.clone()],
+            PrivateKey(cert.serialize_private_key_der()),
+        )?;
+    let ca_verifier = || ServerVerifier::with_ca_certs(&[rustls::Certificate(ca.serialize_der()?)]);
+
+    // Both DNS and IP address SANs are checked.
+    handshake(server.clone(), ca_verifier()?, "bore.test").await?;
+    handshake(server.clone(), ca_verifier()?, "127.0.0.1").await?;
+    assert!(handshake(server.clone(), ca_verifier()?, "other.test")
+        .await
+        .is_err());
+    assert!(handshake(server.clone(), ca_verifier()?, "127.0.0.2")
+        .await
+        .is_err());
+    let roots = ServerVerifier::webpki_roots();
+    assert!(handshake(server.clone(), roots, "bore.test").await.is_err());
+
+    // Pins match the certificate or its public key, regardless of the name.
+    let spki = Pin::of(&cert.get_key_pair().public_key_der());
+    for pin in [Pin::of(&server_cert.0), spki] {
+        handshake(
+            server.clone(),
+            ServerVerifier::pinned(vec![pin]),
+            "any.test",
+        )
+        .await?;
+    }
+    let other = ServerVerifier::pinned(vec![Pin::of(b"other")]);
+    assert!(handshake(server.clone(), other, "bore.test").await.is_err());
+
+    // The server name can be overridden.
+    let tls = verifying_client(ca_verifier()?).with_server_name("bore.test")?;
+    assert_eq!(tls.server_name("10.0.0.1")?, "bore.test".try_into()?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_limits() -> Result<()> {
+    let ca = generate_ca()?;
+    let (cert, key) = issue(&ca, "bore server", &["localhost"])?;
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)?;
+    let settings = ServerSettings {
+        tls: Some(TlsAcceptor::from(Arc::new(server_config))),
+        handshake_timeout: Duration::from_millis(300),
+        max_handshakes: 2,
+        ..ServerSettings::new(1024..=65535, None)
+    };
+    let port = TLS_CONTROL_PORT + 1;
+    tokio::spawn(Server::with_settings(vec![([127, 0, 0, 1], port).into()], settings).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&rustls::Certificate(ca.serialize_der()?))?;
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tls = TlsConnector::from(Arc::new(config));
+    let to = format!("localhost:{port}");
+    let connect =
+        || Client::new_with_tls("localhost", 5000, &to, 0, None, Some(tls.clone()), false);
+    let failures = |reason| TLS_HANDSHAKE_FAILURES.with_label_values(&[reason]).get();
+
+    // A client that never sends its handshake does not hold up others.
+    let _stalled = TcpStream::connect(("localhost", port)).await?;
+    connect().await?;
+
+    // Beyond the limit of handshakes in progress, connections are dropped.
+    let _stalled_too = TcpStream::connect(("localhost", port)).await?;
+    time::sleep(Duration::from_millis(50)).await;
+    assert!(connect().await.is_err());
+    assert_eq!(failures("overloaded"), 1);
+
+    // Stalled handshakes time out, making room again.
+    time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(failures("timeout"), 2);
+    connect().await?;
+    Ok(())
+}